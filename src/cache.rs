@@ -0,0 +1,126 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::lsm::DataBlock;
+
+const NUM_SHARDS: usize = 16;
+
+// (SSTable.id, block offset). File numbers are never reused, so a key can't go stale.
+type BlockKey = (u64, u64);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub usage: usize, // bytes of block data currently cached
+}
+
+struct LruShard {
+    capacity: usize,
+    usage: usize,
+    tick: u64,
+    entries: HashMap<BlockKey, (Arc<DataBlock>, u64)>,
+    order: BTreeMap<u64, BlockKey>, // tick of the last use -> key, least recently used first
+}
+
+impl LruShard {
+    fn get(&mut self, key: BlockKey) -> Option<Arc<DataBlock>> {
+        let (block, last_used) = self.entries.get_mut(&key)?;
+        self.order.remove(last_used);
+        self.tick += 1;
+        *last_used = self.tick;
+        self.order.insert(self.tick, key);
+        Some(Arc::clone(block))
+    }
+
+    fn insert(&mut self, key: BlockKey, block: Arc<DataBlock>) {
+        let charge = block.size();
+        // would evict everything else and still not fit
+        if charge > self.capacity {
+            return;
+        }
+        if let Some((old, last_used)) = self.entries.remove(&key) {
+            self.order.remove(&last_used);
+            self.usage -= old.size();
+        }
+        self.tick += 1;
+        self.entries.insert(key, (block, self.tick));
+        self.order.insert(self.tick, key);
+        self.usage += charge;
+
+        while self.usage > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((evicted, _)) = self.entries.remove(&oldest) {
+                self.usage -= evicted.size();
+            }
+        }
+    }
+}
+
+// Capacity bounded LRU of data blocks that already passed their crc check, shared by every
+// table of an engine. Split into shards so concurrent readers rarely wait on the same lock.
+pub(crate) struct BlockCache {
+    shards: Vec<Mutex<LruShard>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlockCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        let shards = (0..NUM_SHARDS)
+            .map(|_| {
+                Mutex::new(LruShard {
+                    capacity: capacity / NUM_SHARDS,
+                    usage: 0,
+                    tick: 0,
+                    entries: HashMap::new(),
+                    order: BTreeMap::new(),
+                })
+            })
+            .collect();
+        Self {
+            shards,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: BlockKey) -> &Mutex<LruShard> {
+        let mut bytes = [0u8; 16];
+        bytes[..8].copy_from_slice(&key.0.to_le_bytes());
+        bytes[8..].copy_from_slice(&key.1.to_le_bytes());
+        &self.shards[xxh3_64(&bytes) as usize % NUM_SHARDS]
+    }
+
+    pub(crate) fn get(&self, table_id: u64, offset: u64) -> Option<Arc<DataBlock>> {
+        let key = (table_id, offset);
+        let block = self.shard(key).lock().unwrap().get(key);
+        match block {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        block
+    }
+
+    pub(crate) fn insert(&self, table_id: u64, offset: u64, block: Arc<DataBlock>) {
+        let key = (table_id, offset);
+        self.shard(key).lock().unwrap().insert(key, block);
+    }
+
+    pub(crate) fn stats(&self) -> BlockCacheStats {
+        BlockCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            usage: self
+                .shards
+                .iter()
+                .map(|shard| shard.lock().unwrap().usage)
+                .sum(),
+        }
+    }
+}
@@ -1 +1,1090 @@
+use std::cmp::Ordering;
+use std::fs::remove_file;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use crate::errors::Result;
+use crate::helpers::new_timestamp;
+use crate::lsm::{KVEngine, KvEntry, MAX_FILE_SIZE, SSTable, SsTableWriter, TableOptions};
+use crate::merge::{self, MergeOperator};
+use crate::range_del::RangeTombstone;
+
+const L0_COMPACTION_TRIGGER: usize = 4; // number of L0 tables before they get merged into L1
+const L1_MAX_BYTES: u64 = 10 * MAX_FILE_SIZE;
+const LEVEL_SIZE_RATIO: u64 = 10; // L(n+1) can hold LEVEL_SIZE_RATIO times more bytes than L(n)
+const MAX_LEVELS: u32 = 7;
+const TIERED_MIN_THRESHOLD: usize = 4; // tables of similar size needed before a bucket is merged
+const TIERED_MAX_THRESHOLD: usize = 32;
+const TIERED_BUCKET_LOW: f64 = 0.5;
+const TIERED_BUCKET_HIGH: f64 = 1.5;
+const TIERED_MIN_SSTABLE_SIZE: u64 = 1024 * 1024; // everything smaller shares one bucket
+
+// Picked at open time. Leveled keeps read amplification low, size-tiered keeps write
+// amplification low for ingest heavy workloads.
+#[derive(Clone)]
+pub enum CompactionStrategy {
+    Leveled(LeveledCompaction),
+    SizeTiered(SizeTieredCompaction),
+}
+
+impl Default for CompactionStrategy {
+    fn default() -> Self {
+        CompactionStrategy::Leveled(LeveledCompaction::default())
+    }
+}
+
+impl CompactionStrategy {
+    pub(crate) fn pick_compaction(&self, tables: &[Arc<SSTable>]) -> Option<CompactionTask> {
+        match self {
+            CompactionStrategy::Leveled(leveled) => leveled.pick_compaction(tables),
+            CompactionStrategy::SizeTiered(tiered) => tiered.pick_compaction(tables),
+        }
+    }
+
+    // snapshots are the sequence numbers of live snapshots, ascending
+    pub(crate) fn execute(
+        &self,
+        dir: &Path,
+        tables: &[Arc<SSTable>],
+        task: &CompactionTask,
+        snapshots: &[u64],
+        table: TableOptions,
+        merge_operator: Option<&dyn MergeOperator>,
+    ) -> Result<Vec<SSTable>> {
+        match self {
+            CompactionStrategy::Leveled(leveled) => {
+                leveled.execute(dir, tables, task, snapshots, table, merge_operator)
+            }
+            CompactionStrategy::SizeTiered(tiered) => {
+                tiered.execute(dir, tables, task, snapshots, table, merge_operator)
+            }
+        }
+    }
+}
+
+// What a compaction should do: which tables get merged and where the result goes.
+pub(crate) struct CompactionTask {
+    pub(crate) input_ids: Vec<u64>,
+    pub(crate) output_level: u32,
+    // only safe when no deeper table overlaps the inputs, otherwise a dropped tombstone resurrects old data
+    pub(crate) drop_tombstones: bool,
+}
+
+// L0 holds flushed memtables whose key ranges overlap. Every level below holds tables with
+// disjoint key ranges and a byte budget that grows by size_ratio per level.
+#[derive(Clone)]
+pub struct LeveledCompaction {
+    pub(crate) l0_trigger: usize,
+    pub(crate) l1_max_bytes: u64,
+    pub(crate) size_ratio: u64,
+    pub(crate) max_levels: u32,
+    pub(crate) target_file_size: u64,
+}
+
+impl Default for LeveledCompaction {
+    fn default() -> Self {
+        Self {
+            l0_trigger: L0_COMPACTION_TRIGGER,
+            l1_max_bytes: L1_MAX_BYTES,
+            size_ratio: LEVEL_SIZE_RATIO,
+            max_levels: MAX_LEVELS,
+            target_file_size: MAX_FILE_SIZE,
+        }
+    }
+}
+
+impl LeveledCompaction {
+    fn max_bytes_for_level(&self, level: u32) -> u64 {
+        (1..level).fold(self.l1_max_bytes, |acc, _| {
+            acc.saturating_mul(self.size_ratio)
+        })
+    }
+
+    // tables must be in search order (see sort_sstables)
+    pub(crate) fn pick_compaction(&self, tables: &[Arc<SSTable>]) -> Option<CompactionTask> {
+        let l0: Vec<&SSTable> = tables
+            .iter()
+            .filter(|t| t.level == 0)
+            .map(Arc::as_ref)
+            .collect();
+        if !l0.is_empty() && l0.len() >= self.l0_trigger {
+            // L0 tables overlap each other so they all have to go down together
+            return Some(Self::task_with_overlaps(tables, l0, 1));
+        }
+
+        // the last level has nowhere to go
+        let mut best: Option<(f64, u32)> = None;
+        for level in 1..self.max_levels.saturating_sub(1) {
+            let level_bytes: u64 = tables
+                .iter()
+                .filter(|t| t.level == level)
+                .map(|t| t.file_size)
+                .sum();
+            let score = level_bytes as f64 / self.max_bytes_for_level(level) as f64;
+            if score > 1.0 && best.is_none_or(|(s, _)| score > s) {
+                best = Some((score, level));
+            }
+        }
+
+        let (_, level) = best?;
+        // oldest table of the level goes first
+        let victim = tables
+            .iter()
+            .filter(|t| t.level == level)
+            .min_by_key(|t| t.id)?;
+        Some(Self::task_with_overlaps(tables, vec![victim], level + 1))
+    }
+
+    fn task_with_overlaps(
+        tables: &[Arc<SSTable>],
+        inputs: Vec<&SSTable>,
+        output_level: u32,
+    ) -> CompactionTask {
+        let (min_key, max_key) = key_range(&inputs);
+        let mut input_ids: Vec<u64> = inputs.iter().map(|t| t.id).collect();
+        input_ids.extend(
+            tables
+                .iter()
+                .filter(|t| t.level == output_level && overlaps(t, &min_key, &max_key))
+                .map(|t| t.id),
+        );
+
+        let (min_key, max_key) = key_range(
+            &tables
+                .iter()
+                .filter(|t| input_ids.contains(&t.id))
+                .map(Arc::as_ref)
+                .collect::<Vec<_>>(),
+        );
+        let drop_tombstones = !tables
+            .iter()
+            .any(|t| t.level > output_level && overlaps(t, &min_key, &max_key));
+
+        CompactionTask {
+            input_ids,
+            output_level,
+            drop_tombstones,
+        }
+    }
+
+    pub(crate) fn execute(
+        &self,
+        dir: &Path,
+        tables: &[Arc<SSTable>],
+        task: &CompactionTask,
+        snapshots: &[u64],
+        table: TableOptions,
+        merge_operator: Option<&dyn MergeOperator>,
+    ) -> Result<Vec<SSTable>> {
+        merge_tables(
+            dir,
+            tables,
+            task,
+            snapshots,
+            table,
+            merge_operator,
+            self.target_file_size,
+        )
+    }
+}
+
+// Tables are only compared by file_size, all of them stay in L0. A bucket is a run of tables
+// adjacent in age with sizes close to the run average, so the merged output carries the max_seq
+// of the newest table in the run and takes its place in L0 without shadowing anything newer.
+#[derive(Clone)]
+pub struct SizeTieredCompaction {
+    pub(crate) min_threshold: usize,
+    pub(crate) max_threshold: usize,
+    pub(crate) bucket_low: f64,
+    pub(crate) bucket_high: f64,
+    pub(crate) min_sstable_size: u64,
+}
+
+impl Default for SizeTieredCompaction {
+    fn default() -> Self {
+        Self {
+            min_threshold: TIERED_MIN_THRESHOLD,
+            max_threshold: TIERED_MAX_THRESHOLD,
+            bucket_low: TIERED_BUCKET_LOW,
+            bucket_high: TIERED_BUCKET_HIGH,
+            min_sstable_size: TIERED_MIN_SSTABLE_SIZE,
+        }
+    }
+}
+
+impl SizeTieredCompaction {
+    fn fits_bucket(&self, bucket: &[&SSTable], table: &SSTable) -> bool {
+        let total: u64 = bucket.iter().map(|t| t.file_size).sum();
+        let avg = total as f64 / bucket.len() as f64;
+        if table.file_size < self.min_sstable_size && avg < self.min_sstable_size as f64 {
+            return true;
+        }
+        let size = table.file_size as f64;
+        size >= avg * self.bucket_low && size <= avg * self.bucket_high
+    }
+
+    pub(crate) fn pick_compaction(&self, tables: &[Arc<SSTable>]) -> Option<CompactionTask> {
+        // oldest first
+        let mut l0: Vec<&SSTable> = tables
+            .iter()
+            .filter(|t| t.level == 0)
+            .map(Arc::as_ref)
+            .collect();
+        l0.sort_by_key(|t| (t.max_seq, t.id));
+
+        let mut bucket: Vec<&SSTable> = Vec::new();
+        for table in &l0 {
+            if !bucket.is_empty() && !self.fits_bucket(&bucket, table) {
+                if bucket.len() >= self.min_threshold {
+                    break;
+                }
+                bucket.clear();
+            }
+            bucket.push(table);
+            if bucket.len() == self.max_threshold {
+                break;
+            }
+        }
+        if bucket.len() < self.min_threshold.max(2) {
+            return None;
+        }
+
+        let (min_key, max_key) = key_range(&bucket);
+        // tombstones still shadow anything older than the bucket
+        let includes_oldest = l0.first().map(|t| t.id) == bucket.first().map(|t| t.id);
+        let drop_tombstones = includes_oldest
+            && !tables
+                .iter()
+                .any(|t| t.level > 0 && overlaps(t, &min_key, &max_key));
+
+        Some(CompactionTask {
+            input_ids: bucket.iter().map(|t| t.id).collect(),
+            output_level: 0,
+            drop_tombstones,
+        })
+    }
+
+    pub(crate) fn execute(
+        &self,
+        dir: &Path,
+        tables: &[Arc<SSTable>],
+        task: &CompactionTask,
+        snapshots: &[u64],
+        table: TableOptions,
+        merge_operator: Option<&dyn MergeOperator>,
+    ) -> Result<Vec<SSTable>> {
+        // one output per bucket, it has to fit into the age order of L0 as a single table
+        merge_tables(
+            dir,
+            tables,
+            task,
+            snapshots,
+            table,
+            merge_operator,
+            u64::MAX,
+        )
+    }
+}
+
+fn overlaps(table: &SSTable, min_key: &[u8], max_key: &[u8]) -> bool {
+    table.min_key.as_slice() <= max_key && table.max_key.as_slice() >= min_key
+}
+
+fn key_range(tables: &[&SSTable]) -> (Vec<u8>, Vec<u8>) {
+    let min_key = tables.iter().map(|t| &t.min_key).min().cloned();
+    let max_key = tables.iter().map(|t| &t.max_key).max().cloned();
+    (min_key.unwrap_or_default(), max_key.unwrap_or_default())
+}
+
+// search order: L0 newest first, then L1, L2... (tables inside L1+ never overlap). A flushed
+// memtable only holds seqs above every older table, so max_seq orders L0 by age even for
+// compaction outputs that got a fresh id.
+pub(crate) fn sort_sstables(tables: &mut [Arc<SSTable>]) {
+    tables.sort_by(|a, b| {
+        a.level
+            .cmp(&b.level)
+            .then(b.max_seq.cmp(&a.max_seq))
+            .then(b.id.cmp(&a.id))
+    });
+}
+
+// replaces the inputs of task with outputs, returns the tables whose files can be deleted
+pub(crate) fn install(
+    tables: &mut Vec<Arc<SSTable>>,
+    task: &CompactionTask,
+    outputs: Vec<SSTable>,
+) -> Vec<Arc<SSTable>> {
+    let (removed, kept): (Vec<Arc<SSTable>>, Vec<Arc<SSTable>>) = tables
+        .drain(..)
+        .partition(|t| task.input_ids.contains(&t.id));
+    *tables = kept;
+    tables.extend(outputs.into_iter().map(Arc::new));
+    sort_sstables(tables);
+    removed
+}
+
+// k-way merge of sorted sources into one stream ordered by key, then newest seq first. Every
+// version is returned, callers decide which ones are visible. Sources are given newest first
+// and the same (key, seq) showing up in several of them is only returned once.
+pub(crate) struct MergeIterator<I: Iterator<Item = Result<KvEntry>>> {
+    sources: Vec<I>,
+    heads: Vec<Option<KvEntry>>, // next entry of every source, None once it is drained
+    started: bool,
+}
+
+impl<I: Iterator<Item = Result<KvEntry>>> MergeIterator<I> {
+    pub(crate) fn new(sources: Vec<I>) -> Self {
+        let mut iter = Self {
+            heads: Vec::with_capacity(sources.len()),
+            sources,
+            started: false,
+        };
+        iter.heads.resize_with(iter.sources.len(), || None);
+        iter
+    }
+
+    fn advance(&mut self, i: usize) -> Result<()> {
+        self.heads[i] = self.sources[i].next().transpose()?;
+        Ok(())
+    }
+}
+
+impl<I: Iterator<Item = Result<KvEntry>>> Iterator for MergeIterator<I> {
+    type Item = Result<KvEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // sources are pulled lazily the first time around
+        if !self.started {
+            self.started = true;
+            for i in 0..self.sources.len() {
+                if let Err(err) = self.advance(i) {
+                    return Some(Err(err));
+                }
+            }
+        }
+
+        let mut smallest: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            let Some(entry) = head else { continue };
+            // strictly smaller, so on ties the newer source (lower index) is kept
+            if smallest.is_none_or(|s| {
+                let best = self.heads[s].as_ref().unwrap();
+                entry.key.cmp(&best.key).then(best.seq.cmp(&entry.seq)) == Ordering::Less
+            }) {
+                smallest = Some(i);
+            }
+        }
+
+        let smallest = smallest?;
+        let entry = self.heads[smallest].take().unwrap();
+
+        for i in 0..self.sources.len() {
+            let duplicate = self.heads[i]
+                .as_ref()
+                .is_some_and(|h| h.key == entry.key && h.seq == entry.seq);
+            if i != smallest && !duplicate {
+                continue;
+            }
+            if let Err(err) = self.advance(i) {
+                return Some(Err(err));
+            }
+        }
+        Some(Ok(entry))
+    }
+}
+
+// files a merge has written so far, removed again if it fails
+#[derive(Default)]
+struct OutputFiles {
+    finished: Vec<PathBuf>,
+    in_progress: Option<PathBuf>,
+}
+
+fn merge_tables(
+    dir: &Path,
+    tables: &[Arc<SSTable>],
+    task: &CompactionTask,
+    snapshots: &[u64],
+    table: TableOptions,
+    merge_operator: Option<&dyn MergeOperator>,
+    target_file_size: u64,
+) -> Result<Vec<SSTable>> {
+    let mut files = OutputFiles::default();
+
+    if let Err(err) = write_merged_tables(
+        dir,
+        tables,
+        task,
+        snapshots,
+        table.clone(),
+        merge_operator,
+        target_file_size,
+        &mut files,
+    ) {
+        // nothing was installed yet, leave the directory as we found it
+        for path in files.finished.iter().chain(files.in_progress.iter()) {
+            let _ = remove_file(path);
+        }
+        return Err(err);
+    }
+
+    files
+        .finished
+        .iter()
+        .map(|path| SSTable::load(path, &table))
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn write_merged_tables(
+    dir: &Path,
+    tables: &[Arc<SSTable>],
+    task: &CompactionTask,
+    snapshots: &[u64],
+    table: TableOptions,
+    merge_operator: Option<&dyn MergeOperator>,
+    target_file_size: u64,
+    files: &mut OutputFiles,
+) -> Result<()> {
+    let inputs: Vec<&Arc<SSTable>> = tables
+        .iter()
+        .filter(|t| task.input_ids.contains(&t.id))
+        .collect();
+    let sources = inputs.iter().map(|t| t.iter()).collect();
+    // a stripe is the set of readers that see the same version of a key: stripe i means
+    // the i oldest snapshots were taken before this version was written. Only the newest
+    // version in every stripe can still be read.
+    let stripe_of = |seq: u64| snapshots.partition_point(|&s| s < seq);
+    let now = new_timestamp();
+
+    let range_tombstones: Vec<RangeTombstone> = inputs
+        .iter()
+        .flat_map(|t| t.range_tombstones.iter().cloned())
+        .collect();
+    // same rule as point tombstones, everything they covered in the inputs is dropped below
+    let kept_tombstones: Vec<&RangeTombstone> = range_tombstones
+        .iter()
+        .filter(|t| !(task.drop_tombstones && stripe_of(t.seq) == 0))
+        .collect();
+
+    let mut writer: Option<(SsTableWriter, PathBuf)> = None;
+    // first key the current output is responsible for, its range tombstones are cut there
+    let mut lower: Option<Vec<u8>> = None;
+    let mut merged = MergeIterator::new(sources).peekable();
+    while let Some(newest) = merged.next() {
+        // every version of one key, newest first
+        let mut versions = vec![newest?];
+        while let Some(older) = merged
+            .next_if(|e| e.as_ref().is_err() || e.as_ref().is_ok_and(|e| e.key == versions[0].key))
+        {
+            versions.push(older?);
+        }
+        if let Some(operator) = merge_operator {
+            // only the newest version of a stripe is ever read
+            let tops: Vec<bool> = (0..versions.len())
+                .map(|i| i == 0 || stripe_of(versions[i - 1].seq) != stripe_of(versions[i].seq))
+                .collect();
+            merge::collapse(
+                operator,
+                &mut versions,
+                task.drop_tombstones,
+                &range_tombstones,
+                now,
+                |i| tops[i],
+            );
+        }
+
+        let mut last_stripe = None;
+        // an operand left unfolded is read through every version below it
+        let mut under_operand = false;
+        for mut entry in versions {
+            let stripe = stripe_of(entry.seq);
+            if !under_operand {
+                if last_stripe == Some(stripe) {
+                    continue;
+                }
+                last_stripe = Some(stripe);
+                // an expired value is a tombstone that keeps the older versions hidden
+                if entry.is_expired(now) {
+                    entry.deleted = true;
+                    entry.value.clear();
+                    entry.value_ptr = false;
+                    entry.expires_at = 0;
+                }
+                // in the oldest stripe nothing below the tombstone survives, so it has nothing left to hide
+                if entry.deleted && task.drop_tombstones && stripe == 0 {
+                    continue;
+                }
+                // covered and no snapshot in between that could still read it
+                if range_tombstones
+                    .iter()
+                    .any(|t| t.covers(&entry.key, entry.seq) && stripe_of(t.seq) == stripe)
+                {
+                    continue;
+                }
+            }
+            under_operand |= entry.merge_operand;
+
+            // outputs are only cut between two keys, versions of a key stay together
+            if let Some((ss_writer, _)) = writer.as_ref()
+                && ss_writer.estimated_size() >= target_file_size
+                && ss_writer.last_key() != entry.key.as_slice()
+            {
+                let (ss_writer, final_path) = writer.take().unwrap();
+                finish_output(ss_writer, &kept_tombstones, &lower, Some(&entry.key))?;
+                files.finished.push(final_path);
+                files.in_progress = None;
+                lower = Some(entry.key.clone());
+            }
+
+            if writer.is_none() {
+                writer = Some(new_output(dir, task, table.clone(), files)?);
+            }
+            let (ss_writer, _) = writer.as_mut().unwrap();
+            ss_writer.add(&entry)?;
+        }
+    }
+
+    // range tombstones need a table even when every key they covered is gone
+    if writer.is_none() && !kept_tombstones.is_empty() {
+        writer = Some(new_output(dir, task, table.clone(), files)?);
+    }
+    if let Some((ss_writer, final_path)) = writer {
+        finish_output(ss_writer, &kept_tombstones, &lower, None)?;
+        files.finished.push(final_path);
+        files.in_progress = None;
+    }
+    Ok(())
+}
+
+fn new_output(
+    dir: &Path,
+    task: &CompactionTask,
+    table: TableOptions,
+    files: &mut OutputFiles,
+) -> Result<(SsTableWriter, PathBuf)> {
+    let (_, tmp_path, final_path) = KVEngine::create_new_data_file(dir)?;
+    files.in_progress = Some(tmp_path.clone());
+    Ok((
+        SsTableWriter::new(&tmp_path, &final_path, task.output_level, table)?,
+        final_path,
+    ))
+}
+
+// every output gets the pieces of the range tombstones between its first key and the first
+// key of the next output, so tables of one level stay disjoint
+fn finish_output(
+    mut writer: SsTableWriter,
+    tombstones: &[&RangeTombstone],
+    lower: &Option<Vec<u8>>,
+    upper: Option<&[u8]>,
+) -> Result<()> {
+    for t in tombstones {
+        if let Some(piece) = t.clip(lower.as_deref(), upper) {
+            writer.add_range_tombstone(piece);
+        }
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::CompressionType;
+    use tempfile::tempdir;
+
+    // entries are (key, seq, value), a None value is a tombstone
+    fn write_versions(
+        dir: &Path,
+        level: u32,
+        entries: &[(&str, u64, Option<&str>)],
+    ) -> Result<Arc<SSTable>> {
+        let (_, tmp_path, final_path) = KVEngine::create_new_data_file(dir)?;
+        let mut writer =
+            SsTableWriter::new(&tmp_path, &final_path, level, CompressionType::Lz4.into())?;
+        for (key, seq, value) in entries {
+            writer.add(&KvEntry {
+                key: key.as_bytes().to_vec(),
+                value: value.unwrap_or_default().as_bytes().to_vec(),
+                seq: *seq,
+                expires_at: 0,
+                deleted: value.is_none(),
+                value_ptr: false,
+                merge_operand: false,
+            })?;
+        }
+        writer.finish()?;
+        Ok(Arc::new(SSTable::load(
+            &final_path,
+            &CompressionType::None.into(),
+        )?))
+    }
+
+    // every entry at seq 0, so the newer table wins
+    fn write_table(
+        dir: &Path,
+        level: u32,
+        entries: &[(&str, Option<&str>)],
+    ) -> Result<Arc<SSTable>> {
+        let entries: Vec<(&str, u64, Option<&str>)> =
+            entries.iter().map(|(k, v)| (*k, 0, *v)).collect();
+        write_versions(dir, level, &entries)
+    }
+
+    fn live_entries(tables: &[Arc<SSTable>]) -> Result<Vec<(String, String)>> {
+        let sources = tables.iter().map(|t| t.iter()).collect();
+        let mut out = Vec::new();
+        let mut last_key: Option<Vec<u8>> = None;
+        for entry in MergeIterator::new(sources) {
+            let entry = entry?;
+            if last_key.as_ref() == Some(&entry.key) {
+                continue;
+            }
+            last_key = Some(entry.key.clone());
+            if !entry.deleted {
+                out.push((
+                    String::from_utf8(entry.key).unwrap(),
+                    String::from_utf8(entry.value).unwrap(),
+                ));
+            }
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn l0_tables_merge_into_l1() -> Result<()> {
+        let dir = tempdir()?;
+        let mut tables = vec![
+            write_table(dir.path(), 0, &[("a", Some("1")), ("c", Some("1"))])?,
+            write_table(dir.path(), 0, &[("b", Some("2")), ("c", Some("2"))])?,
+            write_table(dir.path(), 0, &[("a", None), ("d", Some("3"))])?,
+            write_table(dir.path(), 0, &[("d", Some("4")), ("e", Some("4"))])?,
+        ];
+        sort_sstables(&mut tables);
+
+        let compaction = LeveledCompaction::default();
+        let task = compaction.pick_compaction(&tables).unwrap();
+        assert_eq!(task.output_level, 1);
+        assert_eq!(task.input_ids.len(), 4);
+        assert!(task.drop_tombstones);
+
+        let outputs = compaction.execute(
+            dir.path(),
+            &tables,
+            &task,
+            &[],
+            CompressionType::Lz4.into(),
+            None,
+        )?;
+        let removed = install(&mut tables, &task, outputs);
+        assert_eq!(removed.len(), 4);
+        assert!(tables.iter().all(|t| t.level == 1));
+        assert!(compaction.pick_compaction(&tables).is_none());
+
+        assert_eq!(
+            live_entries(&tables)?,
+            vec![
+                ("b".to_string(), "2".to_string()),
+                ("c".to_string(), "2".to_string()),
+                ("d".to_string(), "4".to_string()),
+                ("e".to_string(), "4".to_string()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_level_pushes_oldest_table_down() -> Result<()> {
+        let dir = tempdir()?;
+        let mut tables = vec![
+            write_table(dir.path(), 1, &[("a", Some("1")), ("b", None)])?,
+            write_table(dir.path(), 1, &[("m", Some("1")), ("n", Some("1"))])?,
+            write_table(dir.path(), 2, &[("b", Some("old")), ("c", Some("old"))])?,
+            write_table(dir.path(), 3, &[("z", Some("old"))])?,
+        ];
+        sort_sstables(&mut tables);
+        let oldest_l1 = tables.iter().filter(|t| t.level == 1).map(|t| t.id).min();
+
+        let compaction = LeveledCompaction {
+            l1_max_bytes: 1,
+            ..LeveledCompaction::default()
+        };
+        let task = compaction.pick_compaction(&tables).unwrap();
+        assert_eq!(task.output_level, 2);
+        assert_eq!(task.input_ids.len(), 2);
+        assert!(task.input_ids.contains(&oldest_l1.unwrap()));
+        // L3 does not overlap a..c, the tombstone for b can go
+        assert!(task.drop_tombstones);
+
+        let outputs = compaction.execute(
+            dir.path(),
+            &tables,
+            &task,
+            &[],
+            CompressionType::Lz4.into(),
+            None,
+        )?;
+        install(&mut tables, &task, outputs);
+
+        let l2: Vec<&Arc<SSTable>> = tables.iter().filter(|t| t.level == 2).collect();
+        assert_eq!(l2.len(), 1);
+        assert_eq!(l2[0].min_key, b"a");
+        assert_eq!(l2[0].max_key, b"c");
+        assert_eq!(
+            live_entries(&tables)?,
+            vec![
+                ("a".to_string(), "1".to_string()),
+                ("c".to_string(), "old".to_string()),
+                ("m".to_string(), "1".to_string()),
+                ("n".to_string(), "1".to_string()),
+                ("z".to_string(), "old".to_string()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn size_tiered_merges_run_of_similar_tables() -> Result<()> {
+        let dir = tempdir()?;
+        let big: Vec<(String, Option<&str>)> = (0..500)
+            .map(|i| (format!("k{:04}", i), Some("some value that takes up room")))
+            .collect();
+        let big: Vec<(&str, Option<&str>)> = big.iter().map(|(k, v)| (k.as_str(), *v)).collect();
+
+        let mut tables = vec![
+            write_table(dir.path(), 0, &[("a", Some("1"))])?,
+            write_table(dir.path(), 0, &[("b", Some("1"))])?,
+            write_table(dir.path(), 0, &big)?,
+            write_table(dir.path(), 0, &[("a", Some("4"))])?,
+            write_table(dir.path(), 0, &[("b", None)])?,
+            write_table(dir.path(), 0, &[("c", Some("6"))])?,
+            write_table(dir.path(), 0, &[("a", Some("7"))])?,
+        ];
+        sort_sstables(&mut tables);
+
+        let compaction = CompactionStrategy::SizeTiered(SizeTieredCompaction {
+            min_sstable_size: 0,
+            ..SizeTieredCompaction::default()
+        });
+        let task = compaction.pick_compaction(&tables).unwrap();
+        // the big table breaks the first run, a and b at the start stay untouched
+        assert_eq!(task.input_ids.len(), 4);
+        assert!(!task.drop_tombstones);
+
+        let outputs = compaction.execute(
+            dir.path(),
+            &tables,
+            &task,
+            &[],
+            CompressionType::Lz4.into(),
+            None,
+        )?;
+        assert_eq!(outputs.len(), 1);
+        let output_id = outputs[0].id;
+        let removed = install(&mut tables, &task, outputs);
+        assert_eq!(removed.len(), 4);
+        for table in removed {
+            remove_file(&table.file_path)?;
+        }
+        assert_eq!(tables.len(), 4);
+        assert_eq!(tables[0].id, output_id);
+        assert!(compaction.pick_compaction(&tables).is_none());
+
+        let live = live_entries(&tables)?;
+        assert_eq!(live[0], ("a".to_string(), "7".to_string()));
+        assert_eq!(live[1], ("c".to_string(), "6".to_string()));
+        assert_eq!(live.len(), 502);
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_keeps_versions_visible_to_snapshots() -> Result<()> {
+        let dir = tempdir()?;
+        let mut tables = vec![
+            write_versions(dir.path(), 0, &[("a", 1, Some("1")), ("b", 2, Some("1"))])?,
+            write_versions(dir.path(), 0, &[("a", 3, Some("2")), ("b", 4, None)])?,
+            write_versions(dir.path(), 0, &[("a", 6, Some("3")), ("a", 5, Some("x"))])?,
+        ];
+        sort_sstables(&mut tables);
+
+        let compaction = LeveledCompaction {
+            l0_trigger: 1,
+            ..LeveledCompaction::default()
+        };
+        let task = compaction.pick_compaction(&tables).unwrap();
+        assert!(task.drop_tombstones);
+        // a snapshot taken at seq 3 still reads a=2 and b=1
+        let outputs = compaction.execute(
+            dir.path(),
+            &tables,
+            &task,
+            &[3],
+            CompressionType::None.into(),
+            None,
+        )?;
+        install(&mut tables, &task, outputs);
+
+        let versions: Vec<(Vec<u8>, u64, bool)> = tables[0]
+            .iter()
+            .map(|e| e.map(|e| (e.key, e.seq, e.deleted)))
+            .collect::<Result<_>>()?;
+        assert_eq!(
+            versions,
+            vec![
+                (b"a".to_vec(), 6, false),
+                (b"a".to_vec(), 3, false),
+                (b"b".to_vec(), 4, true),
+                (b"b".to_vec(), 2, false),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn range_tombstones_drop_covered_keys() -> Result<()> {
+        let dir = tempdir()?;
+        let older = write_versions(
+            dir.path(),
+            0,
+            &[
+                ("a", 1, Some("1")),
+                ("b", 2, Some("2")),
+                ("c", 3, Some("3")),
+                ("d", 4, Some("4")),
+            ],
+        )?;
+        let (_, tmp_path, final_path) = KVEngine::create_new_data_file(dir.path())?;
+        let mut writer =
+            SsTableWriter::new(&tmp_path, &final_path, 0, CompressionType::None.into())?;
+        writer.add(&KvEntry {
+            key: b"e".to_vec(),
+            value: b"10".to_vec(),
+            seq: 10,
+            expires_at: 0,
+            deleted: false,
+            value_ptr: false,
+            merge_operand: false,
+        })?;
+        writer.add_range_tombstone(RangeTombstone {
+            start: b"b".to_vec(),
+            end: b"d".to_vec(),
+            seq: 9,
+        });
+        writer.finish()?;
+        let newer = Arc::new(SSTable::load(&final_path, &CompressionType::None.into())?);
+        assert_eq!(newer.min_key, b"b");
+        let tables = vec![newer, older];
+        let task = CompactionTask {
+            input_ids: tables.iter().map(|t| t.id).collect(),
+            output_level: 1,
+            drop_tombstones: true,
+        };
+
+        // nothing can read b and c anymore, they go together with the tombstone
+        let outputs = merge_tables(
+            dir.path(),
+            &tables,
+            &task,
+            &[],
+            CompressionType::None.into(),
+            None,
+            u64::MAX,
+        )?;
+        assert_eq!(outputs.len(), 1);
+        assert!(outputs[0].range_tombstones.is_empty());
+        let outputs: Vec<Arc<SSTable>> = outputs.into_iter().map(Arc::new).collect();
+        let keys: Vec<Vec<u8>> = live_entries(&outputs)?
+            .into_iter()
+            .map(|(k, _)| k.into_bytes())
+            .collect();
+        assert_eq!(keys, vec![b"a".to_vec(), b"d".to_vec(), b"e".to_vec()]);
+
+        // a snapshot at 5 still reads b and c, one key per output cuts the tombstone in pieces
+        let outputs = merge_tables(
+            dir.path(),
+            &tables,
+            &task,
+            &[5],
+            CompressionType::None.into(),
+            None,
+            1,
+        )?;
+        assert_eq!(outputs.len(), 5);
+        let pieces: Vec<(Vec<u8>, Vec<u8>)> = outputs
+            .iter()
+            .flat_map(|t| {
+                t.range_tombstones
+                    .iter()
+                    .map(|r| (r.start.clone(), r.end.clone()))
+            })
+            .collect();
+        assert_eq!(
+            pieces,
+            vec![
+                (b"b".to_vec(), b"c".to_vec()),
+                (b"c".to_vec(), b"d".to_vec())
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn expired_entries_become_tombstones() -> Result<()> {
+        let dir = tempdir()?;
+        let older = write_versions(dir.path(), 0, &[("a", 1, Some("1")), ("b", 2, Some("2"))])?;
+        let (_, tmp_path, final_path) = KVEngine::create_new_data_file(dir.path())?;
+        let mut writer =
+            SsTableWriter::new(&tmp_path, &final_path, 0, CompressionType::None.into())?;
+        for (key, expires_at) in [("a", 1), ("b", u64::MAX)] {
+            writer.add(&KvEntry {
+                key: key.as_bytes().to_vec(),
+                value: b"ttl".to_vec(),
+                seq: 10,
+                expires_at,
+                deleted: false,
+                value_ptr: false,
+                merge_operand: false,
+            })?;
+        }
+        writer.finish()?;
+        let tables = vec![
+            Arc::new(SSTable::load(&final_path, &CompressionType::None.into())?),
+            older,
+        ];
+        let mut task = CompactionTask {
+            input_ids: tables.iter().map(|t| t.id).collect(),
+            output_level: 1,
+            drop_tombstones: false,
+        };
+
+        // older tables may still hold a, the expired version keeps hiding it
+        let outputs = merge_tables(
+            dir.path(),
+            &tables,
+            &task,
+            &[5],
+            CompressionType::None.into(),
+            None,
+            u64::MAX,
+        )?;
+        let outputs: Vec<Arc<SSTable>> = outputs.into_iter().map(Arc::new).collect();
+        let mut entries = outputs[0].iter().collect::<Result<Vec<_>>>()?.into_iter();
+        let a = entries.next().unwrap();
+        assert!(a.key == b"a" && a.seq == 10 && a.deleted && a.value.is_empty());
+        assert_eq!(
+            live_entries(&outputs)?,
+            [("b".to_string(), "ttl".to_string())]
+        );
+
+        task.drop_tombstones = true;
+        let outputs = merge_tables(
+            dir.path(),
+            &tables,
+            &task,
+            &[],
+            CompressionType::None.into(),
+            None,
+            u64::MAX,
+        )?;
+        let outputs: Vec<Arc<SSTable>> = outputs.into_iter().map(Arc::new).collect();
+        let keys: Vec<Vec<u8>> = outputs[0]
+            .iter()
+            .map(|e| e.map(|e| e.key))
+            .collect::<Result<_>>()?;
+        assert_eq!(keys, vec![b"b".to_vec()]);
+        Ok(())
+    }
+
+    // adds little endian u64 operands
+    struct Counter;
+
+    impl MergeOperator for Counter {
+        fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+            let count = |b: &[u8]| u64::from_le_bytes(b.try_into().unwrap());
+            (existing.map_or(0, count) + count(operand))
+                .to_le_bytes()
+                .to_vec()
+        }
+    }
+
+    #[test]
+    fn merge_operands_collapse() -> Result<()> {
+        let dir = tempdir()?;
+        let (_, tmp_path, final_path) = KVEngine::create_new_data_file(dir.path())?;
+        let mut writer =
+            SsTableWriter::new(&tmp_path, &final_path, 0, CompressionType::None.into())?;
+        // hits is counted on top of a put, misses only has operands
+        for (key, seq, count, merge_operand) in [
+            ("hits", 9, 4, true),
+            ("hits", 7, 2, true),
+            ("hits", 1, 10, false),
+            ("misses", 8, 1, true),
+            ("misses", 6, 1, true),
+        ] {
+            writer.add(&KvEntry {
+                key: key.as_bytes().to_vec(),
+                value: (count as u64).to_le_bytes().to_vec(),
+                seq,
+                expires_at: 0,
+                deleted: false,
+                value_ptr: false,
+                merge_operand,
+            })?;
+        }
+        writer.finish()?;
+        let tables = vec![Arc::new(SSTable::load(
+            &final_path,
+            &CompressionType::None.into(),
+        )?)];
+        let mut task = CompactionTask {
+            input_ids: tables.iter().map(|t| t.id).collect(),
+            output_level: 1,
+            drop_tombstones: false,
+        };
+        let versions = |task: &CompactionTask| -> Result<Vec<(String, u64, u64, bool)>> {
+            let outputs = merge_tables(
+                dir.path(),
+                &tables,
+                task,
+                &[],
+                CompressionType::None.into(),
+                Some(&Counter),
+                u64::MAX,
+            )?;
+            let outputs: Vec<Arc<SSTable>> = outputs.into_iter().map(Arc::new).collect();
+            outputs[0]
+                .iter()
+                .map(|e| {
+                    let e = e?;
+                    let count = u64::from_le_bytes(e.value.as_slice().try_into().unwrap());
+                    Ok((
+                        String::from_utf8(e.key).unwrap(),
+                        e.seq,
+                        count,
+                        e.merge_operand,
+                    ))
+                })
+                .collect()
+        };
+
+        // deeper levels may still hold misses, its operands have to stay
+        assert_eq!(
+            versions(&task)?,
+            vec![
+                ("hits".to_string(), 9, 16, false),
+                ("misses".to_string(), 8, 1, true),
+                ("misses".to_string(), 6, 1, true),
+            ]
+        );
+        task.drop_tombstones = true;
+        assert_eq!(
+            versions(&task)?,
+            vec![
+                ("hits".to_string(), 9, 16, false),
+                ("misses".to_string(), 8, 2, false),
+            ]
+        );
+        Ok(())
+    }
+}
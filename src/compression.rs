@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::errors::CorruptionType;
+
+const NONE_TAG: u8 = 0;
+const LZ4_TAG: u8 = 1;
+
+// Turns the bytes of a data block into what goes on disk and back. Codecs beside the built-in
+// ones are registered with Options::codec and picked with CompressionType::Custom.
+pub trait CompressionCodec: Send + Sync {
+    // stored with every block the codec compresses, a table can only be read by an engine that
+    // has the codec registered under the same tag. 2..=15, the others are taken by the built-in
+    // codecs
+    fn tag(&self) -> u8;
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+    // max_len bounds the decompressed size, a damaged length must not make us allocate gigabytes
+    fn decompress(&self, data: &[u8], max_len: usize) -> Result<Vec<u8>, CorruptionType>;
+}
+
+struct NoCompression;
+
+impl CompressionCodec for NoCompression {
+    fn tag(&self) -> u8 {
+        NONE_TAG
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        data.to_vec()
+    }
+
+    fn decompress(&self, data: &[u8], max_len: usize) -> Result<Vec<u8>, CorruptionType> {
+        if data.len() > max_len {
+            return Err(CorruptionType::BufferExceedsMaxLength {
+                size: data.len() as u64,
+                max_size: max_len as u64,
+            });
+        }
+        Ok(data.to_vec())
+    }
+}
+
+// LZ4 block format with the uncompressed length prepended as a little endian u32
+struct Lz4Compression;
+
+impl CompressionCodec for Lz4Compression {
+    fn tag(&self) -> u8 {
+        LZ4_TAG
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        lz4_flex::compress_prepend_size(data)
+    }
+
+    fn decompress(&self, data: &[u8], max_len: usize) -> Result<Vec<u8>, CorruptionType> {
+        let Some(len) = data.get(..4) else {
+            return Err(CorruptionType::LengthMismatch {
+                expected: 4,
+                found: data.len(),
+            });
+        };
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        if len > max_len {
+            return Err(CorruptionType::BufferExceedsMaxLength {
+                size: len as u64,
+                max_size: max_len as u64,
+            });
+        }
+        lz4_flex::decompress_size_prepended(data).map_err(|e| CorruptionType::Other(e.to_string()))
+    }
+}
+
+// Codec used for the data blocks an engine writes. The tag is stored with every block, so
+// tables written with different settings stay readable side by side.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CompressionType {
+    #[default]
+    None,
+    Lz4,
+    Custom(u8), // the tag of a codec registered with Options::codec
+}
+
+impl CompressionType {
+    pub(crate) fn tag(self) -> u8 {
+        match self {
+            CompressionType::None => NONE_TAG,
+            CompressionType::Lz4 => LZ4_TAG,
+            CompressionType::Custom(tag) => tag,
+        }
+    }
+}
+
+// Every codec an engine can read and write blocks with, by tag.
+#[derive(Clone, Default)]
+pub(crate) struct Codecs(Arc<BTreeMap<u8, Arc<dyn CompressionCodec>>>);
+
+impl Codecs {
+    pub(crate) fn new(custom: &[Arc<dyn CompressionCodec>]) -> Self {
+        Self(Arc::new(
+            custom
+                .iter()
+                .map(|codec| (codec.tag(), Arc::clone(codec)))
+                .collect(),
+        ))
+    }
+
+    pub(crate) fn get(&self, tag: u8) -> Option<&dyn CompressionCodec> {
+        match tag {
+            NONE_TAG => Some(&NoCompression),
+            LZ4_TAG => Some(&Lz4Compression),
+            _ => self.0.get(&tag).map(|codec| &**codec),
+        }
+    }
+
+    pub(crate) fn is_builtin(tag: u8) -> bool {
+        tag == NONE_TAG || tag == LZ4_TAG
+    }
+}
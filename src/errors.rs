@@ -1,9 +1,5 @@
 use core::fmt;
-use std::{
-    error::Error,
-    fmt::{Formatter, write},
-    path::PathBuf,
-};
+use std::{error::Error, path::PathBuf};
 
 #[derive(Debug)]
 
@@ -31,7 +27,13 @@ pub enum DbError {
     FileError(String, PathBuf),
     MemTableSyncError(String),
     ReportedViaChannel,
-    // FlushingError,
+    MissingMergeOperator,
+    ColumnFamily(String),
+    InvalidKey(String),
+    InvalidValue(String),
+    InvalidOptions(String),
+    ForeignSnapshot,    // a snapshot read through an engine it was not taken from
+    Background(String), // a flush failed, the engine refuses writes until it is reopened
 }
 
 impl fmt::Display for CorruptionType {
@@ -97,6 +99,18 @@ impl fmt::Display for DbError {
             Self::ReportedViaChannel => {
                 write!(f, "Error reported to main thread via channel. ")
             }
+            Self::MissingMergeOperator => {
+                write!(
+                    f,
+                    "Found merge operands but no merge operator is registered"
+                )
+            }
+            Self::ColumnFamily(err) => write!(f, "Column family error: {}", err),
+            Self::InvalidKey(err) => write!(f, "Invalid key: {}", err),
+            Self::InvalidValue(err) => write!(f, "Invalid value: {}", err),
+            Self::InvalidOptions(err) => write!(f, "Invalid options: {}", err),
+            Self::ForeignSnapshot => write!(f, "Snapshot was taken from another engine"),
+            Self::Background(err) => write!(f, "Background job failed: {}", err),
         }
     }
 }
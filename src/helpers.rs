@@ -25,8 +25,76 @@ pub fn compute_crc_data_block(data: &[u8]) -> u32 {
     digest.finalize()
 }
 
+// The crc of any range of some data in about log(len) steps, from the crc of every prefix.
+// crc(a followed by b) is crc(b) xored with crc(a) moved past len(b) zero bytes (the zlib
+// crc32_combine), so a range is what its end prefix has on top of its start prefix.
+pub struct RangeCrc {
+    prefixes: Vec<u32>,
+    zero_ops: Vec<[u32; 32]>, // appending 2^k zero bytes to a crc, as a matrix over GF(2)
+}
+
+impl RangeCrc {
+    pub fn new(data: &[u8]) -> Self {
+        let crc32 = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+        let mut digest = crc32.digest();
+        let mut prefixes = Vec::with_capacity(data.len() + 1);
+        prefixes.push(digest.clone().finalize());
+        for byte in data {
+            digest.update(std::slice::from_ref(byte));
+            prefixes.push(digest.clone().finalize());
+        }
+
+        // one zero bit with the reversed polynomial, squared three times for a byte
+        let mut op = [0u32; 32];
+        op[0] = 0xedb8_8320;
+        for (row, entry) in op.iter_mut().enumerate().skip(1) {
+            *entry = 1 << (row - 1);
+        }
+        for _ in 0..3 {
+            op = square(&op);
+        }
+        let mut zero_ops = vec![op];
+        while 1usize << zero_ops.len() <= data.len() {
+            zero_ops.push(square(zero_ops.last().unwrap()));
+        }
+        Self { prefixes, zero_ops }
+    }
+
+    pub fn crc(&self, range: std::ops::Range<usize>) -> u32 {
+        let mut start = self.prefixes[range.start];
+        let len = range.end - range.start;
+        for (k, op) in self.zero_ops.iter().enumerate() {
+            if len >> k & 1 != 0 {
+                start = times(op, start);
+            }
+        }
+        self.prefixes[range.end] ^ start
+    }
+}
+
+fn times(matrix: &[u32; 32], mut vec: u32) -> u32 {
+    let mut sum = 0;
+    let mut row = 0;
+    while vec != 0 {
+        if vec & 1 != 0 {
+            sum ^= matrix[row];
+        }
+        vec >>= 1;
+        row += 1;
+    }
+    sum
+}
+
+fn square(matrix: &[u32; 32]) -> [u32; 32] {
+    std::array::from_fn(|row| times(matrix, matrix[row]))
+}
+
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+// lowest number the next file may get, raised by every allocation and by the MANIFEST on open
+static NEXT_FILE_NUMBER: AtomicU64 = AtomicU64::new(0);
+
 pub fn new_timestamp() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -35,14 +103,37 @@ pub fn new_timestamp() -> u64 {
 }
 
 pub fn get_hashed_key_positions(key: &[u8], bloom_filter_size: usize) -> [usize; NUM_HASHES] {
-    let h_key = xxh3_128(key);
+    get_positions_from_hash(xxh3_128(key), bloom_filter_size)
+}
+
+// same as get_hashed_key_positions but for a key that was already hashed with xxh3_128
+pub fn get_positions_from_hash(h_key: u128, bloom_filter_size: usize) -> [usize; NUM_HASHES] {
     let h1 = (h_key >> 64) as u64;
     let h2 = h_key as u64;
 
     let mut arr: [usize; NUM_HASHES] = [0; NUM_HASHES];
-    for i in 0..NUM_HASHES {
-        arr[i] = h1.wrapping_add(i as u64).wrapping_mul(h2) as usize % bloom_filter_size;
+    for (i, position) in arr.iter_mut().enumerate() {
+        *position = h1.wrapping_add(i as u64).wrapping_mul(h2) as usize % bloom_filter_size;
     }
 
     arr
 }
+
+// file numbers follow the clock but never repeat or go backwards, even if the clock does
+pub fn new_file_number() -> u64 {
+    let now = new_timestamp();
+    let prev = NEXT_FILE_NUMBER
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |next| {
+            Some(next.max(now) + 1)
+        })
+        .unwrap();
+    prev.max(now)
+}
+
+pub fn next_file_number() -> u64 {
+    NEXT_FILE_NUMBER.load(Ordering::SeqCst)
+}
+
+pub fn reserve_file_numbers(next: u64) {
+    NEXT_FILE_NUMBER.fetch_max(next, Ordering::SeqCst);
+}
@@ -0,0 +1,104 @@
+use std::ops::Bound;
+use std::path::Path;
+
+mod bitcask;
+mod cache;
+mod compact;
+mod compression;
+mod errors;
+mod helpers;
+mod lsm;
+mod manifest;
+mod memtable;
+mod merge;
+mod options;
+mod range_del;
+mod skiplist;
+mod vlog;
+mod wal;
+mod write_controller;
+
+pub use bitcask::KVEngine as BitcaskEngine;
+pub use cache::BlockCacheStats;
+pub use compact::{CompactionStrategy, LeveledCompaction, SizeTieredCompaction};
+pub use compression::{CompressionCodec, CompressionType};
+pub use errors::{CorruptionType, DataCorruptedErr, DbError, Result};
+pub use lsm::{
+    ColumnFamilyId, ColumnFamilyOptions, Db, KVEngine as LsmEngine, LsmIterator, ScanIterator,
+    Snapshot,
+};
+pub use memtable::MemtableKind;
+pub use merge::MergeOperator;
+pub use options::Options;
+pub use wal::{RecoveryMode, SyncConfig, WriteBatch};
+pub use write_controller::WriteStallStats;
+
+// key value pairs of a scan in key order
+pub type ScanItems<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+// What every engine offers, so callers can pick one by configuration and hold it as a
+// Box<dyn StorageEngine>. Getting or deleting a missing key is not an error.
+pub trait StorageEngine {
+    fn open(dir: &Path) -> Result<Self>
+    where
+        Self: Sized;
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()>;
+    fn delete(&mut self, key: &[u8]) -> Result<()>;
+    fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<ScanItems<'_>>;
+    // makes every write so far durable
+    fn sync(&mut self) -> Result<()>;
+    fn close(&mut self) -> Result<()>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EngineKind {
+    Bitcask,
+    Lsm,
+}
+
+pub fn open(kind: EngineKind, dir: &Path) -> Result<Box<dyn StorageEngine>> {
+    Ok(match kind {
+        EngineKind::Bitcask => Box::new(<BitcaskEngine as StorageEngine>::open(dir)?),
+        EngineKind::Lsm => Box::new(<LsmEngine as StorageEngine>::open(dir)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn both_engines_behave_the_same_through_the_trait() -> Result<()> {
+        for kind in [EngineKind::Bitcask, EngineKind::Lsm] {
+            let dir = tempdir()?;
+            {
+                let mut db = open(kind, dir.path())?;
+                db.put(b"a", b"1")?;
+                db.put(b"b", b"2")?;
+                db.put(b"c", b"3")?;
+                db.delete(b"b")?;
+                db.delete(b"missing")?;
+                db.sync()?;
+                assert_eq!(db.get(b"a")?, Some(b"1".to_vec()), "{kind:?}");
+                assert_eq!(db.get(b"b")?, None, "{kind:?}");
+                db.close()?;
+            }
+
+            let db = open(kind, dir.path())?;
+            let scanned = db
+                .scan(Bound::Included(b"a".as_slice()), Bound::Unbounded)?
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(
+                scanned,
+                vec![
+                    (b"a".to_vec(), b"1".to_vec()),
+                    (b"c".to_vec(), b"3".to_vec()),
+                ],
+                "{kind:?}"
+            );
+        }
+        Ok(())
+    }
+}
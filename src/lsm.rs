@@ -1,52 +1,70 @@
-use crc::{CRC_32_ISO_HDLC, Crc};
-
-use core::num;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fs::{self, File, OpenOptions, remove_file};
-use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::iter::Peekable;
+use std::ops::{Bound, Deref, RangeBounds};
+use std::os::unix::fs::FileExt;
 
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{Arc, RwLock};
-use std::sync::{Weak, mpsc};
-use std::thread::spawn;
-use std::unimplemented;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread::{self, JoinHandle, spawn};
+use std::time::Duration;
 
-use crate::errors::CorruptionType::Other;
+use crate::cache::{BlockCache, BlockCacheStats};
+use crate::compact::{self, CompactionStrategy, MergeIterator};
+use crate::compression::{Codecs, CompressionType};
 use crate::errors::{CorruptionType, DataCorruptedErr, DbError, Result};
 use crate::helpers::{
-    NUM_HASHES, compute_crc, compute_crc_data_block, get_hashed_key_positions, new_timestamp,
+    NUM_HASHES, compute_crc_data_block, get_hashed_key_positions, get_positions_from_hash,
+    new_file_number, new_timestamp, reserve_file_numbers,
 };
+use crate::manifest::{Manifest, VersionEdit};
+use crate::memtable::{Memtable, MemtableEntry, MemtableKind};
+use crate::merge::{self, MergeOperator};
+use crate::options::Options;
+use crate::range_del::{self, RangeTombstone};
+use crate::vlog::{ValueLog, ValuePointer, VlogWriter};
+use crate::wal::{BatchOp, Ticket, WalReader, WalRecord, WalRecordType, WalWriter, WriteBatch};
+use crate::write_controller::{WriteController, WriteStallStats};
+use crate::{ScanItems, StorageEngine};
+use std::borrow::Cow;
 use std::cmp::{Ordering, max};
-
-const MAX_FILE_SIZE: u64 = 4 * 1024 * 1024; // SUBJECT TO CHANGE
-const MEMTABLE_THRESHOLD: u64 = 4 * 1024 * 1024; // SUBJECT TO CHANGE
-const DATA_BLOCK: u16 = 8 * 1024; // Data block in SSTable
-const MAX_BLOCK_SIZE: u64 = 1024 * 1024;
-const TAG_DELETION: u8 = 2;
-const TAG_INSERTION: u8 = 4;
-const KEY_MAX_BYTES_SIZE: u64 = 16384;
-const VALUE_MAX_BYTES_SIZE: u64 = 131072;
-
-// WAL config for flush
-
-#[derive(Copy, Clone)]
-enum SyncConfig {
-    None,       // fast, data can be lost
-    Every(u64), // in ms
-    Always,     // Ddurable
-}
+use xxhash_rust::xxh3::xxh3_128;
+
+// defaults of Options
+pub(crate) const MAX_FILE_SIZE: u64 = 4 * 1024 * 1024;
+pub(crate) const MEMTABLE_THRESHOLD: u64 = 4 * 1024 * 1024;
+pub(crate) const MAX_PENDING_MEMTABLES: usize = 4;
+pub(crate) const SLOWDOWN_PENDING_MEMTABLES: usize = 3;
+pub(crate) const DATA_BLOCK: u64 = 8 * 1024; // Data block in SSTable
+pub(crate) const MAX_BLOCK_SIZE: u64 = 1024 * 1024;
+pub(crate) const BLOOM_BITS_PER_KEY: u64 = 10;
+const BLOCK_CACHE_CAPACITY: usize = 8 * 1024 * 1024;
+const FLUSH_RETRY_DELAY: Duration = Duration::from_millis(100); // between attempts at a failed flush
+pub(crate) const BLOCK_CODEC_MASK: u8 = 0x0f; // low bits of the block type byte
+const BLOCK_FORMAT_SHIFT: u8 = 4; // the high bits hold the BlockFormat
+pub(crate) const KEY_MAX_BYTES_SIZE: u64 = 16384;
+pub(crate) const VALUE_MAX_BYTES_SIZE: u64 = 131072;
+const SSTABLE_FOOTER_SIZE: u64 = 64;
+pub(crate) const ENTRY_HEADER_SIZE: usize = 33; // seq(8) | expires_at(8) | ksz(8) | vsz(8) | tombstone(1)
+const PREFIX_ENTRY_HEADER_SIZE: usize = 29; // shared(4) | unshared(4) | vsz(4) | seq(8) | expires_at(8) | tombstone(1)
+const RESTART_INTERVAL: usize = 16; // records between two full keys in a prefix compressed block
+pub(crate) const VALUE_LOG_THRESHOLD: u64 = 4 * 1024; // longer values are moved to the value log on flush
+const VALUE_POINTER_FLAG: u8 = 0x02; // flag byte of a record whose value is a ValuePointer
+const MERGE_OPERAND_FLAG: u8 = 0x04; // flag byte of a record whose value is a merge operand
+const DEFAULT_COLUMN_FAMILY_NAME: &str = "default";
+
+pub type ColumnFamilyId = u32;
+pub(crate) const DEFAULT_COLUMN_FAMILY: ColumnFamilyId = 0; // always there, cannot be dropped
 
 struct BloomFilter {
     bits: Vec<u64>,
     num_bits: u64,
 }
 
-enum WalRecordType<'a> {
-    Deletion(&'a [u8]),            // ( key )
-    Insertion(&'a [u8], &'a [u8]), // (key, value)
-}
-
 struct SparseIndex {
     index_entries: Vec<Vec<u8>>, // PROBLEM: Rethink this really fast, doesnt need to be a Vector of Vectors just push bytes into it
     size: u64,
@@ -59,16 +77,15 @@ impl SparseIndex {
             size: 0,
         }
     }
-    fn add_entry(&mut self, ss_data_block: SsTableDataBlock, offset: u64) {
-        let full_block = ss_data_block.full_data_block();
-        let first_keysz = (full_block.starting_key.len() as u64).to_le_bytes();
-        let data_block_sz = (full_block.bytes.len() as u64).to_le_bytes();
+    fn add_entry(&mut self, starting_key: &[u8], offset: u64, data_block_len: u64) {
+        let first_keysz = (starting_key.len() as u64).to_le_bytes();
         let mut sparse_entry: Vec<u8> = Vec::new();
         // sparse index: sizeof(k), k, offset, datablock_size);
         sparse_entry.extend_from_slice(&first_keysz);
-        sparse_entry.extend_from_slice(&full_block.starting_key);
+        sparse_entry.extend_from_slice(starting_key);
         sparse_entry.extend_from_slice(&offset.to_le_bytes());
-        sparse_entry.extend_from_slice(&data_block_sz);
+        sparse_entry.extend_from_slice(&data_block_len.to_le_bytes());
+        self.size += sparse_entry.len() as u64;
         self.index_entries.push(sparse_entry);
     }
 
@@ -82,7 +99,7 @@ impl SparseIndex {
             let ksz = u64::from_le_bytes(b[current..(current + 8)].try_into().unwrap());
             current += 8;
             let key = b[current..(current + (ksz as usize))].to_vec();
-            current += (ksz as usize);
+            current += ksz as usize;
             let offset = u64::from_le_bytes(b[current..(current + 8)].try_into().unwrap());
             current += 8;
             let data_block_size = u64::from_le_bytes(b[current..(current + 8)].try_into().unwrap());
@@ -95,10 +112,11 @@ impl SparseIndex {
 
 impl BloomFilter {
     fn new(num_bits: usize) -> Self {
-        let words_for_bits = num_bits.div_ceil(64);
+        // round up to whole words so the bit count can be recovered from the on-disk size
+        let words_for_bits = max(num_bits, 1).div_ceil(64);
         Self {
             bits: vec![0u64; words_for_bits],
-            num_bits: num_bits as u64,
+            num_bits: (words_for_bits * 64) as u64,
         }
     }
 
@@ -125,124 +143,220 @@ impl BloomFilter {
     }
 }
 
-struct FileId(u64);
-
-struct WAL {
-    wal_writer: Option<BufWriter<File>>,
-    sync_c: SyncConfig,
-    record_buffer: Vec<u8>,
-    threshold: u64,
-    path: PathBuf,
+// Layout of the records inside a data block, kept in the high bits of the block type byte so
+// blocks of either format can be read.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum BlockFormat {
+    // [ seq(8) | expires_at(8) | ksz(8) | value_sz(8) | tombstone | key | value ] ...
+    Plain,
+    // [ shared(4) | unshared(4) | value_sz(4) | seq(8) | expires_at(8) | tombstone | key[shared..] | value ] ...
+    // | restart offsets(4 each) | restart count(4) ]. A key only stores what differs from the
+    // previous one, every RESTART_INTERVAL records a full key restarts the chain.
+    #[default]
+    PrefixCompressed,
 }
 
-impl WAL {
-    fn new(threshold: u64, sync_c: SyncConfig) -> io::Result<WAL> {
-        let tstamp = new_timestamp();
-        let wal_path = PathBuf::from(format!("{}.wal", tstamp));
-        let wal_file = OpenOptions::new()
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(&wal_path)?;
-        Ok(Self {
-            wal_writer: Some(BufWriter::new(wal_file)),
-            threshold,
-            record_buffer: Vec::new(),
-            sync_c,
-            path: wal_path,
-        })
-    }
-    fn destruct(mut self) -> Result<()> {
-        self.wal_writer = None;
-        remove_file(&self.path)?;
-        Ok(())
-    }
-
-    // PROBLEM: Right now we sync_all for every single record, make sure you use SyncConfig later on for deciding
-    fn record_to_wal<'a>(&mut self, record: WalRecordType<'a>) -> Result<()> {
-        let record_buffer = &mut self.record_buffer;
-        record_buffer.clear();
-        let tstamp = new_timestamp();
-
-        match record {
-            WalRecordType::Deletion(k) => {
-                record_buffer.extend_from_slice(&TAG_DELETION.to_le_bytes());
-                record_buffer.extend_from_slice(&tstamp.to_le_bytes());
-                record_buffer.extend_from_slice(&(k.len() as u64).to_le_bytes());
-                record_buffer.extend_from_slice(k);
-            }
-            WalRecordType::Insertion(k, v) => {
-                record_buffer.extend_from_slice(&TAG_INSERTION.to_le_bytes());
-                record_buffer.extend_from_slice(&tstamp.to_le_bytes());
-                record_buffer.extend_from_slice(&(k.len() as u64).to_le_bytes());
-                record_buffer.extend_from_slice(&(v.len() as u64).to_le_bytes());
-                record_buffer.extend_from_slice(k);
-                record_buffer.extend_from_slice(v);
-            }
+impl BlockFormat {
+    fn version(self) -> u8 {
+        match self {
+            BlockFormat::Plain => 0,
+            BlockFormat::PrefixCompressed => 1,
         }
+    }
 
-        let crc = compute_crc_data_block(&record_buffer);
-        record_buffer.extend_from_slice(&crc.to_le_bytes());
-
-        if let Some(writer) = self.wal_writer.as_mut() {
-            writer.write_all(&record_buffer)?;
-            writer.flush()?;
-            writer.get_ref().sync_all()?;
+    fn from_version(version: u8) -> Option<Self> {
+        match version {
+            0 => Some(BlockFormat::Plain),
+            1 => Some(BlockFormat::PrefixCompressed),
+            _ => None,
         }
-        Ok(())
     }
 }
 
 struct SsTableDataBlock {
-    bytes: Vec<u8>, //[ tstamp(8) | ksz(8) | value_sz(8) | key | value ] ... crc(4) (crc for the entire datablock);
+    bytes: Vec<u8>, // records (see BlockFormat) ... block type(1) | crc(4) (crc for the entire datablock);
     size: usize,
     starting_key: Vec<u8>,
+    format: BlockFormat,
+    last_key: Vec<u8>,
+    restarts: Vec<u32>,
+    records: usize,
 }
 
 impl SsTableDataBlock {
-    fn new(s_key: &[u8]) -> Self {
+    fn new(s_key: &[u8], format: BlockFormat) -> Self {
         // creates SsTableDataBlock
         Self {
             bytes: Vec::new(),
             size: 0,
             starting_key: s_key.to_vec(),
+            format,
+            last_key: Vec::new(),
+            restarts: Vec::new(),
+            records: 0,
         }
     }
-    fn append_to_block(&mut self, entry: &[u8]) {
-        self.bytes.extend_from_slice(entry);
-        self.size += entry.len();
+
+    fn append_to_block(&mut self, entry: &KvEntry) {
+        match self.format {
+            BlockFormat::Plain => self.bytes.extend_from_slice(&entry.serialize()),
+            BlockFormat::PrefixCompressed => {
+                let shared = if self.records.is_multiple_of(RESTART_INTERVAL) {
+                    self.restarts.push(self.bytes.len() as u32);
+                    0
+                } else {
+                    self.last_key
+                        .iter()
+                        .zip(&entry.key)
+                        .take_while(|(a, b)| a == b)
+                        .count()
+                };
+                let suffix = &entry.key[shared..];
+                self.bytes.extend_from_slice(&(shared as u32).to_le_bytes());
+                self.bytes
+                    .extend_from_slice(&(suffix.len() as u32).to_le_bytes());
+                self.bytes
+                    .extend_from_slice(&(entry.value.len() as u32).to_le_bytes());
+                self.bytes.extend_from_slice(&entry.seq.to_le_bytes());
+                self.bytes
+                    .extend_from_slice(&entry.expires_at.to_le_bytes());
+                self.bytes.push(entry.flag());
+                self.bytes.extend_from_slice(suffix);
+                self.bytes.extend_from_slice(&entry.value);
+                self.last_key.clone_from(&entry.key);
+            }
+        }
+        self.records += 1;
+        self.size = self.bytes.len();
     }
 
-    fn is_finished(&self) -> bool {
-        self.size > DATA_BLOCK as usize
+    fn is_finished(&self, block_size: u64) -> bool {
+        self.size as u64 > block_size
     }
 
-    fn full_data_block(mut self) -> Self {
+    // compresses the records and appends the block type and crc. The block type holds the codec
+    // in its low bits, the high bits are left for block format versions
+    fn full_data_block(mut self, compression: CompressionType, codecs: &Codecs) -> Self {
+        if self.format == BlockFormat::PrefixCompressed {
+            for restart in &self.restarts {
+                self.bytes.extend_from_slice(&restart.to_le_bytes());
+            }
+            self.bytes
+                .extend_from_slice(&(self.restarts.len() as u32).to_le_bytes());
+        }
+        let mut codec = CompressionType::None;
+        if let Some(compressor) = codecs
+            .get(compression.tag())
+            .filter(|_| compression != CompressionType::None)
+        {
+            let compressed = compressor.compress(&self.bytes);
+            // saving less than an eighth is not worth decompressing on every read
+            if compressed.len() < self.bytes.len() - self.bytes.len() / 8 {
+                self.bytes = compressed;
+                codec = compression;
+            }
+        }
+        self.bytes
+            .push(self.format.version() << BLOCK_FORMAT_SHIFT | codec.tag());
         let crc = compute_crc_data_block(&self.bytes);
         self.bytes.extend_from_slice(&crc.to_le_bytes());
         self
     }
 }
+
+// A single key/value record as it is stored in a data block, detached from the block buffer.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct KvEntry {
+    pub(crate) key: Vec<u8>,
+    pub(crate) value: Vec<u8>,
+    pub(crate) seq: u64,
+    pub(crate) expires_at: u64, // ns since the epoch, 0 for entries that never expire
+    pub(crate) deleted: bool,
+    pub(crate) value_ptr: bool, // value is a ValuePointer into the value log
+    pub(crate) merge_operand: bool,
+}
+
+impl KvEntry {
+    // an expired entry reads like a tombstone, older versions stay hidden
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
+    }
+
+    // 0x00 value, VALUE_POINTER_FLAG separated value, MERGE_OPERAND_FLAG merge operand,
+    // anything else is a tombstone
+    fn flag(&self) -> u8 {
+        match (self.deleted, self.value_ptr, self.merge_operand) {
+            (true, _, _) => 0xFF,
+            (false, true, _) => VALUE_POINTER_FLAG,
+            (false, false, true) => MERGE_OPERAND_FLAG,
+            (false, false, false) => 0x00,
+        }
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        // [ seq(8) | expires_at(8) | ksz(8) | value_sz(8) | flag | key | value |  ]
+        let tombstone_in_byte: [u8; 1] = [self.flag()];
+        [
+            &self.seq.to_le_bytes(),
+            &self.expires_at.to_le_bytes(),
+            &(self.key.len() as u64).to_le_bytes(),
+            &(self.value.len() as u64).to_le_bytes(),
+            tombstone_in_byte.as_slice(),
+            self.key.as_slice(),
+            &self.value,
+        ]
+        .concat()
+    }
+}
+
+// Borrowed view of one record inside a verified data block buffer.
+struct BlockRecord<'a> {
+    seq: u64,
+    expires_at: u64,
+    key: Cow<'a, [u8]>, // prefix compressed blocks have to rebuild it
+    value: &'a [u8],
+    deleted: bool,
+    value_ptr: bool,
+    merge_operand: bool,
+}
+
+impl BlockRecord<'_> {
+    fn to_entry(&self) -> KvEntry {
+        KvEntry {
+            key: self.key.to_vec(),
+            value: self.value.to_vec(),
+            seq: self.seq,
+            expires_at: self.expires_at,
+            deleted: self.deleted,
+            value_ptr: self.value_ptr,
+            merge_operand: self.merge_operand,
+        }
+    }
+}
+
 // put the cold data into a SStable cold data vector(sparse index, etc)* //
 pub struct SSTable {
-    id: u64,
+    pub(crate) id: u64,
     file: File,
-    file_path: PathBuf,
-    file_size: u64,
-    min_key: Vec<u8>,
-    max_key: Vec<u8>,
+    pub(crate) file_path: PathBuf,
+    pub(crate) file_size: u64,
+    pub(crate) min_key: Vec<u8>,
+    pub(crate) max_key: Vec<u8>,
     sparse_index: Vec<(Vec<u8>, u64, u64)>, // keysz | offset | datablock block length ( before CRC, which means you need to read the next 4 bytes and compute the crc)
     bloom_filter: BloomFilter,
-    corrupted: bool,
+    pub(crate) level: u32,
+    pub(crate) max_seq: u64, // newest sequence number in the table
+    pub(crate) range_tombstones: Vec<RangeTombstone>,
+    max_block_size: u64, // longer blocks are read as corruption
+    codecs: Codecs,      // decompress the blocks, by the tag each one is stored with
 }
 
 impl SSTable {
     // pass a path, reads footer of file and builds an SStable to have in memory for faster lookup
-    fn load(path: &Path) -> Result<Self> {
+    pub(crate) fn load(path: &Path, table: &TableOptions) -> Result<Self> {
+        let max_block_size = table.max_block_size;
         // open reader of file
         // start reading backwards and return the metadata in a SST
-        //// footer is :
-        // sparse_index | bloom_filter | min key | max key | sizeof(sparse_index) | sparse_index_offset| sizeof(bloom_filter) | bloom filter_offset | sizeof(minkey) | minkey offset | sizeof(maxkey) | maxkey offset | 64 bytes(not including min and max key)
         let mut f = File::open(path)?;
         let id = path
             .file_stem()
@@ -253,22 +367,32 @@ impl SSTable {
             })
             .unwrap();
 
-        f.seek(SeekFrom::End(-40))?;
-        let mut footer = [0u8; 40];
-        f.read_exact(&mut footer)?;
         let file_length = f.metadata()?.len();
+        if file_length < SSTABLE_FOOTER_SIZE {
+            return Err(DbError::DataCorrupted(DataCorruptedErr {
+                offset: 0,
+                file_path: path.to_path_buf(),
+                reason: CorruptionType::LengthMismatch {
+                    expected: SSTABLE_FOOTER_SIZE as usize,
+                    found: file_length as usize,
+                },
+            }));
+        }
+        f.seek(SeekFrom::End(-(SSTABLE_FOOTER_SIZE as i64)))?;
+        let mut footer = [0u8; SSTABLE_FOOTER_SIZE as usize];
+        f.read_exact(&mut footer)?;
         // get data lengths from footer, and offsets
         // then read all the data you need to one buffer, then slice into it for each value
         // this can inside a deserialize_footer function instead of here
-        //PROBLEM: make sure sizes are safe, could be corrupted data.
-        // PROBLEM 1.1: I dont need to save everything in the footer. For example, bloom filter offset can be found by doing sparse_i_offset + sparse_i_size
-        // footer is: sparse_index | bloom_f | min_k | max_k | (footer starts here -> ) sparse_index_offset | sparse_index_size | bloom_filter_size | min_k size | max_k_size
-        // 40 bytes instead of 64
+        // footer is: range_tombstones | sparse_index | bloom_f | min_k | max_k | (footer starts here -> ) sparse_index_offset | sparse_index_size | bloom_filter_size | min_k size | max_k_size | level | max_seq | range_tombstones_size
         let sparse_index_offset = u64::from_le_bytes(footer[0..8].try_into().unwrap());
         let size_of_sparse_index = u64::from_le_bytes(footer[8..16].try_into().unwrap());
         let size_of_bloom_filter = u64::from_le_bytes(footer[16..24].try_into().unwrap());
         let size_of_min_key = u64::from_le_bytes(footer[24..32].try_into().unwrap());
         let size_of_max_key = u64::from_le_bytes(footer[32..40].try_into().unwrap());
+        let level = u64::from_le_bytes(footer[40..48].try_into().unwrap()) as u32;
+        let max_seq = u64::from_le_bytes(footer[48..56].try_into().unwrap());
+        let size_of_range_tombstones = u64::from_le_bytes(footer[56..64].try_into().unwrap());
 
         let full_data_length = size_of_sparse_index
             .checked_add(size_of_bloom_filter)
@@ -289,7 +413,11 @@ impl SSTable {
                 })
             })?;
 
-        if full_data_length > file_length {
+        if full_data_length > file_length
+            || sparse_index_offset
+                .checked_add(full_data_length)
+                .is_none_or(|end| end + SSTABLE_FOOTER_SIZE != file_length)
+        {
             return Err(DbError::DataCorrupted(DataCorruptedErr {
                 offset: sparse_index_offset,
                 file_path: path.to_path_buf(),
@@ -323,6 +451,30 @@ impl SSTable {
             .collect();
 
         let parsed_sparse_index = SparseIndex::parse_sparse_index(sparse_index);
+
+        // the range tombstone block sits right in front of the sparse index
+        let range_tombstones = match size_of_range_tombstones {
+            0 => Vec::new(),
+            size => {
+                let corrupted = |reason| {
+                    DbError::DataCorrupted(DataCorruptedErr {
+                        offset: sparse_index_offset.saturating_sub(size),
+                        file_path: path.to_path_buf(),
+                        reason,
+                    })
+                };
+                if size > sparse_index_offset || size > max_block_size {
+                    return Err(corrupted(CorruptionType::BufferExceedsMaxLength {
+                        size,
+                        max_size: sparse_index_offset.min(max_block_size),
+                    }));
+                }
+                let mut block = vec![0u8; size as usize];
+                f.read_exact_at(&mut block, sparse_index_offset - size)?;
+                range_del::decode_block(&block).map_err(corrupted)?
+            }
+        };
+
         Ok(SSTable {
             id,
             file: f,
@@ -333,466 +485,725 @@ impl SSTable {
             sparse_index: parsed_sparse_index,
             bloom_filter: BloomFilter {
                 bits: bloomf_filter_64,
-                num_bits: (size_of_bloom_filter / 8),
+                num_bits: (size_of_bloom_filter * 8),
             },
-            corrupted: false,
+            level,
+            max_seq,
+            range_tombstones,
+            max_block_size,
+            codecs: table.codecs.clone(),
         })
     }
 
-    fn binary_search_sparse_index(&self, key: &[u8]) -> Option<(u64, u64)> {
-        // first u64 is the offset, the second is the datablock size
-        if self.sparse_index.is_empty() {
-            return None;
+    // index of the data block key would live in
+    // first block that can hold key, the last one starting below it. Versions of the key may
+    // go on in the blocks after it
+    fn binary_search_sparse_index(&self, key: &[u8]) -> Option<usize> {
+        let below = self
+            .sparse_index
+            .partition_point(|(first_key, _, _)| first_key.as_slice() < key);
+        match below.checked_sub(1) {
+            Some(block_idx) => Some(block_idx),
+            None => self
+                .sparse_index
+                .first()
+                .filter(|(first_key, _, _)| first_key.as_slice() == key)
+                .map(|_| 0),
+        }
+    }
+
+    // reads a data block, verifies its crc and decompresses it. data_len does not include the
+    // 4 crc bytes
+    fn read_block(&self, offset: u64, data_len: u64) -> Result<(BlockFormat, Vec<u8>)> {
+        if data_len > self.max_block_size {
+            return Err(DbError::DataCorrupted(DataCorruptedErr {
+                offset,
+                file_path: self.file_path.clone(),
+                reason: CorruptionType::BufferExceedsMaxLength {
+                    size: data_len,
+                    max_size: self.max_block_size,
+                },
+            }));
         }
+        // we read CRC here because data_len doesnt take into account the 4 bytes for crc
+        let mut data_buffer = vec![0u8; data_len as usize + 4];
+        self.file.read_exact_at(&mut data_buffer, offset)?;
 
-        let mut lo: i64 = 0;
-        let mut hi: i64 = (self.sparse_index.len() - 1) as i64;
+        let crc_bytes = data_buffer.split_off(data_len as usize);
+        let crc_from_buff = u32::from_le_bytes(crc_bytes.try_into().unwrap());
+        let fresh_crc = compute_crc_data_block(&data_buffer);
 
-        let mut best_candidate: Option<(u64, u64)> = None;
-        while lo <= hi {
-            let mid = lo + (hi - lo) / 2;
-            match self.sparse_index.get(mid as usize) {
-                Some(entry) => {
-                    let key_in_index = entry.0.as_slice();
-                    if key_in_index < key {
-                        best_candidate = Some((entry.1, entry.2));
-                        lo = mid + 1;
-                    } else if key_in_index > key {
-                        hi = mid - 1;
-                    } else {
-                        return Some((entry.1, entry.2));
-                    }
-                }
-                None => unreachable!(),
-            }
+        if fresh_crc != crc_from_buff {
+            return Err(DbError::DataCorrupted(DataCorruptedErr {
+                offset,
+                file_path: self.file_path.clone(),
+                reason: CorruptionType::CrcMismatch {
+                    expected: crc_from_buff,
+                    found: fresh_crc,
+                },
+            }));
         }
 
-        best_candidate
+        let corrupted = |reason| {
+            DbError::DataCorrupted(DataCorruptedErr {
+                offset,
+                file_path: self.file_path.clone(),
+                reason,
+            })
+        };
+        let block_type = data_buffer
+            .pop()
+            .ok_or_else(|| corrupted(CorruptionType::Other("empty data block".to_string())))?;
+        let unknown_type = || {
+            corrupted(CorruptionType::Other(format!(
+                "unknown block type {}",
+                block_type
+            )))
+        };
+        let codec = self
+            .codecs
+            .get(block_type & BLOCK_CODEC_MASK)
+            .ok_or_else(unknown_type)?;
+        let format =
+            BlockFormat::from_version(block_type >> BLOCK_FORMAT_SHIFT).ok_or_else(unknown_type)?;
+        let data = codec
+            .decompress(&data_buffer, self.max_block_size as usize)
+            .map_err(corrupted)?;
+        Ok((format, data))
     }
-}
-struct AVL {
-    root: Option<Box<Node>>,
-    threshold: u64,
-    size: u64,
-    buf_file: Option<BufWriter<File>>, // to write to sstable on flush
-}
-#[derive(PartialEq, Clone, Debug)]
-struct AvlEntry {
-    key: Vec<u8>,
-    value: Vec<u8>,
-    deleted: bool,
-}
-#[derive(PartialEq, Clone, Debug)]
-struct Node {
-    entry: AvlEntry,
-    height: u64,
-    left: Option<Box<Node>>,
-    right: Option<Box<Node>>,
-}
 
-impl Node {
-    fn serialize_kv(&self) -> Vec<u8> {
-        // return [ tstamp(8) | ksz(8) | value_sz(8) | tombstone | key | value |  ]
-        let tstamp = new_timestamp().to_le_bytes();
-        let ksz = self.entry.key.len().to_le_bytes();
-        let vsz = self.entry.value.len().to_le_bytes();
-        let tombstone_in_byte: [u8; 1] = [if self.entry.deleted { 0xFF } else { 0x00 }];
+    fn decode_block(&self, block_idx: usize) -> Result<DataBlock> {
+        let (_, offset, data_len) = self.sparse_index[block_idx];
+        let (format, mut data) = self.read_block(offset, data_len)?;
+        let corrupted = |reason: &str| {
+            DbError::DataCorrupted(DataCorruptedErr {
+                offset,
+                file_path: self.file_path.clone(),
+                reason: CorruptionType::Other(reason.to_string()),
+            })
+        };
 
-        [
-            &tstamp,
-            &ksz,
-            &vsz,
-            tombstone_in_byte.as_slice(),
-            self.entry.key.as_slice(),
-            &self.entry.value,
-        ]
-        .concat()
-    }
-}
+        let mut restart_offsets = Vec::new();
+        if format == BlockFormat::PrefixCompressed {
+            let count_start = data
+                .len()
+                .checked_sub(4)
+                .ok_or_else(|| corrupted("block too short for its restart count"))?;
+            let count = u32::from_le_bytes(data[count_start..].try_into().unwrap()) as usize;
+            let array_start = count
+                .checked_mul(4)
+                .and_then(|len| count_start.checked_sub(len))
+                .ok_or_else(|| corrupted("restart array larger than the block"))?;
+            restart_offsets = data[array_start..count_start]
+                .chunks_exact(4)
+                .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()) as usize)
+                .collect();
+            data.truncate(array_start);
+        }
 
-impl AVL {
-    fn new(threshold: u64) -> Self {
-        Self {
-            root: None,
-            threshold,
-            size: 0,
-            buf_file: None,
+        let mut record_offsets = Vec::new();
+        let mut restarts = Vec::new();
+        let mut key_len = 0;
+        let mut pos = 0;
+        while pos < data.len() {
+            let idx = record_offsets.len();
+            record_offsets.push(pos);
+            pos = match format {
+                BlockFormat::Plain => self.parse_record(offset, &data, pos)?.1,
+                BlockFormat::PrefixCompressed => {
+                    let (record, next_pos) = self.parse_prefix_record(offset, &data, pos)?;
+                    let is_restart = restart_offsets.get(restarts.len()) == Some(&pos);
+                    if is_restart {
+                        restarts.push(idx);
+                    }
+                    // a restart stores its whole key, every other record shares part of the previous one
+                    let valid = match is_restart {
+                        true => record.shared == 0,
+                        false => idx > 0 && record.shared <= key_len,
+                    };
+                    if !valid {
+                        return Err(corrupted("shared key prefix does not match the block"));
+                    }
+                    key_len = record.shared + record.suffix.len();
+                    next_pos
+                }
+            };
         }
+        if restarts.len() != restart_offsets.len() {
+            return Err(corrupted("restart point does not start a record"));
+        }
+
+        Ok(DataBlock {
+            offset,
+            format,
+            data,
+            record_offsets,
+            restarts,
+        })
     }
 
-    fn get(&self, key: &[u8]) -> Option<&[u8]> {
-        if let Some(mut curr) = self.root.as_ref() {
-            loop {
-                if curr.entry.key == key {
-                    return Some(&curr.entry.value);
-                }
-                if curr.entry.key.as_slice() > key {
-                    curr = curr.left.as_ref()?;
-                } else {
-                    curr = curr.right.as_ref()?;
-                }
-            }
-        } else {
-            None
+    // only blocks that decoded cleanly end up in the cache
+    fn block(&self, block_idx: usize, cache: Option<&BlockCache>) -> Result<Arc<DataBlock>> {
+        let Some(cache) = cache else {
+            return Ok(Arc::new(self.decode_block(block_idx)?));
+        };
+        let offset = self.sparse_index[block_idx].1;
+        if let Some(block) = cache.get(self.id, offset) {
+            return Ok(block);
         }
+        let block = Arc::new(self.decode_block(block_idx)?);
+        cache.insert(self.id, offset, Arc::clone(&block));
+        Ok(block)
     }
 
-    fn update_height(node: &mut Box<Node>) {
-        let left_height = if let Some(x) = node.left.as_ref() {
-            x.height as i64
-        } else {
-            -1
+    // parses a record of a prefix compressed block, returns it with the position of the next record
+    fn parse_prefix_record<'a>(
+        &self,
+        block_offset: u64,
+        data_buffer: &'a [u8],
+        pos: usize,
+    ) -> Result<(PrefixRecord<'a>, usize)> {
+        let length_mismatch = |expected| {
+            DbError::DataCorrupted(DataCorruptedErr {
+                offset: block_offset + pos as u64,
+                file_path: self.file_path.clone(),
+                reason: CorruptionType::LengthMismatch {
+                    expected,
+                    found: data_buffer.len(),
+                },
+            })
         };
+        let header = data_buffer
+            .get(pos..pos + PREFIX_ENTRY_HEADER_SIZE)
+            .ok_or_else(|| length_mismatch(pos + PREFIX_ENTRY_HEADER_SIZE))?;
+
+        // [ shared(4) | unshared(4) | value_sz(4) | seq(8) | expires_at(8) | deletedflag(1) | key suffix | value ]
+        let u32_at = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap()) as usize;
+        let (shared, unshared, vsz) = (u32_at(0), u32_at(4), u32_at(8));
+        let seq = u64::from_le_bytes(header[12..20].try_into().unwrap());
+        let expires_at = u64::from_le_bytes(header[20..28].try_into().unwrap());
+        let (deleted, value_ptr, merge_operand) = parse_flag(header[28]);
+
+        // both sizes came from u32s, no overflow
+        let key_start = pos + PREFIX_ENTRY_HEADER_SIZE;
+        let val_start = key_start + unshared;
+        let val_end = val_start + vsz;
+        if val_end > data_buffer.len() {
+            return Err(length_mismatch(val_end));
+        }
 
-        let right_height = if let Some(x) = node.right.as_ref() {
-            x.height as i64
-        } else {
-            -1
+        let record = PrefixRecord {
+            shared,
+            suffix: &data_buffer[key_start..val_start],
+            value: &data_buffer[val_start..val_end],
+            seq,
+            expires_at,
+            deleted,
+            value_ptr,
+            merge_operand,
         };
-        node.height = 1 + max(left_height, right_height) as u64;
+        Ok((record, val_end))
     }
-    fn insert(&mut self, curr: Option<Box<Node>>, n: Node) -> Option<Box<Node>> {
-        if let Some(mut node) = curr {
-            if n.entry.key == node.entry.key {
-                node.entry.value = n.entry.value;
-                node.entry.deleted = n.entry.deleted;
-                return Some(node);
-            }
-            if n.entry.key < node.entry.key {
-                node.left = self.insert(node.left.take(), n);
-            } else {
-                node.right = self.insert(node.right.take(), n);
-            }
 
-            node = Self::balance(node);
-            Some(node)
-        } else {
-            Some(Box::new(n))
+    // parses the record starting at pos, returns it with the position of the next record
+    fn parse_record<'a>(
+        &self,
+        block_offset: u64,
+        data_buffer: &'a [u8],
+        pos: usize,
+    ) -> Result<(BlockRecord<'a>, usize)> {
+        if pos + ENTRY_HEADER_SIZE > data_buffer.len() {
+            return Err(DbError::DataCorrupted(DataCorruptedErr {
+                offset: block_offset + pos as u64,
+                file_path: self.file_path.clone(),
+                reason: CorruptionType::Other(format!(
+                    "truncated record header at buffer position {} (buffer len {})",
+                    pos,
+                    data_buffer.len(),
+                )),
+            }));
         }
-    }
-    /*
 
-    */
+        // [ seq(8) | expires_at(8) | ksz(8) | value_sz(8) | deletedflag(1) | key | value ]
+        let seq = u64::from_le_bytes(data_buffer[pos..pos + 8].try_into().unwrap());
+        let expires_at = u64::from_le_bytes(data_buffer[pos + 8..pos + 16].try_into().unwrap());
+        let ksz = u64::from_le_bytes(data_buffer[pos + 16..pos + 24].try_into().unwrap()) as usize;
+        let vsz = u64::from_le_bytes(data_buffer[pos + 24..pos + 32].try_into().unwrap()) as usize;
+        let (deleted, value_ptr, merge_operand) = parse_flag(data_buffer[pos + 32]);
+        // check ksz and vsz doesnt overflow
+        let key_start = pos + ENTRY_HEADER_SIZE;
+
+        let val_end = key_start
+            .checked_add(ksz)
+            .and_then(|v| v.checked_add(vsz))
+            .ok_or_else(|| {
+                DbError::DataCorrupted(DataCorruptedErr {
+                    offset: block_offset + pos as u64,
+                    file_path: self.file_path.clone(),
+                    reason: CorruptionType::Other(format!(
+                        "record size overflow: ksz={ksz}, vsz={vsz}"
+                    )),
+                })
+            })?;
 
-    fn put(&mut self, key: &[u8], value: &[u8]) {
-        let n = Node {
-            entry: AvlEntry {
-                key: key.to_vec(),
-                value: value.to_vec(),
-                deleted: false,
-            },
-            height: 0,
-            left: None,
-            right: None,
+        if val_end > data_buffer.len() {
+            return Err(DbError::DataCorrupted(DataCorruptedErr {
+                offset: block_offset + pos as u64,
+                file_path: self.file_path.clone(),
+                reason: CorruptionType::LengthMismatch {
+                    expected: val_end,
+                    found: data_buffer.len(),
+                },
+            }));
+        }
+
+        let val_start = key_start + ksz; // if val_end is safe then this is safe(no overflow)
+        let record = BlockRecord {
+            seq,
+            expires_at,
+            key: Cow::Borrowed(&data_buffer[key_start..val_start]),
+            value: &data_buffer[val_start..val_end],
+            deleted,
+            value_ptr,
+            merge_operand,
         };
-        let root = self.root.take();
-        self.root = self.insert(root, n);
-        self.size += 1;
+        Ok((record, val_end))
     }
 
-    fn balance(mut node: Box<Node>) -> Box<Node> {
-        Self::update_height(&mut node);
-        let bf = Self::compute_balance_factor_of_node(&node);
+    // bypasses the block cache, compaction reads every block once and would only evict hot ones
+    pub(crate) fn iter(self: &Arc<Self>) -> SsTableIterator {
+        SsTableIterator {
+            table: Arc::clone(self),
+            cache: None,
+            next_block: 0,
+            entries: Vec::new().into_iter(),
+        }
+    }
 
-        if bf > 1 {
-            // left heavy
+    pub(crate) fn cached_iter(self: &Arc<Self>, cache: &Arc<BlockCache>) -> SsTableIterator {
+        SsTableIterator {
+            cache: Some(Arc::clone(cache)),
+            ..self.iter()
+        }
+    }
+}
 
-            let left_node = node.left.as_mut().unwrap();
-            match Self::compute_balance_factor_of_node(left_node) {
-                bf if bf >= 0 => {
-                    let left = node.left.take().unwrap();
+// record of a prefix compressed block, its key is the first `shared` bytes of the previous key
+// followed by suffix
+struct PrefixRecord<'a> {
+    shared: usize,
+    suffix: &'a [u8],
+    value: &'a [u8],
+    seq: u64,
+    expires_at: u64,
+    deleted: bool,
+    value_ptr: bool,
+    merge_operand: bool,
+}
 
-                    node = Self::right_rotation(node, left);
-                }
-                _ => {
-                    let mut left_child = node.left.take().unwrap();
-                    let right_of_left = left_child.right.take().unwrap();
-                    left_child = Self::left_rotation(left_child, right_of_left);
-                    node = Self::right_rotation(node, left_child)
-                }
-            }
-        } else if bf < -1 {
-            // right heavy
+impl<'a> PrefixRecord<'a> {
+    // appends this record's key onto the previous one
+    fn rebuild_key(&self, key: &mut Vec<u8>) {
+        key.truncate(self.shared);
+        key.extend_from_slice(self.suffix);
+    }
 
-            let right_node = node.right.as_mut().unwrap();
-            match Self::compute_balance_factor_of_node(right_node) {
-                bf if bf <= 0 => {
-                    let right = node.right.take().unwrap();
-                    node = Self::left_rotation(node, right);
-                }
-                _ => {
-                    let mut right_child = node.right.take().unwrap();
-                    let left_of_right = right_child.left.take().unwrap();
-                    right_child = Self::right_rotation(right_child, left_of_right);
-                    node = Self::left_rotation(node, right_child);
-                }
-            }
+    fn with_key(&self, key: Vec<u8>) -> BlockRecord<'a> {
+        BlockRecord {
+            seq: self.seq,
+            expires_at: self.expires_at,
+            key: Cow::Owned(key),
+            value: self.value,
+            deleted: self.deleted,
+            value_ptr: self.value_ptr,
+            merge_operand: self.merge_operand,
         }
+    }
+}
 
-        node
+// a data block whose crc has been verified, with the start of every record already located
+#[derive(Default)]
+pub(crate) struct DataBlock {
+    offset: u64,
+    format: BlockFormat,
+    data: Vec<u8>, // the records, the restart array is already parsed into restarts
+    record_offsets: Vec<usize>,
+    restarts: Vec<usize>, // index of every record that stores its whole key
+}
+
+impl DataBlock {
+    // number of records
+    fn len(&self) -> usize {
+        self.record_offsets.len()
     }
 
-    fn left_rotation(mut parent: Box<Node>, mut child: Box<Node>) -> Box<Node> {
-        // parent and child.right
-        parent.right = child.left.take();
+    // bytes charged against the block cache
+    pub(crate) fn size(&self) -> usize {
+        self.data.len() + (self.record_offsets.len() + self.restarts.len()) * size_of::<usize>()
+    }
 
-        child.left = Some(parent);
+    fn prefix_record<'a>(&'a self, table: &SSTable, idx: usize) -> Result<PrefixRecord<'a>> {
+        let (record, _) =
+            table.parse_prefix_record(self.offset, &self.data, self.record_offsets[idx])?;
+        Ok(record)
+    }
 
-        if let Some(left) = child.left.as_mut() {
-            Self::update_height(left);
+    fn record<'a>(&'a self, table: &SSTable, idx: usize) -> Result<BlockRecord<'a>> {
+        if self.format == BlockFormat::Plain {
+            let (record, _) =
+                table.parse_record(self.offset, &self.data, self.record_offsets[idx])?;
+            return Ok(record);
         }
-        Self::update_height(&mut child);
-        child
+
+        // the key is rebuilt from the closest restart point at or before idx
+        let restart = self.restarts[self.restarts.partition_point(|&r| r <= idx) - 1];
+        let mut key = Vec::new();
+        for i in restart..idx {
+            self.prefix_record(table, i)?.rebuild_key(&mut key);
+        }
+        let record = self.prefix_record(table, idx)?;
+        record.rebuild_key(&mut key);
+        Ok(record.with_key(key))
     }
-    fn right_rotation(mut parent: Box<Node>, mut child: Box<Node>) -> Box<Node> {
-        // parent and child.left
-        parent.left = child.right.take();
-        child.right = Some(parent);
-        if let Some(right) = child.right.as_mut() {
-            Self::update_height(right);
+
+    // index of the first record for which goes_right is false
+    fn partition(&self, table: &SSTable, goes_right: impl Fn(&[u8]) -> bool) -> Result<usize> {
+        if self.format == BlockFormat::Plain {
+            let (mut lo, mut hi) = (0, self.len());
+            while lo < hi {
+                let mid = lo + (hi - lo) / 2;
+                if goes_right(&self.record(table, mid)?.key) {
+                    lo = mid + 1;
+                } else {
+                    hi = mid;
+                }
+            }
+            return Ok(lo);
         }
-        Self::update_height(&mut child);
-        child
-    }
 
-    fn compute_balance_factor_of_node(node: &Node) -> i32 {
-        let bf_l = if let Some(x) = node.left.as_ref() {
-            x.height as i32
-        } else {
-            -1
-        };
-        let bf_r = if let Some(x) = node.right.as_ref() {
-            x.height as i32
-        } else {
-            -1
+        // restart points hold whole keys, so the search only rebuilds keys inside one interval
+        let (mut lo, mut hi) = (0, self.restarts.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if goes_right(self.prefix_record(table, self.restarts[mid])?.suffix) {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        let Some(interval) = lo.checked_sub(1) else {
+            return Ok(0);
         };
-        bf_l - bf_r
+        let end = self.restarts.get(lo).copied().unwrap_or(self.len());
+        let mut key = Vec::new();
+        for idx in self.restarts[interval]..end {
+            self.prefix_record(table, idx)?.rebuild_key(&mut key);
+            if !goes_right(&key) {
+                return Ok(idx);
+            }
+        }
+        Ok(end)
     }
-    fn take_min(mut curr: Box<Node>) -> (Option<Box<Node>>, Option<Box<Node>>) {
-        // in order successor.
-        // we have passed the right child here
-        // go left till the end
 
-        // None
-        if curr.left.is_none() {
-            let right = curr.right.take();
-            return (Some(curr), right);
+    // every record in order, keys are rebuilt once for the whole block
+    fn entries(&self, table: &SSTable) -> Result<Vec<KvEntry>> {
+        let mut entries = Vec::with_capacity(self.len());
+        let mut key = Vec::new();
+        for idx in 0..self.len() {
+            let entry = match self.format {
+                BlockFormat::Plain => self.record(table, idx)?.to_entry(),
+                BlockFormat::PrefixCompressed => {
+                    let record = self.prefix_record(table, idx)?;
+                    record.rebuild_key(&mut key);
+                    record.with_key(key.clone()).to_entry()
+                }
+            };
+            entries.push(entry);
         }
-
-        let (min_node, left_node) = Self::take_min(curr.left.take().unwrap());
-        curr.left = left_node;
-        (min_node, Some(Self::balance(curr)))
+        Ok(entries)
     }
+}
 
-    fn delete(&mut self, key: &[u8]) {
-        let node = Node {
-            entry: AvlEntry {
-                key: key.to_vec(),
-                value: Vec::new(),
-                deleted: true,
-            },
-            height: 1,
-            left: None,
-            right: None,
-        };
+// Walks the records of an SSTable in key order, loading one data block at a time.
+// Holds its own handle to the table so it stays readable after compaction replaces it.
+pub(crate) struct SsTableIterator {
+    table: Arc<SSTable>,
+    cache: Option<Arc<BlockCache>>,
+    next_block: usize,
+    entries: std::vec::IntoIter<KvEntry>, // rest of the current block
+}
 
-        let root = self.root.take();
-        self.root = self.insert(root, node);
-    }
-    fn delete_remove_node(&mut self, curr: Option<Box<Node>>, key: &[u8]) -> Option<Box<Node>> {
-        if let Some(mut node) = curr {
-            if node.entry.key == key {
-                if node.left.is_none() && node.right.is_none() {
-                    return None;
-                } else if node.right.is_some() != node.left.is_some() {
-                    // XOR
-                    // return the child
-                    if let Some(_x) = node.left.as_ref() {
-                        return node.left;
-                    } else {
-                        return node.right;
-                    }
-                } else {
-                    // safe to unwrap here
-                    let (successor, new_right) = Self::take_min(node.right.take().unwrap());
-                    {
-                        let succ = successor.unwrap();
-                        node.right = new_right;
-                        node.entry.value = succ.entry.value;
-                        node.entry.key = succ.entry.key;
-                    }
+impl SsTableIterator {
+    // makes sure entries is not empty, None once the table is exhausted
+    fn fill_block(&mut self) -> Option<Result<()>> {
+        while self.entries.as_slice().is_empty() {
+            if self.next_block >= self.table.sparse_index.len() {
+                return None;
+            }
+            let block_idx = self.next_block;
+            self.next_block += 1;
+            let entries = self
+                .table
+                .block(block_idx, self.cache.as_deref())
+                .and_then(|block| block.entries(&self.table));
+            match entries {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(err) => {
+                    // stop here, a corrupted block means nothing after it can be trusted
+                    self.fuse();
+                    return Some(Err(err));
                 }
-                return Some(Self::balance(node));
             }
+        }
+        Some(Ok(()))
+    }
 
-            if node.entry.key.as_slice() < key {
-                node.right = self.delete_remove_node(node.right.take(), key);
-            } else {
-                node.left = self.delete_remove_node(node.left.take(), key);
+    fn fuse(&mut self) {
+        self.next_block = self.table.sparse_index.len();
+        self.entries = Vec::new().into_iter();
+    }
+
+    // positions the iterator on the first record with a key >= key
+    pub(crate) fn seek(&mut self, key: &[u8]) -> Result<()> {
+        // the sparse index tells us which block the key would start in, no need to read the others
+        self.next_block = self
+            .table
+            .sparse_index
+            .partition_point(|(first_key, _, _)| first_key.as_slice() < key)
+            .saturating_sub(1);
+        self.entries = Vec::new().into_iter();
+
+        loop {
+            match self.fill_block() {
+                None => return Ok(()),
+                Some(Err(err)) => return Err(err),
+                Some(Ok(())) => {}
             }
-            Some(Self::balance(node))
-        } else {
-            curr
+            if self.entries.as_slice()[0].key.as_slice() >= key {
+                return Ok(());
+            }
+            self.entries.next();
+        }
+    }
+}
+
+impl Iterator for SsTableIterator {
+    type Item = Result<KvEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(err) = self.fill_block()? {
+            return Some(Err(err));
         }
+
+        self.entries.next().map(Ok)
     }
+}
+
+// How the tables of a column family are written and read, built from the engine Options
+#[derive(Clone)]
+pub(crate) struct TableOptions {
+    pub(crate) compression: CompressionType, // codec for the blocks, any codec in codecs can be read
+    pub(crate) codecs: Codecs,
+    pub(crate) block_size: u64,
+    pub(crate) max_block_size: u64,
+    pub(crate) bloom_bits_per_key: u64,
+}
 
-    fn get_min_node(node: &Option<Box<Node>>) -> Option<&Vec<u8>> {
-        let mut curr = node.as_ref()?;
-        while let Some(n) = curr.left.as_ref() {
-            curr = n
+impl From<CompressionType> for TableOptions {
+    fn from(compression: CompressionType) -> Self {
+        Self {
+            compression,
+            codecs: Codecs::default(),
+            block_size: DATA_BLOCK,
+            max_block_size: MAX_BLOCK_SIZE,
+            bloom_bits_per_key: BLOOM_BITS_PER_KEY,
         }
+    }
+}
 
-        Some(&curr.entry.key)
+// Streams entries (ascending key, newest version first) into a new SSTable file.
+// Used by memtable flushes and by compaction, so every table on disk has the same layout.
+pub(crate) struct SsTableWriter {
+    writer: BufWriter<File>,
+    tmp_path: PathBuf,
+    final_path: PathBuf,
+    data_block: Option<SsTableDataBlock>,
+    sparse_index: SparseIndex,
+    key_hashes: Vec<u128>, // bloom filter is sized once we know how many keys we wrote
+    offset: u64,
+    min_key: Option<Vec<u8>>,
+    max_key: Vec<u8>,
+    level: u32,
+    max_seq: u64,
+    options: TableOptions,
+    block_format: BlockFormat,
+    range_tombstones: Vec<RangeTombstone>,
+}
+
+impl SsTableWriter {
+    pub(crate) fn new(
+        ss_path_tmp: &Path,
+        ss_path_final: &Path,
+        level: u32,
+        options: TableOptions,
+    ) -> Result<Self> {
+        Ok(Self {
+            writer: BufWriter::new(File::create(ss_path_tmp)?),
+            tmp_path: ss_path_tmp.to_path_buf(),
+            final_path: ss_path_final.to_path_buf(),
+            data_block: None,
+            sparse_index: SparseIndex::new(),
+            key_hashes: Vec::new(),
+            offset: 0,
+            min_key: None,
+            max_key: Vec::new(),
+            level,
+            max_seq: 0,
+            options,
+            block_format: BlockFormat::default(),
+            range_tombstones: Vec::new(),
+        })
     }
-    fn get_max_node(node: &Option<Box<Node>>) -> Option<&Vec<u8>> {
-        let mut curr = node.as_ref()?;
 
-        while let Some(n) = curr.right.as_ref() {
-            curr = n
+    pub(crate) fn add(&mut self, entry: &KvEntry) -> Result<()> {
+        match self.data_block.as_mut() {
+            // versions of a key may run on into the next block, readers follow them there
+            Some(block) if !block.is_finished(self.options.block_size) => {
+                block.append_to_block(entry)
+            }
+            _ => {
+                self.finish_data_block()?;
+                let mut new_ss_db = SsTableDataBlock::new(&entry.key, self.block_format);
+                new_ss_db.append_to_block(entry);
+                self.data_block = Some(new_ss_db);
+            }
+        }
+
+        self.key_hashes.push(xxh3_128(&entry.key));
+        if self.min_key.is_none() {
+            self.min_key = Some(entry.key.clone());
         }
+        self.max_key.clone_from(&entry.key);
+        self.max_seq = max(self.max_seq, entry.seq);
+        Ok(())
+    }
+
+    // key of the last entry added
+    pub(crate) fn last_key(&self) -> &[u8] {
+        &self.max_key
+    }
+
+    // range tombstones go into their own block, in any order
+    pub(crate) fn add_range_tombstone(&mut self, tombstone: RangeTombstone) {
+        self.max_seq = max(self.max_seq, tombstone.seq);
+        self.range_tombstones.push(tombstone);
+    }
+
+    // bytes written so far plus the block still being built
+    pub(crate) fn estimated_size(&self) -> u64 {
+        self.offset + self.data_block.as_ref().map_or(0, |b| b.size as u64)
+    }
 
-        Some(&curr.entry.key)
+    fn finish_data_block(&mut self) -> Result<()> {
+        if let Some(ss_data_block) = self.data_block.take() {
+            let full_block =
+                ss_data_block.full_data_block(self.options.compression, &self.options.codecs);
+            // the sparse index length covers everything but the crc
+            let data_block_len = full_block.bytes.len() as u64 - 4;
+            self.writer.write_all(&full_block.bytes)?;
+            self.sparse_index
+                .add_entry(&full_block.starting_key, self.offset, data_block_len);
+            self.offset += full_block.bytes.len() as u64;
+        }
+        Ok(())
     }
 
     fn serialize_sstable_footer(
-        offset: &mut u64,
+        &self,
         min_key: &[u8],
         max_key: &[u8],
-        sizeof_si: u64,
         sizeof_bf: u64,
+        sizeof_rt: u64,
     ) -> Vec<u8> {
-        // returns the footer
-        // | min key | max key | sizeof(sparse_index) | sparse_index_offset| sizeof(bloom_filter) | bloom filter_offset | sizeof(minkey) | minkey offset | sizeof(maxkey) | maxkey offset |
-        // PROBLEM: Change the footer to be 40 bytes like the fn load reads it
+        let sparse_index_offset = self.offset;
+        // | min key | max key | sparse_index_offset | sizeof(sparse_index) | sizeof(bloom_filter) | sizeof(minkey) | sizeof(maxkey) | level | max_seq | sizeof(range_tombstones) |
+        // everything else can be derived from these: bf_offset = si_offset + sizeof(si) and so on
         let mut footer: Vec<u8> = Vec::new();
-        // offset is start_of_sparse_index
-
-        // TODO: I dont need to save all the offsets necessarily in the footer. They can mostly be derived from other offsets = si_offset + size
-        // Come up with a more space efficient footer. Works for now
-        let min_key_offset = *offset + sizeof_bf + sizeof_si;
-        let max_key_offset = min_key_offset + max_key.len() as u64;
         footer.extend_from_slice(min_key);
         footer.extend_from_slice(max_key);
-        footer.extend_from_slice(&sizeof_si.to_le_bytes());
-        footer.extend_from_slice(&offset.to_le_bytes());
-        *offset += sizeof_si;
+        footer.extend_from_slice(&sparse_index_offset.to_le_bytes());
+        footer.extend_from_slice(&self.sparse_index.size.to_le_bytes());
         footer.extend_from_slice(&sizeof_bf.to_le_bytes());
-        footer.extend_from_slice(&offset.to_le_bytes()); //bf offset
         footer.extend_from_slice(&(min_key.len() as u64).to_le_bytes());
-
-        footer.extend_from_slice(&min_key_offset.to_le_bytes());
         footer.extend_from_slice(&(max_key.len() as u64).to_le_bytes());
-        footer.extend_from_slice(&max_key_offset.to_le_bytes());
-
-        // serialize the footer, sparse_index
+        footer.extend_from_slice(&(self.level as u64).to_le_bytes());
+        footer.extend_from_slice(&self.max_seq.to_le_bytes());
+        footer.extend_from_slice(&sizeof_rt.to_le_bytes());
         footer
     }
 
-    fn build_sstable_recursive(
-        &self,
-        writer: &mut BufWriter<File>,
-        n: &Option<Box<Node>>,
-        bf: &mut BloomFilter,
-        data_block: &mut Option<SsTableDataBlock>,
-        sparse_index: &mut SparseIndex,
-        offset: &mut u64,
-    ) -> Result<()> {
-        if let Some(x) = n {
-            self.build_sstable_recursive(writer, &x.left, bf, data_block, sparse_index, offset)?;
-            if let Some(ss_data_block) = data_block {
-                match ss_data_block.is_finished() {
-                    true => {
-                        let owned_ss_data_block =
-                            data_block.take().expect("Expected a SsTableDataBlock");
-
-                        writer.write_all(&owned_ss_data_block.bytes)?;
-
-                        let data_block_len = owned_ss_data_block.bytes.len() as u64;
-                        sparse_index.add_entry(owned_ss_data_block, *offset);
-                        *offset += data_block_len;
-
-                        let mut new_ss_db = SsTableDataBlock::new(&x.entry.key);
-                        new_ss_db.append_to_block(&x.serialize_kv());
-                        *data_block = Some(new_ss_db);
-                    }
-                    false => {
-                        ss_data_block.append_to_block(&x.serialize_kv());
-                    }
-                }
-            } else {
-                let mut new_ss_db = SsTableDataBlock::new(&x.entry.key);
-                new_ss_db.append_to_block(&x.serialize_kv());
-                *data_block = Some(new_ss_db);
+    // writes index, bloom filter and footer, fsyncs and renames the tmp file into place
+    pub(crate) fn finish(mut self) -> Result<File> {
+        self.finish_data_block()?;
+        // the key range has to take in the tombstones so compaction picks up what they cover
+        let mut min_key = self.min_key.take();
+        let mut max_key = min_key.as_ref().map(|_| self.max_key.clone());
+        for t in &self.range_tombstones {
+            if min_key.as_ref().is_none_or(|k| t.start < *k) {
+                min_key = Some(t.start.clone());
+            }
+            if max_key.as_ref().is_none_or(|k| t.end > *k) {
+                max_key = Some(t.end.clone());
             }
-            let positions = get_hashed_key_positions(&x.entry.key, bf.num_bits as usize);
-            bf.set_bits(positions);
-            self.build_sstable_recursive(writer, &x.right, bf, data_block, sparse_index, offset)?;
         }
-        Ok(())
-    }
-
-    // What if engine crashes mid sync_avl execution? // check if need to be called on start/restart
-
-    fn sync_avl(&self, ss_path_tmp: &Path, ss_path_final: &Path) -> Result<File> {
-        let mut writer_1 = BufWriter::new(File::create(ss_path_tmp)?);
-        let mut data_block: Option<SsTableDataBlock> = None;
-
-        // sizeof(key) | key | offset | datablock block length ( before CRC )
-        let mut sparse_index = SparseIndex::new();
-        let mut bloom_filter = BloomFilter::new(self.size as usize * 10);
-
-        let min_k = Self::get_min_node(&self.root).ok_or_else(|| {
-            DbError::MissingKey("Min key missing in memtable during flushing operation".to_string())
-        })?;
-        let max_k = Self::get_max_node(&self.root).ok_or_else(|| {
-            DbError::MissingKey("Max key missing in memtable during flushing operation".to_string())
-        })?;
-
-        let mut file_offset: u64 = 0;
-        self.build_sstable_recursive(
-            &mut writer_1,
-            &self.root,
-            &mut bloom_filter,
-            &mut data_block,
-            &mut sparse_index,
-            &mut file_offset,
-        )?;
-
-        if let Some(last_db) = data_block {
-            let len = last_db.bytes.len() as u64;
-
-            writer_1.write_all(&last_db.bytes)?;
+        let (Some(min_key), Some(max_key)) = (min_key, max_key) else {
+            return Err(DbError::MissingKey(
+                "Min key missing while finishing SSTable".to_string(),
+            ));
+        };
 
-            sparse_index.add_entry(last_db, file_offset);
+        let mut range_tombstones_size = 0;
+        if !self.range_tombstones.is_empty() {
+            let block = range_del::encode_block(&self.range_tombstones);
+            self.writer.write_all(&block)?;
+            range_tombstones_size = block.len() as u64;
+            self.offset += range_tombstones_size;
+        }
 
-            file_offset += len; // length here is the start of sparse_index // 
+        let mut bloom_filter =
+            BloomFilter::new(self.key_hashes.len() * self.options.bloom_bits_per_key as usize);
+        for hash in &self.key_hashes {
+            bloom_filter.set_bits(get_positions_from_hash(
+                *hash,
+                bloom_filter.num_bits as usize,
+            ));
         }
-        let footer = Self::serialize_sstable_footer(
-            &mut file_offset,
-            min_k,
-            max_k,
-            sparse_index.size,
-            bloom_filter.num_bits,
+
+        let footer = self.serialize_sstable_footer(
+            &min_key,
+            &max_key,
+            (bloom_filter.bits.len() * 8) as u64,
+            range_tombstones_size,
         );
 
-        for entry in &sparse_index.index_entries {
-            writer_1.write_all(entry)?;
+        for entry in &self.sparse_index.index_entries {
+            self.writer.write_all(entry)?;
         }
         for word in &bloom_filter.bits {
-            writer_1.write_all(&word.to_le_bytes())?;
+            self.writer.write_all(&word.to_le_bytes())?;
         }
-        writer_1.write_all(&footer)?;
+        self.writer.write_all(&footer)?;
 
-        let f = writer_1.into_inner().map_err(|e| {
+        let f = self.writer.into_inner().map_err(|e| {
             DbError::FileError(
                 format!("Failed to extract File from BufWriter: {}", e.error()),
-                ss_path_tmp.to_path_buf(),
+                self.tmp_path.clone(),
             )
         })?;
         f.sync_all()?;
 
-        fs::rename(ss_path_tmp, ss_path_final)?;
-        if let Some(dir) = ss_path_final.parent() {
+        fs::rename(&self.tmp_path, &self.final_path)?;
+        if let Some(dir) = self.final_path.parent() {
             // always should have parent
             File::open(dir)?.sync_all()?;
         }
@@ -801,557 +1212,4310 @@ impl AVL {
     }
 }
 
-pub enum FlushingThreadResponse {
-    Success(SSTable),
-    SyncError(DbError),
+const AVL_NODE_OVERHEAD: u64 = 88; // what a node costs on top of its key and value
+
+pub(crate) struct Avl {
+    root: Option<Box<Node>>,
+    size: u64,
+    range_tombstones: Vec<RangeTombstone>,
 }
-struct FlushingManager {
-    tx: Sender<FlushingThreadResponse>,
-    rx: Receiver<FlushingThreadResponse>, // make a DbError::FlushError(and variations)
+#[derive(PartialEq, Clone, Debug)]
+struct AvlEntry {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    seq: u64,
+    expires_at: u64,
+    deleted: bool,
+    merge_operand: bool,
+}
+#[derive(PartialEq, Clone, Debug)]
+struct Node {
+    entry: AvlEntry,
+    height: u64,
+    left: Option<Box<Node>>,
+    right: Option<Box<Node>>,
 }
 
-impl FlushingManager {
-    fn new() -> Self {
-        let (tx, rx) = mpsc::channel::<FlushingThreadResponse>();
-        Self { tx, rx }
+impl AvlEntry {
+    fn as_entry(&self) -> MemtableEntry<'_> {
+        MemtableEntry {
+            key: &self.key,
+            value: &self.value,
+            seq: self.seq,
+            expires_at: self.expires_at,
+            deleted: self.deleted,
+            merge_operand: self.merge_operand,
+        }
     }
+}
 
-    // main will poll and on success, will add the SST to active memory and delete old_wal from directory
-    fn background_flush_memtable(
-        &mut self,
-        frozen: Arc<AVL>,
-        ss_path_tmp: PathBuf,
-        ss_path_final: PathBuf,
-    ) -> Result<()> {
-        // PROBLEM: make sure all potential errors here are handled, no silenced errors
-        let tx: Sender<FlushingThreadResponse> = self.tx.clone();
-        spawn(move || -> Result<()> {
-            let f = match frozen.sync_avl(&ss_path_tmp, &ss_path_final) {
-                Ok(f) => f,
-                Err(err) => {
-                    let _ = tx.send(FlushingThreadResponse::SyncError(err));
-                    return Err(DbError::ReportedViaChannel);
-                }
-            };
-
-            // PROBLEM: ?; on load and handle potential error
-            let sstable = SSTable::load(&ss_path_final);
-
-            let _ = tx.send(FlushingThreadResponse::Success(sstable));
-
-            Ok(())
-        });
-
-        Ok(())
+impl Avl {
+    pub(crate) fn new() -> Self {
+        Self {
+            root: None,
+            size: 0,
+            range_tombstones: Vec::new(),
+        }
     }
 
-    fn build_avl_from_wal(&mut self, memtable: &mut AVL, path: &PathBuf) -> Result<()> {
-        let wal_f = File::open(path)?;
+    fn update_height(node: &mut Box<Node>) {
+        let left_height = if let Some(x) = node.left.as_ref() {
+            x.height as i64
+        } else {
+            -1
+        };
 
-        let file_len = wal_f.metadata()?.len();
+        let right_height = if let Some(x) = node.right.as_ref() {
+            x.height as i64
+        } else {
+            -1
+        };
+        node.height = (1 + max(left_height, right_height)) as u64;
+    }
+    fn insert(&mut self, curr: Option<Box<Node>>, n: Node) -> Option<Box<Node>> {
+        if let Some(mut node) = curr {
+            // every write is a new version, the same (key, seq) only shows up on a WAL replay
+            let ord = version_cmp(&n.entry.key, n.entry.seq, &node.entry.key, node.entry.seq);
+            if ord == Ordering::Equal {
+                node.entry.value = n.entry.value;
+                node.entry.expires_at = n.entry.expires_at;
+                node.entry.deleted = n.entry.deleted;
+                node.entry.merge_operand = n.entry.merge_operand;
+                return Some(node);
+            }
+            if ord == Ordering::Less {
+                node.left = self.insert(node.left.take(), n);
+            } else {
+                node.right = self.insert(node.right.take(), n);
+            }
 
-        let mut reader = BufReader::new(&wal_f);
+            node = Self::balance(node);
+            Some(node)
+        } else {
+            Some(Box::new(n))
+        }
+    }
+    /*
 
-        let mut type_of_record: [u8; 1] = [0u8; 1];
-        let mut ksz = [0u8; 8];
-        let mut tstamp = [0u8; 8];
-        let mut vsz = [0u8; 8];
-        let mut crc = [0u8; 4];
-        let mut pos: u64 = 0;
+    */
 
-        while pos < file_len {
-            reader.read_exact(&mut type_of_record)?; // 1 byte
-            let type_tag = type_of_record[0];
+    fn add(&mut self, entry: AvlEntry) {
+        self.size += (entry.key.len() + entry.value.len()) as u64 + AVL_NODE_OVERHEAD;
+        let n = Node {
+            entry,
+            height: 0,
+            left: None,
+            right: None,
+        };
+        let root = self.root.take();
+        self.root = self.insert(root, n);
+    }
 
-            match type_tag {
-                TAG_DELETION => {
-                    //  TAG_DELETION handle  [ tstamp(8) | ksz(8) | key(sizeof ksz ) |crc (4 bytes) ]
-                    reader.read_exact(&mut tstamp)?;
-                    reader.read_exact(&mut ksz)?;
+    fn balance(mut node: Box<Node>) -> Box<Node> {
+        Self::update_height(&mut node);
+        let bf = Self::compute_balance_factor_of_node(&node);
 
-                    let key_size = u64::from_le_bytes(ksz);
+        if bf > 1 {
+            // left heavy
 
-                    if key_size > KEY_MAX_BYTES_SIZE || pos + key_size + 21 > file_len {
-                        return Err(DbError::DataCorrupted(DataCorruptedErr {
-                            offset: pos,
-                            file_path: path.to_path_buf(),
-                            reason: CorruptionType::Other(format!(
-                                "record size overflow: ksz={key_size}"
-                            )),
-                        }));
-                    }
-                    let mut key_buffer = vec![0u8; key_size as usize];
-
-                    reader.read_exact(&mut key_buffer)?;
-
-                    let crc_data_block =
-                        [type_of_record.as_slice(), &tstamp, &ksz, &key_buffer].concat();
-                    let crc_to_check = compute_crc_data_block(&crc_data_block);
-
-                    reader.read_exact(&mut crc)?;
-
-                    let crc_from_buff = u32::from_le_bytes(crc);
-                    if crc_to_check != crc_from_buff {
-                        return Err(DbError::DataCorrupted(DataCorruptedErr {
-                            offset: pos,
-                            file_path: path.to_path_buf(),
-                            reason: CorruptionType::CrcMismatch {
-                                expected: crc_to_check,
-                                found: crc_from_buff,
-                            },
-                        }));
-                    }
+            let left_node = node.left.as_mut().unwrap();
+            match Self::compute_balance_factor_of_node(left_node) {
+                bf if bf >= 0 => {
+                    let left = node.left.take().unwrap();
 
-                    pos = reader.stream_position()?;
-                    memtable.delete(&key_buffer);
+                    node = Self::right_rotation(node, left);
                 }
-                TAG_INSERTION => {
-                    reader.read_exact(&mut tstamp)?;
-                    reader.read_exact(&mut ksz)?;
-                    reader.read_exact(&mut vsz)?;
-                    let key_size = u64::from_le_bytes(ksz);
-                    let val_size = u64::from_le_bytes(vsz);
-
-                    if key_size > KEY_MAX_BYTES_SIZE
-                        || val_size > VALUE_MAX_BYTES_SIZE
-                        || pos + key_size + val_size + 29 > file_len
-                    // 1 + 8 + 8 + 8 + 4 = 29
-                    {
-                        return Err(DbError::DataCorrupted(DataCorruptedErr {
-                            offset: pos,
-                            file_path: path.to_path_buf(),
-                            reason: CorruptionType::Other(format!(
-                                "record size overflow: ksz={key_size} vsz={val_size}"
-                            )),
-                        }));
-                    }
-                    let mut key_buffer = vec![0u8; key_size as usize];
-                    let mut val_buffer = vec![0u8; val_size as usize];
-                    reader.read_exact(&mut key_buffer)?;
-
-                    reader.read_exact(&mut val_buffer)?;
-
-                    let crc_data_block = [
-                        type_of_record.as_slice(),
-                        &tstamp,
-                        &ksz,
-                        &vsz,
-                        &key_buffer,
-                        &val_buffer,
-                    ]
-                    .concat();
-                    let crc_to_check = compute_crc_data_block(&crc_data_block);
-
-                    reader.read_exact(&mut crc)?;
-
-                    let crc_from_buff = u32::from_le_bytes(crc);
-                    if crc_to_check != crc_from_buff {
-                        return Err(DbError::DataCorrupted(DataCorruptedErr {
-                            offset: pos,
-                            file_path: path.to_path_buf(),
-                            reason: CorruptionType::CrcMismatch {
-                                expected: crc_to_check,
-                                found: crc_from_buff,
-                            },
-                        }));
-                    }
-                    pos = reader.stream_position()?;
+                _ => {
+                    let mut left_child = node.left.take().unwrap();
+                    let right_of_left = left_child.right.take().unwrap();
+                    left_child = Self::left_rotation(left_child, right_of_left);
+                    node = Self::right_rotation(node, left_child)
+                }
+            }
+        } else if bf < -1 {
+            // right heavy
 
-                    memtable.put(&key_buffer, &val_buffer);
-                    // TAG_INSERTION handle tstamp | ksz | vsz | key | value |crc (4 bytes)
+            let right_node = node.right.as_mut().unwrap();
+            match Self::compute_balance_factor_of_node(right_node) {
+                bf if bf <= 0 => {
+                    let right = node.right.take().unwrap();
+                    node = Self::left_rotation(node, right);
                 }
                 _ => {
-                    return Err(DbError::DataCorrupted(DataCorruptedErr {
-                        offset: pos,
-                        file_path: path.to_path_buf(),
-                        reason: Other(
-                            "Received corrupted record type while retrieving WAL".to_string(),
-                        ),
-                    }));
+                    let mut right_child = node.right.take().unwrap();
+                    let left_of_right = right_child.left.take().unwrap();
+                    right_child = Self::right_rotation(right_child, left_of_right);
+                    node = Self::left_rotation(node, right_child);
                 }
             }
         }
 
-        Ok(())
+        node
     }
-    fn retrieve_wal_records(
-        &mut self,
-        path: &PathBuf,
-        sst_tmp_path: &PathBuf,
-        ss_final_path: &PathBuf,
-    ) -> Result<()> {
-        let mut memtable = AVL::new(MEMTABLE_THRESHOLD);
-        self.build_avl_from_wal(&mut memtable, path)?;
 
-        let f = match memtable.sync_avl(sst_tmp_path, ss_final_path) {
-            Ok(f) => f,
-            Err(err) => {
-                // PROBLEM: No error exists for this failure, make it
-                return Err(DbError::ReportedViaChannel);
-            }
-        };
-        let sstable = SSTable::load(ss_final_path);
-        // Problem: Use ?; when loading and handle potential error
-        // Then return the ss table to main to be added to the list
+    fn left_rotation(mut parent: Box<Node>, mut child: Box<Node>) -> Box<Node> {
+        // parent and child.right
+        parent.right = child.left.take();
 
-        Ok(())
-    }
-}
-struct KVEngine {
-    data_directory: PathBuf,
-    sstables: Option<Arc<RwLock<Vec<SSTable>>>>,
-    curr_file_buffer: Option<BufWriter<File>>,
-    curr_file_path: Option<PathBuf>,
-    curr_file_offset: u64,
-    sync_config: SyncConfig,
-    wal: WAL,
-    frozen_wal: Option<WAL>,
-    memtable: AVL, // Problem: Might need to put in Arc<> for
-    flushing_memtable: Option<Weak<AVL>>,
-    corrupted_files: HashSet<FileId>,
-    flushing_manager: FlushingManager,
-}
+        child.left = Some(parent);
 
-impl KVEngine {
-    fn create_new_data_file(dir: &Path) -> io::Result<(File, PathBuf, PathBuf)> {
-        let tstamp = new_timestamp();
-        let data_file_path_final = dir.join(format!("{}.sst", tstamp));
-        let data_file_path_tmp = dir.join(format!("{}.sst.tmp", tstamp));
-        let data_file = OpenOptions::new()
-            .read(true)
-            .append(true)
-            .create_new(true)
-            .open(&data_file_path_tmp)?;
-        Ok((data_file, data_file_path_tmp, data_file_path_final))
+        if let Some(left) = child.left.as_mut() {
+            Self::update_height(left);
+        }
+        Self::update_height(&mut child);
+        child
     }
-
-    // threshold and sync_config can be part of one config struct later.
-    fn open(dir_name: &Path, sync_config: SyncConfig, threshold: u64) -> Result<KVEngine> {
-        let path = PathBuf::from(dir_name);
-
-        let mut sstables: Vec<SSTable> = Vec::new();
-        let memtable = AVL::new(MEMTABLE_THRESHOLD);
-
-        let wal = WAL::new(threshold, sync_config)?;
-        let wal_path: &Path = wal.path.as_ref();
-        let wal_path_metadata = wal_path.metadata();
-
-        let mut self_instance = Self {
-            sstables: None,
-            data_directory: path,
-            curr_file_buffer: None,
-            curr_file_path: None,
-            curr_file_offset: 0,
-            sync_config,
-            memtable,
-            flushing_memtable: None,
-            wal,
-            frozen_wal: None,
-            flushing_manager: FlushingManager::new(),
-            corrupted_files: HashSet::new(),
+    fn right_rotation(mut parent: Box<Node>, mut child: Box<Node>) -> Box<Node> {
+        // parent and child.left
+        parent.left = child.right.take();
+        child.right = Some(parent);
+        if let Some(right) = child.right.as_mut() {
+            Self::update_height(right);
+        }
+        Self::update_height(&mut child);
+        child
+    }
+
+    fn compute_balance_factor_of_node(node: &Node) -> i32 {
+        let bf_l = if let Some(x) = node.left.as_ref() {
+            x.height as i32
+        } else {
+            -1
+        };
+        let bf_r = if let Some(x) = node.right.as_ref() {
+            x.height as i32
+        } else {
+            -1
         };
+        bf_l - bf_r
+    }
 
-        for entry in fs::read_dir(dir_name)? {
-            let entry = entry?;
-            let path = entry.path();
-            if !path.is_file() {
+    // in-order walk that skips the subtrees falling outside of the bounds, every version of a key is kept
+    fn collect_range<'a>(
+        n: &'a Option<Box<Node>>,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        out: &mut Vec<MemtableEntry<'a>>,
+    ) {
+        if let Some(x) = n {
+            let key = x.entry.key.as_slice();
+            let above_lower = key_above_lower(key, lower);
+            let below_upper = key_below_upper(key, upper);
+
+            if above_lower {
+                Self::collect_range(&x.left, lower, upper, out);
+            }
+            if above_lower && below_upper {
+                out.push(x.entry.as_entry());
+            }
+            if below_upper {
+                Self::collect_range(&x.right, lower, upper, out);
+            }
+        }
+    }
+}
+
+impl Memtable for Avl {
+    fn put(&mut self, key: &[u8], value: &[u8], seq: u64, expires_at: u64) {
+        self.add(AvlEntry {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            seq,
+            expires_at,
+            deleted: false,
+            merge_operand: false,
+        });
+    }
+
+    fn delete(&mut self, key: &[u8], seq: u64) {
+        self.add(AvlEntry {
+            key: key.to_vec(),
+            value: Vec::new(),
+            seq,
+            expires_at: 0,
+            deleted: true,
+            merge_operand: false,
+        });
+    }
+
+    fn merge(&mut self, key: &[u8], operand: &[u8], seq: u64) {
+        self.add(AvlEntry {
+            key: key.to_vec(),
+            value: operand.to_vec(),
+            seq,
+            expires_at: 0,
+            deleted: false,
+            merge_operand: true,
+        });
+    }
+
+    fn delete_range(&mut self, start: &[u8], end: &[u8], seq: u64) {
+        self.range_tombstones.push(RangeTombstone {
+            start: start.to_vec(),
+            end: end.to_vec(),
+            seq,
+        });
+        self.size += (start.len() + end.len()) as u64;
+    }
+
+    fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        self.range_tombstones.clone()
+    }
+
+    fn ceiling(&self, lower: Bound<(&[u8], u64)>) -> Option<MemtableEntry<'_>> {
+        let mut best = None;
+        let mut curr = self.root.as_ref();
+        while let Some(n) = curr {
+            if version_above_lower(&n.entry, lower) {
+                best = Some(n.entry.as_entry());
+                curr = n.left.as_ref();
+            } else {
+                curr = n.right.as_ref();
+            }
+        }
+        best
+    }
+
+    fn floor(&self, upper: Bound<(&[u8], u64)>) -> Option<MemtableEntry<'_>> {
+        let mut best = None;
+        let mut curr = self.root.as_ref();
+        while let Some(n) = curr {
+            if version_below_upper(&n.entry, upper) {
+                best = Some(n.entry.as_entry());
+                curr = n.right.as_ref();
+            } else {
+                curr = n.left.as_ref();
+            }
+        }
+        best
+    }
+
+    fn iter<'a>(
+        &'a self,
+        lower: Bound<&'a [u8]>,
+        upper: Bound<&'a [u8]>,
+    ) -> Box<dyn Iterator<Item = MemtableEntry<'a>> + 'a> {
+        let mut out = Vec::new();
+        Self::collect_range(&self.root, lower, upper, &mut out);
+        Box::new(out.into_iter())
+    }
+
+    fn memory_usage(&self) -> u64 {
+        self.size
+    }
+
+    fn is_empty(&self) -> bool {
+        self.root.is_none() && self.range_tombstones.is_empty()
+    }
+}
+
+// What if engine crashes mid flush execution? // check if need to be called on start/restart
+
+fn flush_memtable(
+    memtable: &dyn Memtable,
+    ss_path_tmp: &Path,
+    ss_path_final: &Path,
+    table: TableOptions,
+    vlog: &ValueLog,
+    merge_operator: Option<&dyn MergeOperator>,
+    snapshots: &[u64],
+) -> Result<File> {
+    if memtable.is_empty() {
+        return Err(DbError::MissingKey(
+            "Min key missing in memtable during flushing operation".to_string(),
+        ));
+    }
+
+    // flushed memtables always land in L0, compaction moves them down
+    let mut writer = SsTableWriter::new(ss_path_tmp, ss_path_final, 0, table)?;
+    let mut vlog_writer: Option<VlogWriter> = None;
+    let now = new_timestamp();
+    let range_tombstones = memtable.range_tombstones();
+    // same stripes as compaction, a version hidden by a newer one from every live snapshot is dropped
+    let stripe_of = |seq: u64| snapshots.partition_point(|&s| s < seq);
+    let mut write_key = |versions: &mut Vec<KvEntry>| -> Result<()> {
+        // older tables may hold more of the key, only runs on top of a value here fold
+        if let Some(operator) = merge_operator {
+            let tops: Vec<bool> = (0..versions.len())
+                .map(|i| i == 0 || stripe_of(versions[i - 1].seq) != stripe_of(versions[i].seq))
+                .collect();
+            merge::collapse(operator, versions, false, &range_tombstones, now, |i| {
+                tops[i]
+            });
+        }
+        let mut last_stripe = None;
+        // an operand left unfolded is read through every version below it
+        let mut under_operand = false;
+        for entry in versions.iter_mut() {
+            let stripe = stripe_of(entry.seq);
+            if !under_operand {
+                if last_stripe == Some(stripe) {
+                    continue;
+                }
+                last_stripe = Some(stripe);
+            }
+            under_operand |= entry.merge_operand;
+            if !entry.deleted && !entry.merge_operand && vlog.separates(&entry.value) {
+                // the value log file is only created once a flush has a value for it
+                let vlog_writer = match &mut vlog_writer {
+                    Some(w) => w,
+                    None => vlog_writer.insert(vlog.writer()?),
+                };
+                entry.value = vlog_writer.append(&entry.key, &entry.value)?.encode();
+                entry.value_ptr = true;
+            }
+            writer.add(entry)?;
+        }
+        Ok(())
+    };
+    memtable.for_each_key(&mut write_key)?;
+    for tombstone in range_tombstones {
+        writer.add_range_tombstone(tombstone);
+    }
+    if let Some(vlog_writer) = vlog_writer {
+        vlog_writer.finish()?;
+    }
+    writer.finish()
+}
+
+// (deleted, value_ptr, merge_operand) of a record flag byte, older tables wrote 0x01 or 0xFF
+// for tombstones
+fn parse_flag(flag: u8) -> (bool, bool, bool) {
+    (
+        ![0, VALUE_POINTER_FLAG, MERGE_OPERAND_FLAG].contains(&flag),
+        flag == VALUE_POINTER_FLAG,
+        flag == MERGE_OPERAND_FLAG,
+    )
+}
+
+// memtable order: ascending key, then descending seq so the newest version of a key comes first
+pub(crate) fn version_cmp(key: &[u8], seq: u64, other_key: &[u8], other_seq: u64) -> Ordering {
+    key.cmp(other_key).then(other_seq.cmp(&seq))
+}
+
+fn version_above_lower(entry: &AvlEntry, lower: Bound<(&[u8], u64)>) -> bool {
+    match lower {
+        Bound::Included((k, seq)) => version_cmp(&entry.key, entry.seq, k, seq).is_ge(),
+        Bound::Excluded((k, seq)) => version_cmp(&entry.key, entry.seq, k, seq).is_gt(),
+        Bound::Unbounded => true,
+    }
+}
+
+fn version_below_upper(entry: &AvlEntry, upper: Bound<(&[u8], u64)>) -> bool {
+    match upper {
+        Bound::Included((k, seq)) => version_cmp(&entry.key, entry.seq, k, seq).is_le(),
+        Bound::Excluded((k, seq)) => version_cmp(&entry.key, entry.seq, k, seq).is_lt(),
+        Bound::Unbounded => true,
+    }
+}
+
+fn key_above_lower(key: &[u8], lower: Bound<&[u8]>) -> bool {
+    match lower {
+        Bound::Included(l) => key >= l,
+        Bound::Excluded(l) => key > l,
+        Bound::Unbounded => true,
+    }
+}
+
+fn key_below_upper(key: &[u8], upper: Bound<&[u8]>) -> bool {
+    match upper {
+        Bound::Included(u) => key <= u,
+        Bound::Excluded(u) => key < u,
+        Bound::Unbounded => true,
+    }
+}
+
+type EntrySource = Box<dyn Iterator<Item = Result<KvEntry>> + Send>;
+
+// Ordered scan over the memtables and every SSTable overlapping the range. Yields the newest
+// version of each key written at or before read_seq, tombstoned and expired keys are skipped.
+pub struct ScanIterator {
+    merged: Peekable<MergeIterator<EntrySource>>,
+    lower: Bound<Vec<u8>>,
+    upper: Bound<Vec<u8>>,
+    read_seq: u64,
+    last_key: Option<Vec<u8>>, // key already resolved, its older versions are skipped
+    done: bool,
+    vlog: Arc<ValueLog>,
+    range_tombstones: Vec<RangeTombstone>, // every tombstone visible at read_seq
+    now: u64,                              // expiry is checked against the scan start
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+}
+
+impl ScanIterator {
+    // value of a key whose newest visible version is a merge operand, its older versions come
+    // next in the merged stream
+    fn read_merged(&mut self, top: KvEntry) -> Result<Vec<u8>> {
+        let mut newer_seq = top.seq;
+        let mut operands = vec![top.value];
+        let mut existing = None;
+        while let Some(older) = self
+            .merged
+            .next_if(|e| e.as_ref().is_err() || e.as_ref().is_ok_and(|e| e.key == top.key))
+        {
+            let older = older?;
+            let covered = self
+                .range_tombstones
+                .iter()
+                .any(|t| t.covers(&older.key, older.seq) && t.seq < newer_seq);
+            if older.deleted || covered || older.is_expired(self.now) {
+                break;
+            }
+            if !older.merge_operand {
+                existing = Some(self.vlog.resolve(&older)?);
+                break;
+            }
+            newer_seq = older.seq;
+            operands.push(older.value);
+        }
+        let value = merge::apply_operands(
+            self.merge_operator.as_deref(),
+            &top.key,
+            existing,
+            &operands,
+        )?;
+        Ok(value.unwrap_or_default())
+    }
+}
+
+impl Iterator for ScanIterator {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let entry = match self.merged.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(err)) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+                None => break,
+            };
+            if !key_below_upper(&entry.key, self.upper.as_ref().map(Vec::as_slice)) {
+                break;
+            }
+            if entry.seq > self.read_seq || self.last_key.as_ref() == Some(&entry.key) {
+                continue;
+            }
+            self.last_key = Some(entry.key.clone());
+            // seeking lands on an excluded lower bound
+            if entry.deleted
+                || entry.is_expired(self.now)
+                || !key_above_lower(&entry.key, self.lower.as_ref().map(Vec::as_slice))
+                || range_del::is_covered(&self.range_tombstones, &entry.key, entry.seq)
+            {
+                continue;
+            }
+            let key = entry.key.clone();
+            let value = match entry.merge_operand {
+                true => self.read_merged(entry),
+                false => self.vlog.resolve(&entry),
+            };
+            if value.is_err() {
+                self.done = true;
+            }
+            return Some(value.map(|value| (key, value)));
+        }
+        self.done = true;
+        None
+    }
+}
+
+// A positioned, bidirectional view over one sorted source (memtable or SSTable).
+// Every source yields each of its keys once, as the newest version visible at the read
+// sequence it was created with. Tombstones included, keys with no visible version are skipped.
+trait EntryCursor {
+    fn entry(&self) -> Option<&KvEntry>;
+    fn seek_to_first(&mut self) -> Result<()>;
+    fn seek_to_last(&mut self) -> Result<()>;
+    // first entry with a key >= key
+    fn seek(&mut self, key: &[u8]) -> Result<()>;
+    // last entry with a key <= key
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()>;
+    fn next(&mut self) -> Result<()>;
+    fn prev(&mut self) -> Result<()>;
+}
+
+// Memtables are not walked in place, every step is a fresh search using the current key, so the
+// AVL gets by without parent pointers. M is either a borrowed active memtable or an owned
+// handle to a frozen one.
+struct MemtableCursor<M: Deref<Target = dyn Memtable>> {
+    memtable: M,
+    read_seq: u64,
+    current: Option<KvEntry>,
+}
+
+impl<M: Deref<Target = dyn Memtable>> MemtableCursor<M> {
+    fn new(memtable: M, read_seq: u64) -> Self {
+        Self {
+            memtable,
+            read_seq,
+            current: None,
+        }
+    }
+}
+
+impl<M: Deref<Target = dyn Memtable>> EntryCursor for MemtableCursor<M> {
+    fn entry(&self) -> Option<&KvEntry> {
+        self.current.as_ref()
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.current = self
+            .memtable
+            .visible_ceiling(Bound::Unbounded, self.read_seq)
+            .map(|e| e.to_kv_entry());
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.current = self
+            .memtable
+            .visible_floor(Bound::Unbounded, self.read_seq)
+            .map(|e| e.to_kv_entry());
+        Ok(())
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.current = self
+            .memtable
+            .visible_ceiling(Bound::Included(key), self.read_seq)
+            .map(|e| e.to_kv_entry());
+        Ok(())
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        self.current = self
+            .memtable
+            .visible_floor(Bound::Included(key), self.read_seq)
+            .map(|e| e.to_kv_entry());
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<()> {
+        if let Some(current) = self.current.take() {
+            self.current = self
+                .memtable
+                .visible_ceiling(Bound::Excluded(&current.key), self.read_seq)
+                .map(|e| e.to_kv_entry());
+        }
+        Ok(())
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        if let Some(current) = self.current.take() {
+            self.current = self
+                .memtable
+                .visible_floor(Bound::Excluded(&current.key), self.read_seq)
+                .map(|e| e.to_kv_entry());
+        }
+        Ok(())
+    }
+}
+
+// Records inside a data block can only be parsed front to back, so when a block is loaded we
+// walk it once and keep the offset of every record. That gives us prev() and a binary search
+// inside the block. Versions of a key are stored newest first and may run on from the end of
+// one block into the next, so the cursor moves record by record with step_forward and
+// step_backward, which load the neighbouring block when they fall off either end. A seek starts
+// in the last block whose first key is below the target, where its newest versions can sit,
+// and settle_backward walks back across a block boundary to reach the newest visible version.
+struct SsTableCursor {
+    table: Arc<SSTable>,
+    cache: Arc<BlockCache>,
+    read_seq: u64,
+    block_idx: usize,
+    block: Arc<DataBlock>,
+    idx: usize,
+    current: Option<KvEntry>,
+}
+
+impl SsTableCursor {
+    fn new(table: Arc<SSTable>, cache: Arc<BlockCache>, read_seq: u64) -> Self {
+        Self {
+            table,
+            cache,
+            read_seq,
+            block_idx: 0,
+            block: Arc::default(),
+            idx: 0,
+            current: None,
+        }
+    }
+
+    fn load_block(&mut self, block_idx: usize) -> Result<()> {
+        self.block = self.table.block(block_idx, Some(&self.cache))?;
+        self.block_idx = block_idx;
+        Ok(())
+    }
+
+    fn record_at(&self, idx: usize) -> Result<BlockRecord<'_>> {
+        self.block.record(&self.table, idx)
+    }
+
+    // positions on record idx of the loaded block, or invalidates when it is out of range
+    fn set_current(&mut self, idx: usize) -> Result<()> {
+        self.idx = idx;
+        self.current = match idx < self.block.len() {
+            true => Some(self.record_at(idx)?.to_entry()),
+            false => None,
+        };
+        Ok(())
+    }
+
+    // index of the first record in the loaded block for which goes_right is false
+    fn partition_block(&self, goes_right: impl Fn(&[u8]) -> bool) -> Result<usize> {
+        self.block.partition(&self.table, goes_right)
+    }
+
+    // moves one record forward, versions included
+    fn step_forward(&mut self) -> Result<()> {
+        if self.current.is_none() {
+            return Ok(());
+        }
+        if self.idx + 1 < self.block.len() {
+            return self.set_current(self.idx + 1);
+        }
+        self.current = None;
+        if self.block_idx + 1 < self.table.sparse_index.len() {
+            self.load_block(self.block_idx + 1)?;
+            self.set_current(0)?;
+        }
+        Ok(())
+    }
+
+    // moves one record back, versions included
+    fn step_backward(&mut self) -> Result<()> {
+        if self.current.is_none() {
+            return Ok(());
+        }
+        if self.idx > 0 {
+            return self.set_current(self.idx - 1);
+        }
+        self.current = None;
+        if self.block_idx > 0 {
+            self.load_block(self.block_idx - 1)?;
+            self.set_current(self.block.len() - 1)?;
+        }
+        Ok(())
+    }
+
+    fn current_key(&self) -> Option<Vec<u8>> {
+        self.current.as_ref().map(|e| e.key.clone())
+    }
+
+    // current sits on the newest version of a key, skips the versions written after read_seq
+    fn settle_forward(&mut self) -> Result<()> {
+        while self.current.as_ref().is_some_and(|e| e.seq > self.read_seq) {
+            self.step_forward()?;
+        }
+        Ok(())
+    }
+
+    // current sits on the oldest version of a key, walks back up to the newest visible one
+    fn settle_backward(&mut self) -> Result<()> {
+        while let Some(current) = self.current.as_ref() {
+            let key = current.key.clone();
+            if current.seq > self.read_seq {
+                // the other versions are even newer, the whole key is invisible
+                while self.current.as_ref().is_some_and(|e| e.key == key) {
+                    self.step_backward()?;
+                }
                 continue;
             }
+            // newer visible versions may go back into the previous block
+            loop {
+                let (block_idx, idx) = (self.block_idx, self.idx);
+                self.step_backward()?;
+                if self
+                    .current
+                    .as_ref()
+                    .is_some_and(|e| e.key == key && e.seq <= self.read_seq)
+                {
+                    continue;
+                }
+                // one step too far, back onto the newest visible version
+                if self.block_idx != block_idx {
+                    self.load_block(block_idx)?;
+                }
+                self.set_current(idx)?;
+                break;
+            }
+            break;
+        }
+        Ok(())
+    }
+}
+
+impl EntryCursor for SsTableCursor {
+    fn entry(&self) -> Option<&KvEntry> {
+        self.current.as_ref()
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.current = None;
+        if self.table.sparse_index.is_empty() {
+            return Ok(());
+        }
+        self.load_block(0)?;
+        self.set_current(0)?;
+        self.settle_forward()
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.current = None;
+        let Some(last_block) = self.table.sparse_index.len().checked_sub(1) else {
+            return Ok(());
+        };
+        self.load_block(last_block)?;
+        self.set_current(self.block.len().saturating_sub(1))?;
+        self.settle_backward()
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.current = None;
+        if self.table.sparse_index.is_empty() {
+            return Ok(());
+        }
+        // the key may start at the end of the last block beginning below it
+        let block_idx = self
+            .table
+            .sparse_index
+            .partition_point(|(first_key, _, _)| first_key.as_slice() < key)
+            .saturating_sub(1);
+        self.load_block(block_idx)?;
+        let idx = self.partition_block(|k| k < key)?;
+        if idx < self.block.len() {
+            self.set_current(idx)?;
+        } else if block_idx + 1 < self.table.sparse_index.len() {
+            // everything in this block is smaller, the next block starts above key
+            self.load_block(block_idx + 1)?;
+            self.set_current(0)?;
+        }
+        self.settle_forward()
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        self.current = None;
+        let blocks_at_or_before = self
+            .table
+            .sparse_index
+            .partition_point(|(first_key, _, _)| first_key.as_slice() <= key);
+        let Some(block_idx) = blocks_at_or_before.checked_sub(1) else {
+            return Ok(());
+        };
+        self.load_block(block_idx)?;
+        // the first key of the block is <= key so this is at least 1
+        let idx = self.partition_block(|k| k <= key)?;
+        self.set_current(idx - 1)?;
+        self.settle_backward()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        let Some(key) = self.current_key() else {
+            return Ok(());
+        };
+        while self.current.as_ref().is_some_and(|e| e.key == key) {
+            self.step_forward()?;
+        }
+        self.settle_forward()
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        let Some(key) = self.current_key() else {
+            return Ok(());
+        };
+        while self.current.as_ref().is_some_and(|e| e.key == key) {
+            self.step_backward()?;
+        }
+        self.settle_backward()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Direction {
+    Forward,
+    Backward,
+}
+
+// Bidirectional iterator over the whole engine. Children are ordered newest first: active
+// memtable, frozen memtable, then SSTables in search order. When several children sit on the
+// same key the highest seq wins (lowest index on a tie) and the others are stepped past it
+// together, so every key shows up once with its newest visible version. Tombstoned keys and
+// keys under a newer range tombstone or past their expiry are skipped.
+pub struct LsmIterator<'a> {
+    children: Vec<Box<dyn EntryCursor + 'a>>,
+    current: Option<usize>,
+    direction: Direction,
+    vlog: Arc<ValueLog>,
+    // value of the current entry when it lives in the value log or is folded from merge operands
+    resolved_value: Option<Vec<u8>>,
+    range_tombstones: Vec<RangeTombstone>,
+    now: u64, // expiry is checked against the iterator creation
+    read_merged: ReadMerged<'a>,
+}
+
+// reads a key through its merge operands, the older versions sit in children behind the current one
+type ReadMerged<'a> = Box<dyn Fn(&[u8]) -> Result<Option<Vec<u8>>> + 'a>;
+
+impl<'a> LsmIterator<'a> {
+    fn new(
+        children: Vec<Box<dyn EntryCursor + 'a>>,
+        vlog: Arc<ValueLog>,
+        range_tombstones: Vec<RangeTombstone>,
+        read_merged: ReadMerged<'a>,
+    ) -> Self {
+        Self {
+            children,
+            current: None,
+            direction: Direction::Forward,
+            vlog,
+            resolved_value: None,
+            range_tombstones,
+            now: new_timestamp(),
+            read_merged,
+        }
+    }
+
+    pub fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    pub fn key(&self) -> Option<&[u8]> {
+        self.current_entry().map(|e| e.key.as_slice())
+    }
+
+    pub fn value(&self) -> Option<&[u8]> {
+        match self.current_entry()? {
+            e if e.value_ptr || e.merge_operand => self.resolved_value.as_deref(),
+            e => Some(e.value.as_slice()),
+        }
+    }
+
+    fn current_entry(&self) -> Option<&KvEntry> {
+        self.children[self.current?].entry()
+    }
+
+    pub fn seek_to_first(&mut self) -> Result<()> {
+        for child in self.children.iter_mut() {
+            child.seek_to_first()?;
+        }
+        self.settle(Direction::Forward)
+    }
+
+    pub fn seek_to_last(&mut self) -> Result<()> {
+        for child in self.children.iter_mut() {
+            child.seek_to_last()?;
+        }
+        self.settle(Direction::Backward)
+    }
+
+    pub fn seek(&mut self, key: &[u8]) -> Result<()> {
+        for child in self.children.iter_mut() {
+            child.seek(key)?;
+        }
+        self.settle(Direction::Forward)
+    }
+
+    pub fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        for child in self.children.iter_mut() {
+            child.seek_for_prev(key)?;
+        }
+        self.settle(Direction::Backward)
+    }
+
+    // a cursor rather than an Iterator, it steps both ways and stays on the current key
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<()> {
+        let Some(key) = self.key().map(<[u8]>::to_vec) else {
+            return Ok(());
+        };
+        if self.direction == Direction::Backward {
+            // children that were behind the current key have to jump over to the other side
+            for child in self.children.iter_mut() {
+                child.seek(&key)?;
+            }
+        }
+        self.step_past(&key, Direction::Forward)?;
+        self.settle(Direction::Forward)
+    }
+
+    pub fn prev(&mut self) -> Result<()> {
+        let Some(key) = self.key().map(<[u8]>::to_vec) else {
+            return Ok(());
+        };
+        if self.direction == Direction::Forward {
+            for child in self.children.iter_mut() {
+                child.seek_for_prev(&key)?;
+            }
+        }
+        self.step_past(&key, Direction::Backward)?;
+        self.settle(Direction::Backward)
+    }
+
+    // moves every child sitting on key one step in direction
+    fn step_past(&mut self, key: &[u8], direction: Direction) -> Result<()> {
+        for child in self.children.iter_mut() {
+            if child.entry().is_some_and(|e| e.key == key) {
+                match direction {
+                    Direction::Forward => child.next()?,
+                    Direction::Backward => child.prev()?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn pick_current(&mut self) {
+        let mut best: Option<(usize, &KvEntry)> = None;
+        for (i, child) in self.children.iter().enumerate() {
+            let Some(entry) = child.entry() else { continue };
+            let better = match best {
+                None => true,
+                Some((_, b)) => {
+                    let ord = entry.key.cmp(&b.key);
+                    let ahead = match self.direction {
+                        Direction::Forward => ord.is_lt(),
+                        Direction::Backward => ord.is_gt(),
+                    };
+                    ahead || (ord.is_eq() && entry.seq > b.seq)
+                }
+            };
+            if better {
+                best = Some((i, entry));
+            }
+        }
+        self.current = best.map(|(i, _)| i);
+    }
+
+    // picks the next visible key in direction, stepping over tombstones
+    fn settle(&mut self, direction: Direction) -> Result<()> {
+        self.direction = direction;
+        loop {
+            self.pick_current();
+            match self.current_entry() {
+                Some(entry)
+                    if entry.deleted
+                        || entry.is_expired(self.now)
+                        || range_del::is_covered(&self.range_tombstones, &entry.key, entry.seq) =>
+                {
+                    let key = entry.key.clone();
+                    self.step_past(&key, direction)?;
+                }
+                Some(entry) if entry.value_ptr => {
+                    self.resolved_value = Some(self.vlog.resolve(entry)?);
+                    return Ok(());
+                }
+                Some(entry) if entry.merge_operand => {
+                    self.resolved_value = (self.read_merged)(&entry.key)?;
+                    return Ok(());
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+}
+
+// a frozen memtable on its way to disk, with everything the coordinator needs to install it
+struct FlushJob {
+    cf: ColumnFamilyId,
+    wal_id: u64, // rotation the memtable was frozen in
+    memtable: Arc<dyn Memtable>,
+    immutable: FrozenMemtables, // queue the memtable is released from once its table is installed
+    sstables: Arc<RwLock<Vec<Arc<SSTable>>>>,
+    tmp_path: PathBuf,
+    final_path: PathBuf,
+    table: TableOptions,
+    vlog: Arc<ValueLog>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    snapshots: SnapshotList,
+    compaction: CompactionJob, // handed to the compaction thread once the table is installed
+}
+
+impl FlushJob {
+    // writes the table from scratch, a retry overwrites whatever a failed attempt left
+    fn flush(&self) -> Result<SSTable> {
+        // snapshots taken after the rotation only read the newest versions, which are always kept
+        let snapshots: Vec<u64> = self.snapshots.lock().unwrap().keys().copied().collect();
+        flush_memtable(
+            &*self.memtable,
+            &self.tmp_path,
+            &self.final_path,
+            self.table.clone(),
+            &self.vlog,
+            self.merge_operator.as_deref(),
+            &snapshots,
+        )?;
+        SSTable::load(&self.final_path, &self.table)
+    }
+}
+
+// The tables of a column family with everything needed to compact them. Taken while the engine
+// is locked, the merge itself runs without the lock and only the install takes the table lock.
+struct CompactionJob {
+    cf: ColumnFamilyId,
+    dir: PathBuf,
+    sstables: Arc<RwLock<Vec<Arc<SSTable>>>>,
+    strategy: CompactionStrategy,
+    table: TableOptions,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    snapshots: SnapshotList,
+    manifest: Arc<Mutex<Manifest>>,
+    running: Arc<Mutex<()>>, // shared by every job of the engine, two merges never pick the same inputs
+}
+
+impl CompactionJob {
+    // runs compactions until the strategy has nothing left to do
+    fn run(&self) -> Result<()> {
+        while self.compact_once()? {}
+        Ok(())
+    }
+
+    // false when there was nothing to compact
+    fn compact_once(&self) -> Result<bool> {
+        let _running = self.running.lock().unwrap();
+        // tables installed by a flush in the meantime are no inputs, the install keeps them
+        let tables: Vec<Arc<SSTable>> = self.sstables.read().unwrap().clone();
+        let Some(task) = self.strategy.pick_compaction(&tables) else {
+            return Ok(false);
+        };
+        // snapshots taken from here on read the newest versions, which are always kept
+        let snapshots: Vec<u64> = self.snapshots.lock().unwrap().keys().copied().collect();
+        let outputs = self.strategy.execute(
+            &self.dir,
+            &tables,
+            &task,
+            &snapshots,
+            self.table.clone(),
+            self.merge_operator.as_deref(),
+        )?;
+
+        let removed = {
+            let mut manifest = self.manifest.lock().unwrap();
+            if family_dropped(&manifest, self.cf) {
+                for table in &outputs {
+                    let _ = remove_file(&table.file_path);
+                }
+                return Ok(false);
+            }
+            // the logged edit is the commit point, until then the outputs are just orphans
+            let mut edit = VersionEdit::default();
+            for id in &task.input_ids {
+                edit.remove_file(*id);
+            }
+            for table in &outputs {
+                edit.add_file(self.cf, table);
+            }
+            // the newest write the inputs held, their seqs must not be handed out again
+            let max_seq = tables
+                .iter()
+                .filter(|t| task.input_ids.contains(&t.id))
+                .map(|t| t.max_seq)
+                .max()
+                .unwrap_or(0);
+            edit.set_last_sequence(max_seq);
+            if let Err(err) = manifest.log(edit) {
+                for table in &outputs {
+                    let _ = remove_file(&table.file_path);
+                }
+                return Err(err);
+            }
+            // swap inputs for outputs in one go so readers never see a partial result
+            let mut tables = self.sstables.write().unwrap();
+            compact::install(&mut tables, &task, outputs)
+        };
+        // the MANIFEST no longer knows them, a file left behind is dropped at the next open
+        for table in removed {
+            let _ = remove_file(&table.file_path);
+        }
+        Ok(true)
+    }
+}
+
+// the default family can never be dropped
+fn family_dropped(manifest: &Manifest, cf: ColumnFamilyId) -> bool {
+    cf != DEFAULT_COLUMN_FAMILY && !manifest.state().column_families.contains_key(&cf)
+}
+
+// what a flush thread hands back, the flush stays pending until the coordinator drops it
+struct FlushingThreadResponse {
+    id: u64, // flushes are numbered in the order they were started
+    job: FlushJob,
+    result: Result<SSTable>,
+    _finished: FlushFinished,
+}
+
+// a rotated WAL, destroyed once every memtable frozen with it is installed
+struct FrozenWal {
+    id: u64,
+    wal: WalWriter,
+    unflushed: usize,
+}
+
+type FrozenMemtables = Arc<RwLock<VecDeque<Arc<dyn Memtable>>>>;
+type FrozenWals = Arc<Mutex<VecDeque<FrozenWal>>>;
+
+struct FlushingManager {
+    write_controller: Arc<WriteController>, // counts the flushes not installed yet
+    tx: Option<Sender<FlushingThreadResponse>>, // set once the coordinator runs, taken on drop
+    next_flush: u64,
+    coordinator: Option<JoinHandle<()>>,
+    compactor: Option<JoinHandle<()>>, // compacts a family after a flush installed a table into it
+    background_error: Arc<Mutex<Option<String>>>, // set by the first failed flush, kept until reopen
+    shutdown: Arc<AtomicBool>,                    // stops the retries once the engine is dropped
+}
+
+// marks the flush as finished when it is dropped, on every path
+struct FlushFinished(Arc<WriteController>);
+
+impl Drop for FlushFinished {
+    fn drop(&mut self) {
+        self.0.flush_finished();
+    }
+}
+
+// waits for the flushes still running, nothing touches the directory once the engine is gone
+impl Drop for FlushingManager {
+    fn drop(&mut self) {
+        self.shutdown.store(true, AtomicOrdering::Release);
+        self.tx = None;
+        if let Some(coordinator) = self.coordinator.take() {
+            let _ = coordinator.join();
+        }
+        // the coordinator is gone, and with it the last sender of compactions
+        if let Some(compactor) = self.compactor.take() {
+            let _ = compactor.join();
+        }
+    }
+}
+
+// Receives the results of the background threads in a single place, so tables are installed
+// and WALs destroyed one at a time, in the order the memtables were frozen. Runs until every
+// sender is gone.
+struct Coordinator {
+    manifest: Arc<Mutex<Manifest>>,
+    compactions: Sender<CompactionJob>,
+    frozen_wals: FrozenWals,
+    vlog: Arc<ValueLog>,
+    background_error: Arc<Mutex<Option<String>>>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl Coordinator {
+    fn run(self, rx: Receiver<FlushingThreadResponse>) {
+        // a flush that finished early waits for the older ones, readers search frozen memtables
+        // before tables and must never find an older memtable in front of a newer table
+        let mut finished = BTreeMap::new();
+        let mut next = 0;
+        for response in rx {
+            finished.insert(response.id, response);
+            while let Some(response) = finished.remove(&next) {
+                next += 1;
+                if !self.complete(response) {
+                    return;
+                }
+            }
+        }
+    }
+
+    // Installs the table, retrying the flush until it goes through so the memtable and its WAL
+    // can be released. The first failure sticks: writes are refused with it until the engine is
+    // reopened, even once a retry got through. False once the engine is gone, what was left is
+    // replayed from the WALs at the next open.
+    fn complete(&self, response: FlushingThreadResponse) -> bool {
+        let mut result = response.result;
+        loop {
+            match result.and_then(|table| self.install(&response.job, table)) {
+                Ok(()) => break,
+                Err(err) => {
+                    self.background_error
+                        .lock()
+                        .unwrap()
+                        .get_or_insert_with(|| err.to_string());
+                }
+            }
+            thread::sleep(FLUSH_RETRY_DELAY);
+            if self.shutdown.load(AtomicOrdering::Acquire) {
+                return false;
+            }
+            result = response.job.flush();
+        }
+        self.release(&response.job);
+        let _ = self.compactions.send(response.job.compaction);
+        true
+    }
+
+    fn install(&self, job: &FlushJob, table: SSTable) -> Result<()> {
+        let mut manifest = self.manifest.lock().unwrap();
+        if family_dropped(&manifest, job.cf) {
+            // the family was dropped while its memtable was being flushed, nobody reads the table
+            let _ = remove_file(&table.file_path);
+            return Ok(());
+        }
+        let mut edit = VersionEdit::default();
+        edit.add_file(job.cf, &table);
+        if let Err(err) = manifest.log(edit) {
+            let _ = remove_file(&table.file_path);
+            return Err(err);
+        }
+        let mut tables = job.sstables.write().unwrap();
+        tables.push(Arc::new(table));
+        compact::sort_sstables(&mut tables);
+        Ok(())
+    }
+
+    // once the table is in, the memtable and eventually its WAL can go
+    fn release(&self, job: &FlushJob) {
+        // readers find the entries in the table from now on
+        job.immutable
+            .write()
+            .unwrap()
+            .retain(|memtable| !Arc::ptr_eq(memtable, &job.memtable));
+
+        let mut frozen_wals = self.frozen_wals.lock().unwrap();
+        if let Some(frozen) = frozen_wals.iter_mut().find(|w| w.id == job.wal_id) {
+            frozen.unflushed -= 1;
+        }
+        // oldest first, a replay must never find an older WAL next to the tables of a newer one.
+        // a WAL that could not be removed is only replayed into a duplicate table at the next open
+        while frozen_wals.front().is_some_and(|w| w.unflushed == 0) {
+            let _ = frozen_wals.pop_front().unwrap().wal.destruct();
+        }
+        // no flush is running, every value log file is referenced by an installed table
+        if frozen_wals.is_empty() {
+            self.vlog.release_pending();
+        }
+    }
+}
+
+impl FlushingManager {
+    fn new(write_controller: Arc<WriteController>) -> Self {
+        Self {
+            write_controller,
+            tx: None,
+            next_flush: 0,
+            coordinator: None,
+            compactor: None,
+            background_error: Arc::new(Mutex::new(None)),
+            shutdown: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // once the MANIFEST is open, flushes can be installed
+    fn start(
+        &mut self,
+        manifest: Arc<Mutex<Manifest>>,
+        frozen_wals: FrozenWals,
+        vlog: Arc<ValueLog>,
+    ) {
+        let (tx, rx) = mpsc::channel::<FlushingThreadResponse>();
+        let (compactions, compaction_rx) = mpsc::channel::<CompactionJob>();
+        let shutdown = Arc::clone(&self.shutdown);
+        self.compactor = Some(spawn(move || {
+            for job in compaction_rx {
+                // a failed compaction leaves nothing behind, the next flush of the family retries
+                while !shutdown.load(AtomicOrdering::Acquire) && job.compact_once().unwrap_or(false)
+                {
+                }
+            }
+        }));
+        let coordinator = Coordinator {
+            manifest,
+            compactions,
+            frozen_wals,
+            vlog,
+            background_error: Arc::clone(&self.background_error),
+            shutdown: Arc::clone(&self.shutdown),
+        };
+        self.tx = Some(tx);
+        self.coordinator = Some(spawn(move || coordinator.run(rx)));
+    }
+
+    fn check_background_error(&self) -> Result<()> {
+        match &*self.background_error.lock().unwrap() {
+            Some(err) => Err(DbError::Background(err.clone())),
+            None => Ok(()),
+        }
+    }
+
+    // the coordinator installs the table, or retries the flush if it could not be written
+    fn background_flush_memtable(&mut self, job: FlushJob) -> Result<()> {
+        let Some(tx) = self.tx.clone() else {
+            return Err(DbError::Background(
+                "flushes cannot start before the engine is open".to_string(),
+            ));
+        };
+        let id = self.next_flush;
+        self.next_flush += 1;
+        self.write_controller.flush_started();
+        let finished = FlushFinished(Arc::clone(&self.write_controller));
+        spawn(move || {
+            let result = job.flush();
+            let _ = tx.send(FlushingThreadResponse {
+                id,
+                job,
+                result,
+                _finished: finished,
+            });
+        });
+
+        Ok(())
+    }
+
+    fn build_memtables_from_wal(
+        &mut self,
+        memtables: &mut BTreeMap<ColumnFamilyId, Box<dyn Memtable>>,
+        path: &Path,
+        options: &Options,
+    ) -> Result<()> {
+        let reader = WalReader::open(path, options.recovery)?
+            .with_limits(options.max_key_size, options.max_value_size);
+        for record in reader {
+            let (cf, record) = record?;
+            // the reader only hands out batches whose whole record checked out
+            if let WalRecord::Batch { seq, batch } = record {
+                for (cf, memtable) in memtables.iter_mut() {
+                    memtable.apply_batch(&batch, seq, *cf);
+                }
+                continue;
+            }
+            // the family was dropped after the record was written
+            let Some(memtable) = memtables.get_mut(&cf) else {
+                continue;
+            };
+            match record {
+                WalRecord::Insertion {
+                    seq,
+                    key,
+                    value,
+                    expires_at,
+                } => memtable.put(&key, &value, seq, expires_at),
+                WalRecord::Deletion { seq, key } => memtable.delete(&key, seq),
+                WalRecord::Merge { seq, key, operand } => memtable.merge(&key, &operand, seq),
+                WalRecord::RangeDeletion { seq, start, end } => {
+                    memtable.delete_range(&start, &end, seq)
+                }
+                WalRecord::Batch { .. } => unreachable!("batches are applied above"),
+            }
+        }
+        Ok(())
+    }
+    // replays an old WAL into fresh L0 tables, one for every column family it holds records of
+    fn retrieve_wal_records(
+        &mut self,
+        path: &Path,
+        dir: &Path,
+        options: &Options,
+        families: &BTreeMap<ColumnFamilyId, ColumnFamily>,
+        vlog: &ValueLog,
+    ) -> Result<Vec<(ColumnFamilyId, SSTable)>> {
+        let mut memtables: BTreeMap<ColumnFamilyId, Box<dyn Memtable>> = families
+            .iter()
+            .map(|(cf, family)| (*cf, family.options.memtable.new_memtable()))
+            .collect();
+        self.build_memtables_from_wal(&mut memtables, path, options)?;
+
+        let mut tables = Vec::new();
+        for (cf, memtable) in memtables {
+            if memtable.is_empty() {
+                continue;
+            }
+            let (_, tmp_path, final_path) = KVEngine::create_new_data_file(dir)?;
+            let table = options.table_options(families[&cf].options.compression);
+            // replay runs before a merge operator can be registered, compaction folds the operands
+            flush_memtable(
+                &*memtable,
+                &tmp_path,
+                &final_path,
+                table.clone(),
+                vlog,
+                None,
+                &[],
+            )?;
+            tables.push((cf, SSTable::load(&final_path, &table)?));
+        }
+        Ok(tables)
+    }
+}
+// seq of every live snapshot -> number of handles holding it
+type SnapshotList = Arc<Mutex<BTreeMap<u64, usize>>>;
+
+// A consistent read view of the engine: only writes with a sequence number at or below seq
+// are visible. Compaction keeps the versions it needs until the handle is dropped. Sequence
+// numbers mean nothing to another engine, reads through one are refused.
+pub struct Snapshot {
+    seq: u64,
+    live: SnapshotList,
+}
+
+impl Snapshot {
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn get(&self, engine: &KVEngine, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_cf(engine, DEFAULT_COLUMN_FAMILY, key)
+    }
+
+    pub fn get_cf(
+        &self,
+        engine: &KVEngine,
+        cf: ColumnFamilyId,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        self.check_engine(engine)?;
+        engine.get_at(cf, key, self.seq)
+    }
+
+    pub fn scan<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        engine: &KVEngine,
+        range: R,
+    ) -> Result<ScanIterator> {
+        self.scan_cf(engine, DEFAULT_COLUMN_FAMILY, range)
+    }
+
+    pub fn scan_cf<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        engine: &KVEngine,
+        cf: ColumnFamilyId,
+        range: R,
+    ) -> Result<ScanIterator> {
+        self.check_engine(engine)?;
+        Ok(engine.scan_at(engine.family(cf)?, range, self.seq))
+    }
+
+    // every engine keeps its own list of live snapshots, the handle is registered in it
+    fn check_engine(&self, engine: &KVEngine) -> Result<()> {
+        if !Arc::ptr_eq(&self.live, &engine.snapshots) {
+            return Err(DbError::ForeignSnapshot);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut live = self.live.lock().unwrap();
+        if let Some(count) = live.get_mut(&self.seq) {
+            *count -= 1;
+            if *count == 0 {
+                live.remove(&self.seq);
+            }
+        }
+    }
+}
+
+// Tuning of one column family.
+pub struct ColumnFamilyOptions {
+    pub(crate) compaction: CompactionStrategy,
+    pub(crate) compression: CompressionType, // codec for the blocks the family writes, any codec can be read
+    pub(crate) memtable_threshold: u64,
+    pub(crate) memtable: MemtableKind,
+}
+
+impl Default for ColumnFamilyOptions {
+    fn default() -> Self {
+        Self {
+            compaction: CompactionStrategy::default(),
+            compression: CompressionType::default(),
+            memtable_threshold: MEMTABLE_THRESHOLD,
+            memtable: MemtableKind::default(),
+        }
+    }
+}
+
+impl ColumnFamilyOptions {
+    pub fn compaction(mut self, compaction: CompactionStrategy) -> Self {
+        self.compaction = compaction;
+        self
+    }
+
+    pub fn compression(mut self, compression: CompressionType) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn memtable_threshold(mut self, bytes: u64) -> Self {
+        self.memtable_threshold = bytes;
+        self
+    }
+
+    pub fn memtable(mut self, kind: MemtableKind) -> Self {
+        self.memtable = kind;
+        self
+    }
+}
+
+// A separate keyspace with its own memtable, tables and options. Families share the WAL,
+// the MANIFEST, the value log and sequence numbers, so batches and snapshots span all of them.
+struct ColumnFamily {
+    id: ColumnFamilyId,
+    name: String,
+    options: ColumnFamilyOptions,
+    memtable: Box<dyn Memtable>,
+    immutable: FrozenMemtables, // frozen memtables waiting on their flush, newest first
+    sstables: Arc<RwLock<Vec<Arc<SSTable>>>>,
+}
+
+impl ColumnFamily {
+    fn new(id: ColumnFamilyId, name: &str, options: ColumnFamilyOptions) -> Self {
+        Self {
+            id,
+            name: name.to_string(),
+            memtable: options.memtable.new_memtable(),
+            options,
+            immutable: Arc::new(RwLock::new(VecDeque::new())),
+            sstables: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+
+    // whether bytes more would take the memtable past its threshold
+    fn is_full(&self, bytes: u64) -> bool {
+        bytes + self.memtable.memory_usage() >= self.options.memtable_threshold
+    }
+
+    fn search_for_kv_in_sstables(
+        &self,
+        key: &[u8],
+        read_seq: u64,
+        cache: &BlockCache,
+    ) -> Result<Option<KvEntry>> {
+        // Lock here is held for the entirety of the loop. Ok for now, mostly reads, rare writes
+        // tables are kept newest first (L0 by id, then L1, L2..) so the first hit wins
+        for element in self.sstables.read().unwrap().iter() {
+            match KVEngine::should_search_sstable_file(key, element) {
+                true => {
+                    if let Some(entry) =
+                        KVEngine::search_kv_in_sstable(element, cache, key, read_seq)?
+                    {
+                        return Ok(Some(entry));
+                    }
+                }
+                false => continue,
+            }
+        }
+        Ok(None)
+    }
+
+    // newest entry for key written at or before read_seq, tombstones included. Entries under
+    // a range tombstone or past their expiry come back marked deleted. The value is still a pointer if it was separated
+    fn lookup(&self, key: &[u8], read_seq: u64, cache: &BlockCache) -> Result<Option<KvEntry>> {
+        // the active memtable, then the frozen ones newest first
+        let immutable = self.immutable.read().unwrap();
+        let val = std::iter::once(&*self.memtable)
+            .chain(immutable.iter().map(|m| &**m))
+            .find_map(|m| m.get(key, read_seq));
+
+        let mut entry = match val {
+            None => self.search_for_kv_in_sstables(key, read_seq, cache)?,
+            found => found,
+        };
+        if let Some(entry) = entry.as_mut() {
+            // the guard is reused, a second read lock could queue behind an installing flush
+            let tombstones = self.range_tombstones_locked(&immutable, read_seq);
+            entry.deleted |= range_del::is_covered(&tombstones, key, entry.seq)
+                || entry.is_expired(new_timestamp());
+        }
+        Ok(entry)
+    }
+
+    // range tombstones of every memtable and table written at or before read_seq
+    fn range_tombstones(&self, read_seq: u64) -> Vec<RangeTombstone> {
+        self.range_tombstones_locked(&self.immutable.read().unwrap(), read_seq)
+    }
+
+    fn range_tombstones_locked(
+        &self,
+        immutable: &VecDeque<Arc<dyn Memtable>>,
+        read_seq: u64,
+    ) -> Vec<RangeTombstone> {
+        let mut tombstones = self.memtable.range_tombstones();
+        for frozen in immutable {
+            tombstones.extend(frozen.range_tombstones());
+        }
+        for table in self.sstables.read().unwrap().iter() {
+            tombstones.extend_from_slice(&table.range_tombstones);
+        }
+        tombstones.retain(|t| t.seq <= read_seq);
+        tombstones
+    }
+}
+
+pub struct KVEngine {
+    data_directory: PathBuf,
+    options: Options,
+    wal: WalWriter,          // shared by every column family
+    frozen_wals: FrozenWals, // one per rotation whose memtables are not installed yet, oldest first
+    rotations: u64,
+    families: BTreeMap<ColumnFamilyId, ColumnFamily>,
+    flushing_manager: FlushingManager,
+    manifest: Arc<Mutex<Manifest>>, // shared with the coordinator installing flushed tables
+    block_cache: Arc<BlockCache>,   // shared by every table, keyed by table id and block offset
+    vlog: Arc<ValueLog>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    last_seq: u64, // sequence number of the latest write
+    snapshots: SnapshotList,
+    compaction_running: Arc<Mutex<()>>, // held by the compaction in progress
+}
+
+impl KVEngine {
+    pub(crate) fn create_new_data_file(dir: &Path) -> io::Result<(File, PathBuf, PathBuf)> {
+        let file_number = new_file_number();
+        let data_file_path_final = dir.join(format!("{}.sst", file_number));
+        let data_file_path_tmp = dir.join(format!("{}.sst.tmp", file_number));
+        let data_file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create_new(true)
+            .open(&data_file_path_tmp)?;
+        Ok((data_file, data_file_path_tmp, data_file_path_final))
+    }
+
+    // fails on invalid options and on options the data directory was not written to fit
+    pub fn open(dir_name: &Path, options: Options) -> Result<KVEngine> {
+        let path = PathBuf::from(dir_name);
+        options.validate()?;
+        if let Some(stored) = Options::load(dir_name)? {
+            options.check_compatible(&stored)?;
+        }
+
+        let recovered = Manifest::recover(dir_name)?;
+        // directories from before the MANIFEST existed keep every table they hold
+        let adopt_all = recovered.is_none();
+        let state = recovered.unwrap_or_default();
+        reserve_file_numbers(state.next_file_number);
+
+        let mut families = BTreeMap::new();
+        families.insert(
+            DEFAULT_COLUMN_FAMILY,
+            ColumnFamily::new(
+                DEFAULT_COLUMN_FAMILY,
+                DEFAULT_COLUMN_FAMILY_NAME,
+                options.column_family_options(),
+            ),
+        );
+        // families from before their options were kept in the MANIFEST take the engine ones
+        for (cf, name) in &state.column_families {
+            let family_options = match state.column_family_options.get(cf) {
+                Some(encoded) => ColumnFamilyOptions::decode(encoded).ok_or_else(|| {
+                    DbError::ColumnFamily(format!(
+                        "options of column family {} in the MANIFEST are malformed",
+                        name
+                    ))
+                })?,
+                None => options.column_family_options(),
+            };
+            options.check_compression(family_options.compression)?;
+            families.insert(*cf, ColumnFamily::new(*cf, name, family_options));
+        }
+
+        // every table is read with the codecs of the engine, whatever family it belongs to
+        let reading = options.table_options(options.compression);
+        let mut sstables: Vec<(ColumnFamilyId, Arc<SSTable>)> = Vec::new();
+        let mut old_wals: Vec<PathBuf> = Vec::new();
+
+        for entry in fs::read_dir(dir_name)? {
+            let entry = entry?;
+            let path = entry.path();
+            if !path.is_file() {
+                continue;
+            }
+            // a flush or compaction that never got to its rename
+            if path.to_string_lossy().ends_with(".sst.tmp") {
+                remove_file(&path)?;
+                continue;
+            }
+
+            let ext = match path.extension().and_then(|x| x.to_str()) {
+                Some(e) => e,
+                _ => continue,
+            };
+            if ext == "sst" {
+                let id = path
+                    .file_stem()
+                    .and_then(|x| x.to_str()?.parse::<u64>().ok());
+                match id.and_then(|id| state.files.get(&id)) {
+                    Some(meta) => {
+                        let mut ss_table = SSTable::load(&path, &reading)?;
+                        ss_table.level = meta.level;
+                        sstables.push((meta.column_family, Arc::new(ss_table)));
+                    }
+                    None if adopt_all => sstables.push((
+                        DEFAULT_COLUMN_FAMILY,
+                        Arc::new(SSTable::load(&path, &reading)?),
+                    )),
+                    // written but never logged, or already compacted away
+                    None => remove_file(&path)?,
+                }
+            } else if ext == "wal" {
+                old_wals.push(path);
+            }
+        }
+        if let Some(missing) = state
+            .files
+            .keys()
+            .find(|id| !sstables.iter().any(|(_, t)| t.id == **id))
+        {
+            return Err(DbError::FileError(
+                "table listed in the MANIFEST is missing".to_string(),
+                dir_name.join(format!("{}.sst", missing)),
+            ));
+        }
+
+        // new WAL is created after the scan so it doesnt get replayed
+        let wal = WalWriter::new(dir_name, options.sync)?;
+        let mut flushing_manager = FlushingManager::new(Arc::new(WriteController::new(
+            options.slowdown_pending_memtables,
+            options.max_pending_memtables,
+        )));
+        let vlog = Arc::new(ValueLog::new(dir_name, &options));
+
+        // flush old wals to disk, oldest first so the resulting table ids keep their order
+        old_wals.sort();
+        for wal_path in &old_wals {
+            // wal populates a memtable per family and we flush each to disk as an .sst
+            for (cf, ss_table) in flushing_manager
+                .retrieve_wal_records(wal_path, dir_name, &options, &families, &vlog)?
+            {
+                sstables.push((cf, Arc::new(ss_table)));
+            }
+        }
+
+        // every old WAL has been turned into a table by now
+        let last_seq = sstables
+            .iter()
+            .map(|(_, t)| t.max_seq)
+            .max()
+            .unwrap_or(0)
+            .max(state.last_sequence);
+
+        // the fresh manifest takes over the families and the replayed tables, only then can their WALs go
+        let mut edit = VersionEdit::default();
+        for (cf, name) in &state.column_families {
+            edit.add_column_family(*cf, name);
+            edit.set_column_family_options(*cf, families[cf].options.encode());
+        }
+        edit.set_next_column_family(state.next_column_family);
+        for (cf, table) in &sstables {
+            edit.add_file(*cf, table);
+        }
+        edit.set_last_sequence(last_seq);
+        let manifest = Arc::new(Mutex::new(Manifest::create(dir_name, edit)?));
+        for wal_path in &old_wals {
+            remove_file(wal_path)?;
+        }
+        vlog.release_pending();
+        // only once recovery went through, a failed open leaves the options the data was written with
+        options.persist(dir_name)?;
+
+        let frozen_wals = FrozenWals::default();
+        flushing_manager.start(
+            Arc::clone(&manifest),
+            Arc::clone(&frozen_wals),
+            Arc::clone(&vlog),
+        );
+
+        for (cf, table) in sstables {
+            if let Some(family) = families.get_mut(&cf) {
+                family.sstables.write().unwrap().push(table);
+            }
+        }
+        for family in families.values() {
+            compact::sort_sstables(&mut family.sstables.write().unwrap());
+        }
+
+        Ok(Self {
+            data_directory: path,
+            options,
+            wal,
+            frozen_wals,
+            rotations: 0,
+            flushing_manager,
+            manifest,
+            block_cache: Arc::new(BlockCache::new(BLOCK_CACHE_CAPACITY)),
+            families,
+            vlog,
+            merge_operator: None,
+            last_seq,
+            snapshots: Arc::new(Mutex::new(BTreeMap::new())),
+            compaction_running: Arc::new(Mutex::new(())),
+        })
+    }
+
+    fn should_search_sstable_file(key: &[u8], sstable: &SSTable) -> bool {
+        // checks the metadata of sstable and tells us whether we should look for the kv in the sstable
+        if key > sstable.max_key.as_slice() || key < sstable.min_key.as_slice() {
+            return false;
+        }
+        let bf_bit_positions =
+            get_hashed_key_positions(key, sstable.bloom_filter.num_bits as usize);
+        sstable.bloom_filter.check_bits(bf_bit_positions)
+    }
+
+    // returns the newest entry for key written at or before read_seq, tombstones included
+    fn search_kv_in_sstable(
+        sstable: &SSTable,
+        cache: &BlockCache,
+        key: &[u8],
+        read_seq: u64,
+    ) -> Result<Option<KvEntry>> {
+        let Some(mut block_idx) = sstable.binary_search_sparse_index(key) else {
+            return Ok(None);
+        };
+
+        loop {
+            let block = sstable.block(block_idx, Some(cache))?;
+            // versions follow newest first, skip the ones written after read_seq
+            for idx in block.partition(sstable, |k| k < key)?..block.len() {
+                let record = block.record(sstable, idx)?;
+                if *record.key != *key {
+                    return Ok(None);
+                }
+                if record.seq <= read_seq {
+                    return Ok(Some(record.to_entry()));
+                }
+            }
+            // the older versions go on in the next block, if it starts with the key
+            block_idx += 1;
+            match sstable.sparse_index.get(block_idx) {
+                Some((first_key, _, _)) if first_key.as_slice() == key => {}
+                _ => return Ok(None),
+            }
+        }
+    }
+    fn family(&self, cf: ColumnFamilyId) -> Result<&ColumnFamily> {
+        self.families
+            .get(&cf)
+            .ok_or_else(|| DbError::ColumnFamily(format!("no column family with id {}", cf)))
+    }
+
+    fn family_mut(&mut self, cf: ColumnFamilyId) -> Result<&mut ColumnFamily> {
+        self.families
+            .get_mut(&cf)
+            .ok_or_else(|| DbError::ColumnFamily(format!("no column family with id {}", cf)))
+    }
+
+    fn default_family(&self) -> &ColumnFamily {
+        &self.families[&DEFAULT_COLUMN_FAMILY]
+    }
+
+    pub fn column_family(&self, name: &str) -> Option<ColumnFamilyId> {
+        self.families
+            .values()
+            .find(|family| family.name == name)
+            .map(|family| family.id)
+    }
+
+    pub fn list_column_families(&self) -> Vec<String> {
+        self.families
+            .values()
+            .map(|family| family.name.clone())
+            .collect()
+    }
+
+    pub fn create_column_family(
+        &mut self,
+        name: &str,
+        options: ColumnFamilyOptions,
+    ) -> Result<ColumnFamilyId> {
+        if self.column_family(name).is_some() {
+            return Err(DbError::ColumnFamily(format!(
+                "column family {} already exists",
+                name
+            )));
+        }
+        self.options.check_compression(options.compression)?;
+        let cf = self
+            .manifest
+            .lock()
+            .unwrap()
+            .state()
+            .next_column_family
+            .max(DEFAULT_COLUMN_FAMILY + 1);
+        let mut edit = VersionEdit::default();
+        edit.add_column_family(cf, name);
+        edit.set_column_family_options(cf, options.encode());
+        self.manifest.lock().unwrap().log(edit)?;
+        self.families
+            .insert(cf, ColumnFamily::new(cf, name, options));
+        Ok(cf)
+    }
+
+    // kept in the MANIFEST like the options the family was created with. The default family
+    // takes its options from Options again at the next open
+    pub fn set_column_family_options(
+        &mut self,
+        cf: ColumnFamilyId,
+        options: ColumnFamilyOptions,
+    ) -> Result<()> {
+        self.family(cf)?;
+        self.options.check_compression(options.compression)?;
+        if cf != DEFAULT_COLUMN_FAMILY {
+            let mut edit = VersionEdit::default();
+            edit.set_column_family_options(cf, options.encode());
+            self.manifest.lock().unwrap().log(edit)?;
+        }
+        self.family_mut(cf)?.options = options;
+        Ok(())
+    }
+
+    // drops the family with everything written to it, replay skips its records in the WAL
+    pub fn drop_column_family(&mut self, name: &str) -> Result<()> {
+        let cf = self
+            .column_family(name)
+            .ok_or_else(|| DbError::ColumnFamily(format!("no column family named {}", name)))?;
+        if cf == DEFAULT_COLUMN_FAMILY {
+            return Err(DbError::ColumnFamily(
+                "the default column family cannot be dropped".to_string(),
+            ));
+        }
+        let mut edit = VersionEdit::default();
+        edit.drop_column_family(cf);
+        self.manifest.lock().unwrap().log(edit)?;
+
+        let family = self.families.remove(&cf).unwrap();
+        for table in family.sstables.read().unwrap().iter() {
+            remove_file(&table.file_path)?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_at(DEFAULT_COLUMN_FAMILY, key, self.last_seq)
+    }
+
+    pub fn get_cf(&self, cf: ColumnFamilyId, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_at(cf, key, self.last_seq)
+    }
+
+    fn get_at(&self, cf: ColumnFamilyId, key: &[u8], read_seq: u64) -> Result<Option<Vec<u8>>> {
+        let family = self.family(cf)?;
+        // operands are collected newest first until a value or a tombstone ends the chain
+        let mut operands = Vec::new();
+        let mut seq = read_seq;
+        let existing = loop {
+            match family.lookup(key, seq, &self.block_cache)? {
+                Some(entry) if entry.deleted => break None,
+                Some(entry) if entry.merge_operand => {
+                    operands.push(entry.value);
+                    let Some(older) = entry.seq.checked_sub(1) else {
+                        break None;
+                    };
+                    seq = older;
+                }
+                Some(entry) => break Some(self.vlog.resolve(&entry)?),
+                None => break None,
+            }
+        };
+        merge::apply_operands(self.merge_operator.as_deref(), key, existing, &operands)
+    }
+
+    fn scan<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> ScanIterator {
+        self.scan_at(self.default_family(), range, self.last_seq)
+    }
+
+    pub fn scan_cf<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        cf: ColumnFamilyId,
+        range: R,
+    ) -> Result<ScanIterator> {
+        Ok(self.scan_at(self.family(cf)?, range, self.last_seq))
+    }
+
+    fn scan_at<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        family: &ColumnFamily,
+        range: R,
+        read_seq: u64,
+    ) -> ScanIterator {
+        let lower = range.start_bound().map(|k| k.as_ref());
+        let upper = range.end_bound().map(|k| k.as_ref());
+
+        // newest source first: active memtable, frozen memtables, then tables in search order
+        let mut sources: Vec<EntrySource> = Vec::new();
+        // memtable entries are copied, the scan must not hold on to the memtable
+        let copy = |memtable: &dyn Memtable| -> EntrySource {
+            let entries: Vec<KvEntry> = memtable
+                .iter(lower, upper)
+                .map(MemtableEntry::to_kv_entry)
+                .collect();
+            Box::new(entries.into_iter().map(Ok))
+        };
+        sources.push(copy(&*family.memtable));
+        for frozen in family.immutable.read().unwrap().iter() {
+            sources.push(copy(&**frozen));
+        }
+
+        for table in family.sstables.read().unwrap().iter() {
+            if !key_below_upper(&table.min_key, upper) || !key_above_lower(&table.max_key, lower) {
+                continue;
+            }
+
+            let mut iter = table.cached_iter(&self.block_cache);
+            let seeked = match lower {
+                Bound::Included(l) | Bound::Excluded(l) => iter.seek(l),
+                Bound::Unbounded => Ok(()),
+            };
+            match seeked {
+                Ok(()) => sources.push(Box::new(iter)),
+                // surfaces through the iterator like any other read error
+                Err(err) => sources.push(Box::new(std::iter::once(Err(err)))),
+            }
+        }
+
+        ScanIterator {
+            merged: MergeIterator::new(sources).peekable(),
+            lower: lower.map(<[u8]>::to_vec),
+            upper: upper.map(<[u8]>::to_vec),
+            read_seq,
+            last_key: None,
+            done: false,
+            vlog: Arc::clone(&self.vlog),
+            range_tombstones: family.range_tombstones(read_seq),
+            now: new_timestamp(),
+            merge_operator: self.merge_operator.clone(),
+        }
+    }
+
+    // unpositioned until one of the seek methods is called
+    pub fn iter(&self) -> LsmIterator<'_> {
+        self.iter_family(self.default_family())
+    }
+
+    pub fn iter_cf(&self, cf: ColumnFamilyId) -> Result<LsmIterator<'_>> {
+        Ok(self.iter_family(self.family(cf)?))
+    }
+
+    fn iter_family<'a>(&'a self, family: &'a ColumnFamily) -> LsmIterator<'a> {
+        let read_seq = self.last_seq;
+        let cf = family.id;
+        let mut children: Vec<Box<dyn EntryCursor + '_>> =
+            vec![Box::new(MemtableCursor::new(&*family.memtable, read_seq))];
+        for frozen in family.immutable.read().unwrap().iter() {
+            children.push(Box::new(MemtableCursor::new(Arc::clone(frozen), read_seq)));
+        }
+        for table in family.sstables.read().unwrap().iter() {
+            children.push(Box::new(SsTableCursor::new(
+                Arc::clone(table),
+                Arc::clone(&self.block_cache),
+                read_seq,
+            )));
+        }
+        LsmIterator::new(
+            children,
+            Arc::clone(&self.vlog),
+            family.range_tombstones(read_seq),
+            Box::new(move |key| self.get_at(cf, key, read_seq)),
+        )
+    }
+
+    pub fn block_cache_stats(&self) -> BlockCacheStats {
+        self.block_cache.stats()
+    }
+
+    pub fn write_stall_stats(&self) -> WriteStallStats {
+        self.flushing_manager.write_controller.stats()
+    }
+
+    // pins the current sequence number, reads through the handle ignore every later write
+    pub fn snapshot(&self) -> Snapshot {
+        *self
+            .snapshots
+            .lock()
+            .unwrap()
+            .entry(self.last_seq)
+            .or_insert(0) += 1;
+        Snapshot {
+            seq: self.last_seq,
+            live: Arc::clone(&self.snapshots),
+        }
+    }
+
+    // runs compactions until the strategy of every column family has nothing left to do
+    pub fn compact(&self) -> Result<()> {
+        for job in self.compaction_jobs() {
+            job.run()?;
+        }
+        Ok(())
+    }
+
+    fn compaction_jobs(&self) -> Vec<CompactionJob> {
+        self.families
+            .values()
+            .map(|family| self.compaction_job(family))
+            .collect()
+    }
+
+    fn compaction_job(&self, family: &ColumnFamily) -> CompactionJob {
+        CompactionJob {
+            cf: family.id,
+            dir: self.data_directory.clone(),
+            sstables: Arc::clone(&family.sstables),
+            strategy: family.options.compaction.clone(),
+            table: self.options.table_options(family.options.compression),
+            merge_operator: self.merge_operator.clone(),
+            snapshots: Arc::clone(&self.snapshots),
+            manifest: Arc::clone(&self.manifest),
+            running: Arc::clone(&self.compaction_running),
+        }
+    }
+
+    // Value log garbage collection. A file is collected once at most half of it is still live:
+    // live values are written again through the normal write path and the file is deleted.
+    // Returns the number of bytes reclaimed
+    pub fn collect_value_log(&mut self) -> Result<u64> {
+        let snapshots: Vec<u64> = self.snapshots.lock().unwrap().keys().copied().collect();
+        let mut reclaimed = 0;
+
+        'files: for file_id in self.vlog.collectable_files()? {
+            let (file_size, records) = self.vlog.records(file_id)?;
+            let mut live = Vec::new();
+            let mut live_bytes = 0;
+            for (key, pointer) in records {
+                // a snapshot still reads this copy, the file has to stay
+                for seq in &snapshots {
+                    if self.points_to(&key, pointer, *seq)?.is_some() {
+                        continue 'files;
+                    }
+                }
+                if let Some((cf, expires_at, under_operands)) =
+                    self.points_to(&key, pointer, self.last_seq)?
+                {
+                    // folded, the operands would outlive the value they were applied to
+                    if under_operands && expires_at != 0 {
+                        continue 'files;
+                    }
+                    live_bytes += pointer.size;
+                    live.push((cf, key, pointer, expires_at, under_operands));
+                }
+            }
+            if live_bytes * 2 > file_size {
+                continue;
+            }
+
+            for (cf, key, pointer, expires_at, under_operands) in &live {
+                // a put on top of merge operands has to carry what reads see through them
+                let value = match under_operands {
+                    true => self.get_at(*cf, key, self.last_seq)?.unwrap_or_default(),
+                    false => self.vlog.read(key, *pointer)?,
+                };
+                self.put_expiring(*cf, key, &value, *expires_at)?;
+            }
+            // the new copies have to survive a crash before the old ones go
+            if !live.is_empty() {
+                self.sync()?;
+            }
+            self.vlog.remove(file_id)?;
+            reclaimed += file_size - live_bytes;
+        }
+        Ok(reclaimed)
+    }
+
+    // whether the value read at read_seq, or the one its merge operands apply to, is the value
+    // log record at pointer. Returns the column family it belongs to, its expiry and whether
+    // operands sit on top of it
+    fn points_to(
+        &self,
+        key: &[u8],
+        pointer: ValuePointer,
+        read_seq: u64,
+    ) -> Result<Option<(ColumnFamilyId, u64, bool)>> {
+        // a value log file is written by the flush of one family, no other one points into it
+        'families: for family in self.families.values() {
+            let mut seq = read_seq;
+            let mut under_operands = false;
+            loop {
+                let Some(entry) = family.lookup(key, seq, &self.block_cache)? else {
+                    continue 'families;
+                };
+                if !entry.deleted && entry.merge_operand {
+                    under_operands = true;
+                    match entry.seq.checked_sub(1) {
+                        Some(older) => seq = older,
+                        None => continue 'families,
+                    }
+                    continue;
+                }
+                if !entry.deleted
+                    && entry.value_ptr
+                    && ValuePointer::decode(&entry.value) == Some(pointer)
+                {
+                    return Ok(Some((family.id, entry.expires_at, under_operands)));
+                }
+                continue 'families;
+            }
+        }
+        Ok(None)
+    }
+
+    // anything replay would read as corruption is turned away before it reaches the WAL
+    fn check_entry_size(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        if key.len() as u64 > self.options.max_key_size {
+            return Err(DbError::InvalidKey(format!(
+                "{} bytes is longer than max_key_size {}",
+                key.len(),
+                self.options.max_key_size
+            )));
+        }
+        if value.len() as u64 > self.options.max_value_size {
+            return Err(DbError::InvalidValue(format!(
+                "{} bytes is longer than max_value_size {}",
+                value.len(),
+                self.options.max_value_size
+            )));
+        }
+        Ok(())
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.put_expiring(DEFAULT_COLUMN_FAMILY, key, value, 0)
+    }
+
+    pub fn put_cf(&mut self, cf: ColumnFamilyId, key: &[u8], value: &[u8]) -> Result<()> {
+        self.put_expiring(cf, key, value, 0)
+    }
+
+    // reads treat the value as absent once ttl has passed, compaction drops it
+    pub fn put_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        let expires_at = Self::expires_at(ttl);
+        self.put_expiring(DEFAULT_COLUMN_FAMILY, key, value, expires_at)
+    }
+
+    fn expires_at(ttl: Duration) -> u64 {
+        let ttl = u64::try_from(ttl.as_nanos()).unwrap_or(u64::MAX);
+        // 0 would mean no expiry at all
+        new_timestamp().saturating_add(ttl).max(1)
+    }
+
+    fn put_expiring(
+        &mut self,
+        cf: ColumnFamilyId,
+        key: &[u8],
+        value: &[u8],
+        expires_at: u64,
+    ) -> Result<()> {
+        let record = match expires_at {
+            0 => WalRecordType::Insertion(key, value),
+            _ => WalRecordType::ExpiringInsertion(key, value, expires_at),
+        };
+        self.write_record(cf, record)
+    }
+
+    // applies every operation of the batch or none of them, whatever column families they touch
+    pub fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        // a batch names the family of every operation itself
+        self.write_record(DEFAULT_COLUMN_FAMILY, WalRecordType::Batch(&batch))
+    }
+
+    // Every write goes through check_write, log_write and apply_write in that order. Only the
+    // last one needs exclusive access, Db runs the other two next to readers.
+    fn write_record(&mut self, cf: ColumnFamilyId, record: WalRecordType) -> Result<()> {
+        if self.check_write(cf, record)? {
+            self.flushing_manager.write_controller.wait_for_flush();
+            self.rotate_memtable_and_wal()?;
+        }
+        self.flushing_manager.write_controller.delay_write();
+        // logged before it becomes visible, so a failed append never shows up in reads
+        let seq = self.last_seq + 1;
+        self.log_write(seq, cf, record)?.wait_durable()?;
+        self.apply_write(seq, cf, record)
+    }
+
+    // fails before anything is logged, true when the memtables have to be rotated first
+    fn check_write(&self, cf: ColumnFamilyId, record: WalRecordType) -> Result<bool> {
+        self.flushing_manager.check_background_error()?;
+        let (touched, bytes) = match record {
+            WalRecordType::Insertion(key, value)
+            | WalRecordType::ExpiringInsertion(key, value, _)
+            | WalRecordType::Merge(key, value) => {
+                self.check_entry_size(key, value)?;
+                (BTreeSet::from([cf]), key.len() + value.len())
+            }
+            WalRecordType::Deletion(key) => {
+                self.check_entry_size(key, &[])?;
+                (BTreeSet::from([cf]), key.len())
+            }
+            WalRecordType::RangeDeletion(start, end) => {
+                self.check_entry_size(start, &[])?;
+                self.check_entry_size(end, &[])?;
+                (BTreeSet::from([cf]), start.len() + end.len())
+            }
+            WalRecordType::Batch(batch) => {
+                for (_, op) in batch.ops() {
+                    match op {
+                        BatchOp::Put(key, value) => self.check_entry_size(key, value)?,
+                        BatchOp::Delete(key) => self.check_entry_size(key, &[])?,
+                    }
+                }
+                let touched = batch.ops().iter().map(|(cf, _)| *cf).collect();
+                (touched, batch.size() as usize)
+            }
+        };
+        // rotate up front, a write never straddles two memtables
+        let mut full = false;
+        for cf in &touched {
+            full |= self.family(*cf)?.is_full(bytes as u64);
+        }
+        Ok(full)
+    }
+
+    // hands the write to the WAL under seq, returns the ticket to wait on for durability.
+    // The WAL is shared by reference, so this only needs &self
+    fn log_write(&self, seq: u64, cf: ColumnFamilyId, record: WalRecordType) -> Result<Ticket> {
+        self.wal.append(seq, cf, record)
+    }
+
+    // makes a logged write visible
+    fn apply_write(&mut self, seq: u64, cf: ColumnFamilyId, record: WalRecordType) -> Result<()> {
+        let memtable = &mut self.family_mut(cf)?.memtable;
+        match record {
+            WalRecordType::Insertion(key, value) => memtable.put(key, value, seq, 0),
+            WalRecordType::ExpiringInsertion(key, value, expires_at) => {
+                memtable.put(key, value, seq, expires_at)
+            }
+            WalRecordType::Deletion(key) => memtable.delete(key, seq),
+            WalRecordType::Merge(key, operand) => memtable.merge(key, operand, seq),
+            WalRecordType::RangeDeletion(start, end) => memtable.delete_range(start, end, seq),
+            WalRecordType::Batch(batch) => {
+                let touched: BTreeSet<ColumnFamilyId> =
+                    batch.ops().iter().map(|(cf, _)| *cf).collect();
+                for cf in touched {
+                    self.family_mut(cf)?.memtable.apply_batch(batch, seq, cf);
+                }
+            }
+        }
+        self.publish(seq, record);
+        Ok(())
+    }
+
+    // Inserts a logged write while readers keep going, which works when every memtable it
+    // touches is a skiplist. Nothing of it is visible until publish. false leaves the write
+    // to apply_write
+    fn apply_shared(&self, seq: u64, cf: ColumnFamilyId, record: WalRecordType) -> Result<bool> {
+        let touched: BTreeSet<ColumnFamilyId> = match record {
+            WalRecordType::Batch(batch) => batch.ops().iter().map(|(cf, _)| *cf).collect(),
+            _ => BTreeSet::from([cf]),
+        };
+        let mut lists = Vec::new();
+        for cf in touched {
+            match self.family(cf)?.memtable.concurrent() {
+                Some(list) => lists.push((cf, list)),
+                None => return Ok(false),
+            }
+        }
+        for (cf, list) in lists {
+            match record {
+                WalRecordType::Insertion(key, value) => list.put(key, value, seq, 0),
+                WalRecordType::ExpiringInsertion(key, value, expires_at) => {
+                    list.put(key, value, seq, expires_at)
+                }
+                WalRecordType::Deletion(key) => list.delete(key, seq),
+                WalRecordType::Merge(key, operand) => list.merge(key, operand, seq),
+                WalRecordType::RangeDeletion(start, end) => list.delete_range(start, end, seq),
+                WalRecordType::Batch(batch) => list.apply_batch(batch, seq, cf),
+            }
+        }
+        Ok(true)
+    }
+
+    // reads resolve against last_seq, so a batch becomes visible at once
+    fn publish(&mut self, seq: u64, record: WalRecordType) {
+        self.last_seq = last_seq_of(seq, record);
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.delete_cf(DEFAULT_COLUMN_FAMILY, key)
+    }
+
+    pub fn delete_cf(&mut self, cf: ColumnFamilyId, key: &[u8]) -> Result<()> {
+        self.write_record(cf, WalRecordType::Deletion(key))
+    }
+
+    // Conditional writes. Writers are serialized by &mut self (a Db holds its writer lock across
+    // both), so nothing gets between the check and the write. Nothing is logged when the condition fails, the result says whether
+    // the write happened
+    pub fn put_if_absent(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        self.compare_and_swap(key, None, value)
+    }
+
+    // expected None means the key has to be absent
+    pub fn compare_and_swap(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: &[u8],
+    ) -> Result<bool> {
+        if self
+            .get_at(DEFAULT_COLUMN_FAMILY, key, self.last_seq)?
+            .as_deref()
+            != expected
+        {
+            return Ok(false);
+        }
+        self.put(key, new)?;
+        Ok(true)
+    }
+
+    pub fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool> {
+        if self
+            .get_at(DEFAULT_COLUMN_FAMILY, key, self.last_seq)?
+            .as_deref()
+            != Some(expected)
+        {
+            return Ok(false);
+        }
+        self.delete(key)?;
+        Ok(true)
+    }
+
+    // reads fold every operand of key onto the value below them with the registered operator
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.write_record(DEFAULT_COLUMN_FAMILY, WalRecordType::Merge(key, operand))
+    }
+
+    pub fn set_merge_operator(&mut self, operator: Arc<dyn MergeOperator>) {
+        self.merge_operator = Some(operator);
+    }
+
+    // deletes every key in [start, end) with a single tombstone
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) -> Result<()> {
+        if start >= end {
+            return Ok(());
+        }
+        self.write_record(
+            DEFAULT_COLUMN_FAMILY,
+            WalRecordType::RangeDeletion(start, end),
+        )
+    }
+
+    // fsyncs every write so far whatever the sync option says
+    fn sync(&self) -> Result<()> {
+        for frozen in self.frozen_wals.lock().unwrap().iter() {
+            frozen.wal.sync()?;
+        }
+        self.wal.sync()
+    }
+
+    // the WAL holds records of every column family, so they all move on to a fresh memtable with it
+    fn rotate_memtable_and_wal(&mut self) -> Result<()> {
+        // every file is created before anything is frozen, a failure leaves the memtables and
+        // the WAL as they were
+        let mut paths = BTreeMap::new();
+        let created = self
+            .families
+            .values()
+            .filter(|family| !family.memtable.is_empty())
+            .try_for_each(|family| {
+                let (_, tmp_path, final_path) =
+                    KVEngine::create_new_data_file(&self.data_directory)?;
+                paths.insert(family.id, (tmp_path, final_path));
+                Ok(())
+            })
+            .and_then(|()| WalWriter::new(&self.data_directory, self.options.sync));
+        let new_wal = match created {
+            Ok(wal) => wal,
+            Err(err) => {
+                for (tmp_path, _) in paths.values() {
+                    let _ = remove_file(tmp_path);
+                }
+                return Err(err.into());
+            }
+        };
+        let old_wal = std::mem::replace(&mut self.wal, new_wal);
+        self.rotations += 1;
+
+        let mut jobs = Vec::new();
+        let compactions = self.compaction_jobs();
+        for (family, compaction) in self.families.values_mut().zip(compactions) {
+            let Some((tmp_path, final_path)) = paths.remove(&family.id) else {
+                continue;
+            };
+            let frozen: Arc<dyn Memtable> = Arc::from(std::mem::replace(
+                &mut family.memtable,
+                family.options.memtable.new_memtable(),
+            ));
+            family
+                .immutable
+                .write()
+                .unwrap()
+                .push_front(Arc::clone(&frozen));
+            let job = FlushJob {
+                cf: family.id,
+                wal_id: self.rotations,
+                memtable: frozen,
+                immutable: Arc::clone(&family.immutable),
+                sstables: Arc::clone(&family.sstables),
+                tmp_path,
+                final_path,
+                table: self.options.table_options(family.options.compression),
+                vlog: Arc::clone(&self.vlog),
+                merge_operator: self.merge_operator.clone(),
+                snapshots: Arc::clone(&self.snapshots),
+                compaction,
+            };
+            jobs.push(job);
+        }
+        // every record in it went to a memtable, none of them had any
+        if jobs.is_empty() {
+            return old_wal.destruct();
+        }
+        // registered before any flush can finish and look for it
+        self.frozen_wals.lock().unwrap().push_back(FrozenWal {
+            id: self.rotations,
+            wal: old_wal,
+            unflushed: jobs.len(),
+        });
+
+        for job in jobs {
+            self.flushing_manager.background_flush_memtable(job)?;
+        }
+
+        Ok(())
+    }
+
+    // fn serialize_record(tstamp: u64, key: &[u8], value: &[u8]) -> Vec<u8> {
+    //     let crc32 = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+    //     let body: Vec<u8> = [
+    //         &tstamp.to_le_bytes()[..],
+    //         &(key.len() as u64).to_le_bytes(),
+    //         &(value.len() as u64).to_le_bytes(),
+    //         key,
+    //         value,
+    //     ]
+    //     .concat();
+    //     let checksum = crc32.checksum(&body);
+    //     let mut record = checksum.to_le_bytes().to_vec();
+    //     record.extend(body);
+    //     record
+    // }
+}
+
+// sequence number of the last operation of a write starting at seq
+fn last_seq_of(seq: u64, record: WalRecordType) -> u64 {
+    match record {
+        WalRecordType::Batch(batch) => seq + batch.len() as u64 - 1,
+        _ => seq,
+    }
+}
+
+// Handle for sharing one engine between threads behind an Arc. Readers only take the read side
+// of the lock, so they run next to each other and next to the WAL append and fsync of a write.
+// Writers queue on their own mutex to log, wait for the fsync they share without it and then
+// take the write side, in log order, just long enough to make their write visible.
+pub struct Db {
+    engine: RwLock<KVEngine>,
+    writer: Mutex<()>,
+    queue: Mutex<WriteQueue>,
+    published_cv: Condvar,
+    write_controller: Arc<WriteController>, // stalls writers without holding the engine lock
+}
+
+// Writes in the WAL that are not visible yet. A write is published once it is durable and every
+// write logged before it has been published, a write whose fsync failed is skipped.
+#[derive(Default)]
+struct WriteQueue {
+    logged: u64,    // sequence number of the last write handed to the WAL
+    published: u64, // every write up to here is visible or failed
+}
+
+impl Db {
+    pub fn open(dir: &Path, options: Options) -> Result<Db> {
+        let engine = KVEngine::open(dir, options)?;
+        Ok(Self {
+            write_controller: Arc::clone(&engine.flushing_manager.write_controller),
+            engine: RwLock::new(engine),
+            writer: Mutex::new(()),
+            queue: Mutex::new(WriteQueue::default()),
+            published_cv: Condvar::new(),
+        })
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, KVEngine> {
+        self.engine.read().unwrap()
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, KVEngine> {
+        self.engine.write().unwrap()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.read().get(key)
+    }
+
+    pub fn get_cf(&self, cf: ColumnFamilyId, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.read().get_cf(cf, key)
+    }
+
+    // the iterator owns what it reads, the lock is only held while it is set up
+    pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> ScanItems<'static> {
+        Box::new(self.read().scan::<&[u8], _>((lower, upper)))
+    }
+
+    pub fn scan_cf(
+        &self,
+        cf: ColumnFamilyId,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<ScanItems<'static>> {
+        Ok(Box::new(
+            self.read().scan_cf::<&[u8], _>(cf, (lower, upper))?,
+        ))
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.read().snapshot()
+    }
+
+    pub fn get_at(&self, snapshot: &Snapshot, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        snapshot.get(&self.read(), key)
+    }
+
+    pub fn get_cf_at(
+        &self,
+        snapshot: &Snapshot,
+        cf: ColumnFamilyId,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        snapshot.get_cf(&self.read(), cf, key)
+    }
+
+    pub fn scan_at(
+        &self,
+        snapshot: &Snapshot,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<ScanItems<'static>> {
+        Ok(Box::new(
+            snapshot.scan::<&[u8], _>(&self.read(), (lower, upper))?,
+        ))
+    }
+
+    pub fn scan_cf_at(
+        &self,
+        snapshot: &Snapshot,
+        cf: ColumnFamilyId,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<ScanItems<'static>> {
+        Ok(Box::new(snapshot.scan_cf::<&[u8], _>(
+            &self.read(),
+            cf,
+            (lower, upper),
+        )?))
+    }
+
+    // The iterator reads the memtables in place, so f runs under the read lock. Writes wait
+    // until it returns, writing from inside f deadlocks.
+    pub fn with_iter<T>(&self, f: impl FnOnce(&mut LsmIterator<'_>) -> Result<T>) -> Result<T> {
+        let engine = self.read();
+        f(&mut engine.iter())
+    }
+
+    pub fn with_iter_cf<T>(
+        &self,
+        cf: ColumnFamilyId,
+        f: impl FnOnce(&mut LsmIterator<'_>) -> Result<T>,
+    ) -> Result<T> {
+        let engine = self.read();
+        f(&mut engine.iter_cf(cf)?)
+    }
+
+    pub fn column_family(&self, name: &str) -> Option<ColumnFamilyId> {
+        self.read().column_family(name)
+    }
+
+    pub fn list_column_families(&self) -> Vec<String> {
+        self.read().list_column_families()
+    }
+
+    pub fn create_column_family(
+        &self,
+        name: &str,
+        options: ColumnFamilyOptions,
+    ) -> Result<ColumnFamilyId> {
+        let _writer = self.lock_writer();
+        self.write().create_column_family(name, options)
+    }
+
+    pub fn set_column_family_options(
+        &self,
+        cf: ColumnFamilyId,
+        options: ColumnFamilyOptions,
+    ) -> Result<()> {
+        let _writer = self.lock_writer();
+        self.write().set_column_family_options(cf, options)
+    }
+
+    pub fn drop_column_family(&self, name: &str) -> Result<()> {
+        let _writer = self.lock_writer();
+        self.write().drop_column_family(name)
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.write_record(DEFAULT_COLUMN_FAMILY, WalRecordType::Insertion(key, value))
+    }
+
+    pub fn put_cf(&self, cf: ColumnFamilyId, key: &[u8], value: &[u8]) -> Result<()> {
+        self.write_record(cf, WalRecordType::Insertion(key, value))
+    }
+
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        let record = WalRecordType::ExpiringInsertion(key, value, KVEngine::expires_at(ttl));
+        self.write_record(DEFAULT_COLUMN_FAMILY, record)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.write_record(DEFAULT_COLUMN_FAMILY, WalRecordType::Deletion(key))
+    }
+
+    pub fn delete_cf(&self, cf: ColumnFamilyId, key: &[u8]) -> Result<()> {
+        self.write_record(cf, WalRecordType::Deletion(key))
+    }
+
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        if start >= end {
+            return Ok(());
+        }
+        let record = WalRecordType::RangeDeletion(start, end);
+        self.write_record(DEFAULT_COLUMN_FAMILY, record)
+    }
+
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.write_record(DEFAULT_COLUMN_FAMILY, WalRecordType::Merge(key, operand))
+    }
+
+    pub fn write_batch(&self, batch: &WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.write_record(DEFAULT_COLUMN_FAMILY, WalRecordType::Batch(batch))
+    }
+
+    // conditional writes keep the writer lock from the check to the write and only check once
+    // every write logged before them is visible
+    pub fn put_if_absent(&self, key: &[u8], value: &[u8]) -> Result<bool> {
+        self.compare_and_swap(key, None, value)
+    }
+
+    pub fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: &[u8],
+    ) -> Result<bool> {
+        let record = WalRecordType::Insertion(key, new);
+        let logged = {
+            let _writer = self.lock_writer();
+            if self.read().get(key)?.as_deref() != expected {
+                return Ok(false);
+            }
+            self.write_locked(DEFAULT_COLUMN_FAMILY, record)?
+        };
+        self.finish_write(DEFAULT_COLUMN_FAMILY, record, logged)?;
+        Ok(true)
+    }
+
+    pub fn delete_if_equals(&self, key: &[u8], expected: &[u8]) -> Result<bool> {
+        let record = WalRecordType::Deletion(key);
+        let logged = {
+            let _writer = self.lock_writer();
+            if self.read().get(key)?.as_deref() != Some(expected) {
+                return Ok(false);
+            }
+            self.write_locked(DEFAULT_COLUMN_FAMILY, record)?
+        };
+        self.finish_write(DEFAULT_COLUMN_FAMILY, record, logged)?;
+        Ok(true)
+    }
+
+    pub fn set_merge_operator(&self, operator: Arc<dyn MergeOperator>) {
+        let _writer = self.lock_writer();
+        self.write().set_merge_operator(operator);
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.read().sync()
+    }
+
+    pub fn write_stall_stats(&self) -> WriteStallStats {
+        self.write_controller.stats()
+    }
+
+    pub fn block_cache_stats(&self) -> BlockCacheStats {
+        self.read().block_cache_stats()
+    }
+
+    // live values are written again, readers and writers wait until it is done
+    pub fn collect_value_log(&self) -> Result<u64> {
+        let _writer = self.lock_writer();
+        self.write().collect_value_log()
+    }
+
+    // readers and writers keep going while the tables are merged
+    pub fn compact(&self) -> Result<()> {
+        let jobs = self.read().compaction_jobs();
+        for job in jobs {
+            job.run()?;
+        }
+        Ok(())
+    }
+
+    fn write_record(&self, cf: ColumnFamilyId, record: WalRecordType) -> Result<()> {
+        let logged = {
+            let _writer = self.writer.lock().unwrap();
+            self.write_locked(cf, record)?
+        };
+        self.finish_write(cf, record, logged)
+    }
+
+    // the writer lock, once every write logged so far is visible
+    fn lock_writer(&self) -> MutexGuard<'_, ()> {
+        let writer = self.writer.lock().unwrap();
+        self.wait_published();
+        writer
+    }
+
+    // the caller holds the writer lock, so nothing gets logged while this waits
+    fn wait_published(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        while queue.published < queue.logged {
+            queue = self.published_cv.wait(queue).unwrap();
+        }
+    }
+
+    // The caller holds the writer lock. Logs the write under the next sequence number, it stays
+    // invisible until finish_write, which the caller runs after it lets go of the lock so the
+    // writers queued behind it share the fsync.
+    fn write_locked(&self, cf: ColumnFamilyId, record: WalRecordType) -> Result<(u64, Ticket)> {
+        if self.read().check_write(cf, record)? {
+            // the writes in flight belong to the memtables and WAL about to be frozen
+            self.wait_published();
+            self.write_controller.wait_for_flush();
+            self.write().rotate_memtable_and_wal()?;
+        }
+        self.write_controller.delay_write();
+        let mut queue = self.queue.lock().unwrap();
+        if queue.published == queue.logged {
+            // nothing in flight, catch up with writes made on the engine directly
+            queue.logged = max(queue.logged, self.read().last_seq);
+            queue.published = queue.logged;
+        }
+        let seq = queue.logged + 1;
+        let ticket = self.read().log_write(seq, cf, record)?;
+        queue.logged = last_seq_of(seq, record);
+        Ok((seq, ticket))
+    }
+
+    // Waits for the write to be durable, then publishes it right after the writes logged before
+    // it. A failed fsync publishes nothing, the caller gets the error and readers never see it.
+    fn finish_write(
+        &self,
+        cf: ColumnFamilyId,
+        record: WalRecordType,
+        (seq, ticket): (u64, Ticket),
+    ) -> Result<()> {
+        let durable = ticket.wait_durable();
+        let mut queue = self.queue.lock().unwrap();
+        while queue.published + 1 < seq {
+            queue = self.published_cv.wait(queue).unwrap();
+        }
+        let res = durable.and_then(|()| {
+            // readers keep going while a skiplist takes the write
+            if self.read().apply_shared(seq, cf, record)? {
+                self.write().publish(seq, record);
+                Ok(())
+            } else {
+                self.write().apply_write(seq, cf, record)
+            }
+        });
+        queue.published = last_seq_of(seq, record);
+        self.published_cv.notify_all();
+        res
+    }
+}
+
+impl StorageEngine for KVEngine {
+    fn open(dir: &Path) -> Result<Self> {
+        KVEngine::open(dir, Options::default())
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_cf(DEFAULT_COLUMN_FAMILY, key)
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        KVEngine::put(self, key, value)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        KVEngine::delete(self, key)
+    }
+
+    fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<ScanItems<'_>> {
+        Ok(Box::new(KVEngine::scan::<&[u8], _>(self, (lower, upper))))
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        KVEngine::sync(self)
+    }
+
+    // writes are logged before they are acknowledged, nothing is left to flush
+    fn close(&mut self) -> Result<()> {
+        KVEngine::sync(self)
+    }
+}
+
+/*Notes:
+ // footer is :  | min key | max key | sizeof(sparse_index) | sparse_index_offset| sizeof(bloom_filter) | bloom filter_offset | sizeof(minkey) | minkey offset | sizeof(maxkey) | maxkey offset | 64 bytes(not including min and max key)
+DataBlocks:  [ tstamp(8) | ksz(8) | value_sz(8) | key | value  tstamp(8) | ksz(8) | value_sz(8) | key | value ... crc(4)]
+SSTable: Datablock1 | DataBlock2 ... Datablock N | Footer
+Bloom filter: k-hash bit array per SSTable to skip files on negative lookups. Uses bloom_bits_per_key bits per key (10 by default). Built during flush of AVL.
+*/
+// SparseIndex => [ firskey:[offset, datablock_length] ]
+// wal record looks like: ksz, vsz, k, v, crc(4 bytes)
+// When you read a data block in the sparse index, remember to account for the 4 crc bytes yourself, they are not accounted forin the length
+/*
+TODOS:
+Build SSTables on open to have the metadata in memory.
+Need to rewrite the delete function. Right now I am removing the Node from the tree but this can cause a bug:
+if you delete a key thats in the memtable, you remove the node, but what if its in one of the SStables?
+since the memtable hasnt been flushed yet, you will check memtable -> not found then check ss table and return the value even though
+it was deleted.
+So instead of removing the node, just add a tombstone on deletes. this means that the tree will just grow and now need to rebalance on deletes.
+On delete: just do insert(key, node) and have node.deleted true.
+
+On KVEngine get() you check if a kv is in the memtable, if yes, check the deleted flag.
+[ tstamp(8) | ksz(8) | value_sz(8) | deletedflag(1) | key | value ]
+deletedflag 1 = deleted, 0 = alive
+
+
+ For WAL records, we have deletion and insertion types so far. Will use one byte to define type. 00000100(4) = INSERTION. 00000010(2) = DELETION.
+ serialized should look like this: TYPE | RECORD
+ RECORD can be tstamp | ksz | key |crc (4 bytes) OR it can be  | tstamp | ksz |vsz | key | value | crc(4 bytes)
+ POTENTIAL PROBLEM: should I include sequence numbers for each k/v pair ?
+ PROBLEM/UPDATE: make Bufreaders with capacity instead
+ PROBLEM/OPT: metadata footer can
+
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compact::SizeTieredCompaction;
+    use crate::compression::CompressionCodec;
+    use crate::wal::{RecoveryMode, SyncConfig};
+    use std::sync::atomic::AtomicUsize;
+    use tempfile::tempdir;
+
+    fn open_engine(dir: &Path) -> Result<KVEngine> {
+        KVEngine::open(dir, Options::default())
+    }
+
+    fn collect(iter: ScanIterator) -> Result<Vec<(String, String)>> {
+        iter.map(|kv| {
+            kv.map(|(k, v)| (String::from_utf8(k).unwrap(), String::from_utf8(v).unwrap()))
+        })
+        .collect()
+    }
+
+    #[test]
+    fn scan_merges_memtable_and_sstables() -> Result<()> {
+        let dir = tempdir()?;
+        {
+            let mut db = open_engine(dir.path())?;
+            for key in ["a", "b", "c", "d", "e"] {
+                db.put(key.as_bytes(), b"old")?;
+            }
+            db.delete(b"c")?;
+        }
+
+        // reopening replays the WAL into an SSTable
+        let mut db = open_engine(dir.path())?;
+        assert_eq!(db.default_family().sstables.read().unwrap().len(), 1);
+        db.put(b"b", b"new")?;
+        db.put(b"bb", b"new")?;
+        db.delete(b"d")?;
+
+        assert_eq!(
+            collect(db.scan(b"b".as_slice()..b"e".as_slice()))?,
+            vec![
+                ("b".to_string(), "new".to_string()),
+                ("bb".to_string(), "new".to_string()),
+            ]
+        );
+        assert_eq!(
+            collect(db.scan::<&[u8], _>((Bound::Excluded(b"a".as_slice()), Bound::Unbounded)))?,
+            vec![
+                ("b".to_string(), "new".to_string()),
+                ("bb".to_string(), "new".to_string()),
+                ("e".to_string(), "old".to_string()),
+            ]
+        );
+        assert_eq!(collect(db.scan::<&[u8], _>(..))?.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn iterator_walks_both_directions_across_blocks() -> Result<()> {
+        let dir = tempdir()?;
+        {
+            let mut db = open_engine(dir.path())?;
+            for i in 0..300 {
+                let value = format!("old-{:0>40}", i);
+                db.put(format!("k{:03}", i).as_bytes(), value.as_bytes())?;
+            }
+            db.delete(b"k150")?;
+        }
+
+        let mut db = open_engine(dir.path())?;
+        // several data blocks, so prev has to cross block boundaries
+        assert!(
+            db.default_family().sstables.read().unwrap()[0]
+                .sparse_index
+                .len()
+                > 1
+        );
+        db.put(b"k100", b"new")?;
+        db.delete(b"k200")?;
+
+        let mut iter = db.iter();
+        iter.seek_for_prev(b"k150")?;
+        assert_eq!(iter.key(), Some(b"k149".as_slice()));
+        iter.prev()?;
+        assert_eq!(iter.key(), Some(b"k148".as_slice()));
+        iter.next()?;
+        iter.next()?;
+        assert_eq!(iter.key(), Some(b"k151".as_slice()));
+
+        iter.seek(b"k199x")?;
+        assert_eq!(iter.key(), Some(b"k201".as_slice()));
+        iter.prev()?;
+        assert_eq!(iter.key(), Some(b"k199".as_slice()));
+
+        iter.seek_for_prev(b"k100")?;
+        assert_eq!(iter.value(), Some(b"new".as_slice()));
+
+        iter.seek_to_first()?;
+        assert_eq!(iter.key(), Some(b"k000".as_slice()));
+        iter.prev()?;
+        assert!(!iter.valid());
+
+        iter.seek_to_last()?;
+        let mut seen = 0;
+        let mut last_key = b"k999".to_vec();
+        while let Some(key) = iter.key() {
+            assert!(key < last_key.as_slice());
+            last_key = key.to_vec();
+            seen += 1;
+            iter.prev()?;
+        }
+        assert_eq!(seen, 298);
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_ignores_later_writes() -> Result<()> {
+        let dir = tempdir()?;
+        {
+            let mut db = open_engine(dir.path())?;
+            db.put(b"a", b"1")?;
+            db.put(b"b", b"1")?;
+        }
+
+        // a and b live in an SSTable, the later writes in the memtable
+        let mut db = open_engine(dir.path())?;
+        db.put(b"a", b"2")?;
+        let snapshot = db.snapshot();
+        db.put(b"a", b"3")?;
+        db.delete(b"b")?;
+        db.put(b"c", b"3")?;
+
+        assert_eq!(snapshot.seq(), 3);
+        assert_eq!(snapshot.get(&db, b"a")?, Some(b"2".to_vec()));
+        assert_eq!(snapshot.get(&db, b"b")?, Some(b"1".to_vec()));
+        assert_eq!(snapshot.get(&db, b"c")?, None);
+        assert_eq!(
+            collect(snapshot.scan::<&[u8], _>(&db, ..)?)?,
+            vec![
+                ("a".to_string(), "2".to_string()),
+                ("b".to_string(), "1".to_string()),
+            ]
+        );
+
+        assert_eq!(db.get(b"a")?, Some(b"3".to_vec()));
+        assert_eq!(db.get(b"b")?, None);
+        let other_dir = tempdir()?;
+        let other = open_engine(other_dir.path())?;
+        assert!(matches!(
+            snapshot.get(&other, b"a"),
+            Err(DbError::ForeignSnapshot)
+        ));
+        let mut iter = db.iter();
+        iter.seek_to_last()?;
+        assert_eq!(iter.key(), Some(b"c".as_slice()));
+        iter.prev()?;
+        assert_eq!(iter.value(), Some(b"3".as_slice()));
+        iter.prev()?;
+        assert!(!iter.valid());
+
+        drop(snapshot);
+        assert!(db.snapshots.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    // rotates and waits until the frozen memtables are installed as tables
+    fn flush_and_wait(engine: &mut KVEngine) -> Result<()> {
+        engine.rotate_memtable_and_wal()?;
+        while engine.write_stall_stats().pending_memtables > 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn flushes_start_compactions_in_the_background() -> Result<()> {
+        let dir = tempdir()?;
+        let mut engine = open_engine(dir.path())?;
+        for round in 0..4 {
+            for i in 0..50 {
+                engine.put(
+                    format!("key{:02}", i).as_bytes(),
+                    format!("v{round}").as_bytes(),
+                )?;
+            }
+            flush_and_wait(&mut engine)?;
+        }
+
+        // four L0 tables make the leveled strategy merge them into L1
+        let started = std::time::Instant::now();
+        while engine.default_family().sstables.read().unwrap()[0].level == 0 {
+            assert!(started.elapsed() < Duration::from_secs(30));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(engine.default_family().sstables.read().unwrap().len(), 1);
+        drop(engine);
+
+        let engine = open_engine(dir.path())?;
+        assert_eq!(engine.get(b"key07")?, Some(b"v3".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn flush_keeps_only_versions_a_snapshot_can_read() -> Result<()> {
+        // overwrites nobody can read any more are dropped instead of piling up in one block
+        let dir = tempdir()?;
+        let mut engine = open_engine(dir.path())?;
+        for i in 0..300 {
+            engine.put(b"hot", &[(i % 250) as u8; 4000])?;
+        }
+        flush_and_wait(&mut engine)?;
+        assert_eq!(engine.get(b"hot")?, Some(vec![49; 4000]));
+
+        // versions pinned by snapshots run on over many small blocks
+        let dir = tempdir()?;
+        let options = Options::default()
+            .block_size(1024)
+            .max_key_size(64)
+            .max_value_size(4096)
+            .max_block_size(8192);
+        let mut engine = KVEngine::open(dir.path(), options)?;
+        engine.put(b"cold", b"before")?;
+        let mut snapshots = Vec::new();
+        for i in 0..20 {
+            engine.put(b"hot", &[i; 3000])?;
+            snapshots.push(engine.snapshot());
+        }
+        engine.put(b"warm", b"after")?;
+        flush_and_wait(&mut engine)?;
+
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            assert_eq!(snapshot.get(&engine, b"hot")?, Some(vec![i as u8; 3000]));
+        }
+        assert_eq!(engine.get(b"hot")?, Some(vec![19; 3000]));
+        let mut iter = engine.iter();
+        iter.seek_for_prev(b"hot")?;
+        assert_eq!(iter.value(), Some(&[19; 3000][..]));
+        iter.prev()?;
+        assert_eq!(iter.key(), Some(&b"cold"[..]));
+        iter.seek(b"hot")?;
+        assert_eq!(iter.value(), Some(&[19; 3000][..]));
+        iter.next()?;
+        assert_eq!(iter.key(), Some(&b"warm"[..]));
+        Ok(())
+    }
+
+    #[test]
+    fn write_batch_is_replayed_all_or_nothing() -> Result<()> {
+        let dir = tempdir()?;
+        let wal_path = {
+            let mut db = open_engine(dir.path())?;
+            db.put(b"x", b"1")?;
+            let mut batch = WriteBatch::new();
+            batch.put(b"a", b"1");
+            batch.put(b"b", b"1");
+            batch.delete(b"x");
+            db.write(batch)?;
+
+            assert_eq!(db.get(b"a")?, Some(b"1".to_vec()));
+            assert_eq!(db.get(b"x")?, None);
+            assert_eq!(db.last_seq, 4);
+            db.wal.path().to_path_buf()
+        };
+
+        let mut memtables =
+            BTreeMap::from([(DEFAULT_COLUMN_FAMILY, MemtableKind::Avl.new_memtable())]);
+        FlushingManager::new(Arc::default()).build_memtables_from_wal(
+            &mut memtables,
+            &wal_path,
+            &Options::default().recovery(RecoveryMode::Fail),
+        )?;
+        let memtable = &memtables[&DEFAULT_COLUMN_FAMILY];
+        assert!(memtable.get(b"b", u64::MAX).is_some());
+        assert!(memtable.get(b"x", u64::MAX).unwrap().deleted);
+
+        // flip a byte inside the batch body, none of its operations may come back
+        let mut bytes = fs::read(&wal_path)?;
+        let len = bytes.len();
+        bytes[len - 10] ^= 0xFF;
+        fs::write(&wal_path, bytes)?;
+
+        let mut memtables =
+            BTreeMap::from([(DEFAULT_COLUMN_FAMILY, MemtableKind::Avl.new_memtable())]);
+        let replayed = FlushingManager::new(Arc::default()).build_memtables_from_wal(
+            &mut memtables,
+            &wal_path,
+            &Options::default().recovery(RecoveryMode::Fail),
+        );
+        assert!(matches!(replayed, Err(DbError::DataCorrupted(_))));
+        assert!(
+            memtables[&DEFAULT_COLUMN_FAMILY]
+                .get(b"a", u64::MAX)
+                .is_none()
+        );
+
+        // the batch is the last record, so it reads as a torn tail and is cut off
+        let mut memtables =
+            BTreeMap::from([(DEFAULT_COLUMN_FAMILY, MemtableKind::Avl.new_memtable())]);
+        FlushingManager::new(Arc::default()).build_memtables_from_wal(
+            &mut memtables,
+            &wal_path,
+            &Options::default().recovery(RecoveryMode::TruncateTail),
+        )?;
+        let memtable = &memtables[&DEFAULT_COLUMN_FAMILY];
+        assert!(!memtable.get(b"x", u64::MAX).unwrap().deleted);
+        assert!(memtable.get(b"a", u64::MAX).is_none());
+        Ok(())
+    }
 
-            let ext = match path.extension().and_then(|x| x.to_str()) {
-                Some(e) => e,
-                _ => continue,
-            };
-            if ext == "sst" {
-                let ss_table = SSTable::load(&path);
-                sstables.push(ss_table);
-            } else if ext == "wal" {
-                // flush old wals to disk
-                // TODO
-                let (file, tmp_path, final_path) =
-                    KVEngine::create_new_data_file(&self_instance.data_directory)?;
-                // wal populates this and we flush it to disk as an .sst
-                let _ = self_instance.flushing_manager.retrieve_wal_records(
-                    &path,
-                    &tmp_path,
-                    &final_path,
-                );
-            }
+    #[test]
+    fn open_rebuilds_tables_from_manifest_and_drops_orphans() -> Result<()> {
+        let dir = tempdir()?;
+        {
+            let mut db = open_engine(dir.path())?;
+            db.put(b"a", b"1")?;
+            db.put(b"b", b"2")?;
         }
+        let (table_path, last_seq) = {
+            let db = open_engine(dir.path())?;
+            let tables = db.default_family().sstables.read().unwrap();
+            (tables[0].file_path.clone(), db.last_seq)
+        };
 
-        sstables.sort_by_key(|p| p.id);
-
-        // if let Ok(wal_m) = wal_path_metadata
-        //     && wal_m.len() > 0
-        // {
-        //     self_instance.wal.sync_wal()?;
-        // }
+        // a table the manifest never heard of and a flush that died before its rename
+        let orphan = dir.path().join("1.sst");
+        let leftover = dir.path().join("2.sst.tmp");
+        fs::copy(&table_path, &orphan)?;
+        fs::write(&leftover, b"partial")?;
+
+        let db = open_engine(dir.path())?;
+        assert!(!orphan.exists());
+        assert!(!leftover.exists());
+        assert_eq!(db.default_family().sstables.read().unwrap().len(), 1);
+        assert_eq!(db.last_seq, last_seq);
+        assert_eq!(
+            db.get_at(DEFAULT_COLUMN_FAMILY, b"b", db.last_seq)?,
+            Some(b"2".to_vec())
+        );
+        drop(db);
 
-        self_instance.sstables = Some(Arc::new(RwLock::new(sstables)));
-        Ok(self_instance)
+        // losing a logged table is an error instead of silently dropping its data
+        remove_file(&table_path)?;
+        assert!(matches!(
+            open_engine(dir.path()),
+            Err(DbError::FileError(_, path)) if path == table_path
+        ));
+        Ok(())
     }
 
-    fn should_search_sstable_file(key: &[u8], sstable: &SSTable) -> bool {
-        // checks the metadata of sstable and tells us whether we should look for the kv in the sstable
-        if key > sstable.max_key.as_slice() || key < sstable.min_key.as_slice() {
-            return false;
+    #[test]
+    fn block_cache_serves_repeated_reads() -> Result<()> {
+        let dir = tempdir()?;
+        {
+            let mut db = open_engine(dir.path())?;
+            for i in 0..300 {
+                db.put(format!("k{:03}", i).as_bytes(), &[b'v'; 64])?;
+            }
         }
-        let bf_size = sstable.bloom_filter.bits.len();
-        let bf_bit_positions = get_hashed_key_positions(key, bf_size);
-        bf_bit_positions
-            .iter()
-            .all(|&pos| *sstable.bloom_filter.bits.get(pos).unwrap() != 0)
+
+        let db = open_engine(dir.path())?;
+        assert_eq!(
+            db.get_at(DEFAULT_COLUMN_FAMILY, b"k010", db.last_seq)?,
+            Some(vec![b'v'; 64])
+        );
+        let first = db.block_cache_stats();
+        assert_eq!((first.hits, first.misses), (0, 1));
+        assert!(first.usage > 0);
+
+        // same block for a neighbouring key, then again through a scan
+        assert_eq!(
+            db.get_at(DEFAULT_COLUMN_FAMILY, b"k011", db.last_seq)?,
+            Some(vec![b'v'; 64])
+        );
+        assert_eq!(
+            collect(db.scan(b"k010".as_slice()..b"k012".as_slice()))?.len(),
+            2
+        );
+        let stats = db.block_cache_stats();
+        assert_eq!((stats.hits, stats.misses), (2, 1));
+        assert_eq!(stats.usage, first.usage);
+        Ok(())
     }
 
-    fn search_kv_in_sstable(sstable: &SSTable, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        let Some((offset, data_len)) = sstable.binary_search_sparse_index(key) else {
-            return Ok(None);
+    #[test]
+    fn tables_with_different_codecs_are_read_side_by_side() -> Result<()> {
+        let dir = tempdir()?;
+        let open_with = |compression| {
+            let options = Options::default()
+                .sync(SyncConfig::None)
+                .compression(compression);
+            KVEngine::open(dir.path(), options)
         };
+        let json = |i: usize| format!(r#"{{"id":{},"status":"active","tags":["a","b"]}}"#, i);
 
-        if data_len > MAX_BLOCK_SIZE {
-            return Err(DbError::DataCorrupted(DataCorruptedErr {
-                offset,
-                file_path: sstable.file_path.clone(),
-                reason: CorruptionType::BufferExceedsMaxLength {
-                    size: data_len,
-                    max_size: MAX_BLOCK_SIZE,
-                },
-            }));
+        // every open flushes the previous WAL with its own codec
+        for prefix in ["a", "b"] {
+            let mut db = open_with(CompressionType::None)?;
+            for i in 0..200 {
+                db.put(format!("{}{:03}", prefix, i).as_bytes(), json(i).as_bytes())?;
+            }
         }
-        let mut data_buffer = vec![0u8; data_len as usize];
+        let db = open_with(CompressionType::Lz4)?;
+        let sizes: Vec<u64> = {
+            let tables = db.default_family().sstables.read().unwrap();
+            let mut tables: Vec<&Arc<SSTable>> = tables.iter().collect();
+            tables.sort_by_key(|t| t.min_key.clone());
+            tables.iter().map(|t| t.file_size).collect()
+        };
+        assert_eq!(sizes.len(), 2);
+        assert!(sizes[1] * 2 < sizes[0]);
 
-        let mut crc = [0u8; 4];
+        assert_eq!(
+            db.get_at(DEFAULT_COLUMN_FAMILY, b"a007", db.last_seq)?,
+            Some(json(7).into_bytes())
+        );
+        assert_eq!(
+            db.get_at(DEFAULT_COLUMN_FAMILY, b"b199", db.last_seq)?,
+            Some(json(199).into_bytes())
+        );
+        assert_eq!(collect(db.scan::<&[u8], _>(..))?.len(), 400);
+        Ok(())
+    }
 
-        // PROBLEM: Lets switch to read_exact_at here since buffer isnt reused
-        let mut reader = BufReader::new(&sstable.file);
-        reader.seek(SeekFrom::Start(offset))?;
+    // lz4 under another tag, counting the blocks it was handed
+    struct CountingCodec(Arc<AtomicUsize>);
 
-        reader.read_exact(&mut data_buffer)?;
+    impl CompressionCodec for CountingCodec {
+        fn tag(&self) -> u8 {
+            7
+        }
 
-        //
-        // we read CRC here because data_len above doesnt take into account the 4 bytes for crc
-        reader.read_exact(&mut crc)?;
-        let crc_from_buff = u32::from_le_bytes(crc);
+        fn compress(&self, data: &[u8]) -> Vec<u8> {
+            self.0.fetch_add(1, AtomicOrdering::SeqCst);
+            lz4_flex::compress_prepend_size(data)
+        }
 
-        let fresh_crc = compute_crc_data_block(&data_buffer);
+        fn decompress(
+            &self,
+            data: &[u8],
+            _max_len: usize,
+        ) -> std::result::Result<Vec<u8>, CorruptionType> {
+            lz4_flex::decompress_size_prepended(data)
+                .map_err(|e| CorruptionType::Other(e.to_string()))
+        }
+    }
 
-        if fresh_crc != crc_from_buff {
-            return Err(DbError::DataCorrupted(DataCorruptedErr {
-                offset,
-                file_path: sstable.file_path.clone(),
-                reason: CorruptionType::CrcMismatch {
-                    expected: crc_from_buff,
-                    found: fresh_crc,
-                },
-            }));
+    #[test]
+    fn a_registered_codec_writes_and_reads_blocks() -> Result<()> {
+        let dir = tempdir()?;
+        let compressed = Arc::new(AtomicUsize::new(0));
+        let open_with_codec = || {
+            let options = Options::default()
+                .sync(SyncConfig::None)
+                .codec(Arc::new(CountingCodec(Arc::clone(&compressed))))
+                .compression(CompressionType::Custom(7));
+            KVEngine::open(dir.path(), options)
+        };
+        let json = |i: usize| format!(r#"{{"id":{},"status":"active","tags":["a","b"]}}"#, i);
+
+        {
+            let mut db = open_with_codec()?;
+            for i in 0..200 {
+                db.put(format!("{:03}", i).as_bytes(), json(i).as_bytes())?;
+            }
         }
+        // the WAL is flushed with the custom codec on the next open
+        let db = open_with_codec()?;
+        assert!(compressed.load(AtomicOrdering::SeqCst) > 0);
+        assert_eq!(db.get(b"042")?, Some(json(42).into_bytes()));
+        assert_eq!(collect(db.scan::<&[u8], _>(..))?.len(), 200);
+        drop(db);
+
+        // without the codec the blocks cannot be read, and the codec cannot be picked
+        let options = Options::default().sync(SyncConfig::None);
+        assert!(
+            options
+                .clone()
+                .compression(CompressionType::Custom(7))
+                .validate()
+                .is_err()
+        );
+        assert!(
+            options
+                .clone()
+                .codec(Arc::new(CountingCodec(Arc::clone(&compressed))))
+                .codec(Arc::new(CountingCodec(Arc::clone(&compressed))))
+                .validate()
+                .is_err()
+        );
+        let db = KVEngine::open(dir.path(), options)?;
+        assert!(db.get(b"042").is_err());
+        Ok(())
+    }
 
-        let mut pos = 0;
-        while pos < data_buffer.len() {
-            if pos + 25 > data_buffer.len() {
-                return Err(DbError::DataCorrupted(DataCorruptedErr {
-                    offset: offset + pos as u64,
-                    file_path: sstable.file_path.clone(),
-                    reason: CorruptionType::Other(format!(
-                        "truncated record header at buffer position {} (buffer len {})",
-                        pos,
-                        data_buffer.len(),
-                    )),
-                }));
-            }
-
-            // its actually: [ tstamp(8) | ksz(8) | value_sz(8) |tombstone| key | value |  ]
-            let ksz =
-                u64::from_le_bytes(data_buffer[pos + 8..pos + 16].try_into().unwrap()) as usize;
-            let vsz =
-                u64::from_le_bytes(data_buffer[pos + 16..pos + 24].try_into().unwrap()) as usize;
-            let deleted = &data_buffer[pos + 24..pos + 25][0];
-            // [ tstamp(8) | ksz(8) | value_sz(8) | deletedflag(1) | key | value ]
-            // check ksz and vsz doesnt overflow
-            let key_start = pos + 25;
-
-            let val_end = key_start
-                .checked_add(ksz)
-                .and_then(|v| v.checked_add(vsz))
-                .ok_or_else(|| {
-                    DbError::DataCorrupted(DataCorruptedErr {
-                        offset: offset + pos as u64,
-                        file_path: sstable.file_path.clone(),
-                        reason: CorruptionType::Other(format!(
-                            "record size overflow: ksz={ksz}, vsz={vsz}"
-                        )),
-                    })
-                })?;
-
-            if val_end > data_buffer.len() {
-                return Err(DbError::DataCorrupted(DataCorruptedErr {
-                    offset: offset + pos as u64,
-                    file_path: sstable.file_path.clone(),
-                    reason: CorruptionType::LengthMismatch {
-                        expected: val_end,
-                        found: data_buffer.len(),
-                    },
-                }));
+    #[test]
+    fn prefix_compressed_blocks_read_like_plain_ones() -> Result<()> {
+        let dir = tempdir()?;
+        let cache = Arc::new(BlockCache::new(BLOCK_CACHE_CAPACITY));
+        let mut entries = Vec::new();
+        for i in 0..100u64 {
+            let key = format!("tenant/123/user/{:04}", i).into_bytes();
+            // some keys get a second version, a few of them end up across restart points
+            if i % 7 == 0 {
+                entries.push(KvEntry {
+                    key: key.clone(),
+                    value: b"new".to_vec(),
+                    seq: 200 + i,
+                    expires_at: 0,
+                    deleted: false,
+                    value_ptr: false,
+                    merge_operand: false,
+                });
             }
+            entries.push(KvEntry {
+                key,
+                value: format!("v{}", i).into_bytes(),
+                seq: i + 1,
+                expires_at: 0,
+                deleted: false,
+                value_ptr: false,
+                merge_operand: false,
+            });
+        }
 
-            let val_start = key_start + ksz; // if val_end is safe then this is safe(no overflow)
-            let curr_key = &data_buffer[key_start..val_start];
-            let value: &[u8] = &data_buffer[val_start..val_end];
+        let mut tables = Vec::new();
+        for format in [BlockFormat::Plain, BlockFormat::PrefixCompressed] {
+            let (_, tmp_path, final_path) = KVEngine::create_new_data_file(dir.path())?;
+            let mut writer =
+                SsTableWriter::new(&tmp_path, &final_path, 0, CompressionType::None.into())?;
+            writer.block_format = format;
+            for entry in &entries {
+                writer.add(entry)?;
+            }
+            writer.finish()?;
+            tables.push(Arc::new(SSTable::load(
+                &final_path,
+                &CompressionType::None.into(),
+            )?));
+        }
+        assert!(tables[1].file_size < tables[0].file_size);
 
-            match curr_key.cmp(key) {
-                Ordering::Less => {
-                    pos = val_end;
-                    continue;
-                }
-                Ordering::Equal => {
-                    if *deleted == 1 {
-                        return Ok(None);
-                    }
-                    return Ok(Some(value.to_vec()));
+        for table in &tables {
+            assert_eq!(table.iter().collect::<Result<Vec<_>>>()?, entries);
+            let lookup = |key: &str, read_seq| {
+                KVEngine::search_kv_in_sstable(table, &cache, key.as_bytes(), read_seq)
+                    .map(|e| e.map(|e| e.value))
+            };
+            assert_eq!(
+                lookup("tenant/123/user/0014", u64::MAX)?,
+                Some(b"new".to_vec())
+            );
+            assert_eq!(lookup("tenant/123/user/0014", 100)?, Some(b"v14".to_vec()));
+            assert_eq!(lookup("tenant/123/user/0099", 100)?, Some(b"v99".to_vec()));
+            assert_eq!(lookup("tenant/123/user/0050x", u64::MAX)?, None);
+
+            let mut cursor = SsTableCursor::new(Arc::clone(table), Arc::clone(&cache), 100);
+            cursor.seek(b"tenant/123/user/0033x")?;
+            assert_eq!(cursor.entry().unwrap().key, b"tenant/123/user/0034");
+            cursor.seek_for_prev(b"tenant/123/user/0049")?;
+            assert_eq!(cursor.entry().unwrap().value, b"v49");
+            cursor.prev()?;
+            assert_eq!(cursor.entry().unwrap().value, b"v48");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn large_values_go_through_the_value_log() -> Result<()> {
+        let dir = tempdir()?;
+        let big = |c: u8| vec![c; 2 * VALUE_LOG_THRESHOLD as usize];
+        let vlog_files = || -> Result<Vec<PathBuf>> {
+            let mut files = Vec::new();
+            for entry in fs::read_dir(dir.path())? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|x| x == "vlog") {
+                    files.push(path);
                 }
-                Ordering::Greater => break,
             }
+            Ok(files)
+        };
+        {
+            let mut db = open_engine(dir.path())?;
+            for (key, c) in [("a", b'a'), ("b", b'b'), ("c", b'c')] {
+                db.put(key.as_bytes(), &big(c))?;
+            }
+            db.put(b"small", b"inline")?;
         }
-        Ok(None)
+
+        let first_vlog;
+        {
+            let mut db = open_engine(dir.path())?;
+            // the replayed WAL moved the three large values out of the table
+            first_vlog = vlog_files()?;
+            assert_eq!(first_vlog.len(), 1);
+            assert_eq!(db.get(b"b")?, Some(big(b'b')));
+            assert_eq!(db.get(b"small")?, Some(b"inline".to_vec()));
+            let scanned: Vec<_> = db.scan::<&[u8], _>(..).collect::<Result<_>>()?;
+            assert_eq!(scanned[2], (b"c".to_vec(), big(b'c')));
+            let mut iter = db.iter();
+            iter.seek(b"a")?;
+            assert_eq!(iter.value(), Some(big(b'a').as_slice()));
+            drop(iter);
+
+            db.put(b"a", &big(b'A'))?;
+            db.delete(b"b")?;
+        }
+
+        // only c is still live in the first file, GC moves it and drops the file
+        let mut db = open_engine(dir.path())?;
+        assert!(db.collect_value_log()? > 0);
+        assert!(!first_vlog[0].exists());
+        assert_eq!(db.get(b"a")?, Some(big(b'A')));
+        assert_eq!(db.get(b"b")?, None);
+        assert_eq!(db.get(b"c")?, Some(big(b'c')));
+        drop(db);
+
+        let db = open_engine(dir.path())?;
+        assert_eq!(db.get(b"c")?, Some(big(b'c')));
+        Ok(())
     }
-    fn search_for_kv_in_sstables(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        if let Some(sstables) = &self.sstables {
-            // Lock here is held for the entirety of the loop. Ok for now, mostly reads, rare writes
-            for element in sstables.read().unwrap().iter() {
-                match Self::should_search_sstable_file(key, element) {
-                    true => {
-                        if let Some(sstable) = Self::search_kv_in_sstable(element, key)? {
-                            return Ok(Some(sstable));
-                        }
-                    }
-                    false => continue,
-                }
+
+    #[test]
+    fn delete_range_hides_keys_until_rewritten() -> Result<()> {
+        let dir = tempdir()?;
+        let live = |db: &KVEngine| -> Result<Vec<String>> {
+            Ok(collect(db.scan::<&[u8], _>(..))?
+                .into_iter()
+                .map(|(k, _)| k)
+                .collect())
+        };
+        {
+            let mut db = open_engine(dir.path())?;
+            for key in ["tenant/a/1", "tenant/a/2", "tenant/a/3", "tenant/b/1"] {
+                db.put(key.as_bytes(), b"v")?;
             }
         }
-        Ok(None)
+        {
+            // the puts sit in a table by now, the tombstone in the memtable
+            let mut db = open_engine(dir.path())?;
+            db.delete_range(b"tenant/a/", b"tenant/a0")?;
+            db.put(b"tenant/a/2", b"again")?;
+            assert_eq!(db.get(b"tenant/a/1")?, None);
+            assert_eq!(db.get(b"tenant/a/2")?, Some(b"again".to_vec()));
+            assert_eq!(live(&db)?, ["tenant/a/2", "tenant/b/1"]);
+            let mut iter = db.iter();
+            iter.seek_to_first()?;
+            assert_eq!(iter.key(), Some(b"tenant/a/2".as_slice()));
+        }
+
+        // replayed from the WAL into the range tombstone block of a table
+        let db = open_engine(dir.path())?;
+        let tables = Arc::clone(&db.default_family().sstables);
+        assert_eq!(tables.read().unwrap()[0].range_tombstones.len(), 1);
+        assert_eq!(db.get(b"tenant/a/3")?, None);
+        assert_eq!(live(&db)?, ["tenant/a/2", "tenant/b/1"]);
+        Ok(())
     }
 
-    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        let flushing = self.flushing_memtable.as_ref().and_then(|x| x.upgrade());
+    #[test]
+    fn ttl_values_expire() -> Result<()> {
+        let dir = tempdir()?;
+        {
+            let mut db = open_engine(dir.path())?;
+            db.put(b"session", b"old")?;
+            db.put_with_ttl(b"session", b"new", Duration::from_millis(200))?;
+            db.put_with_ttl(b"user", b"alice", Duration::from_secs(3600))?;
+            assert_eq!(db.get(b"session")?, Some(b"new".to_vec()));
+        }
+
+        // replayed into a table, the expiry comes along
+        let db = open_engine(dir.path())?;
+        std::thread::sleep(Duration::from_millis(250));
+        // the expired version hides the older one too
+        assert_eq!(db.get(b"session")?, None);
+        assert_eq!(
+            collect(db.scan::<&[u8], _>(..))?,
+            [("user".to_string(), "alice".to_string())]
+        );
+        let mut iter = db.iter();
+        iter.seek_to_first()?;
+        assert_eq!(iter.key(), Some(b"user".as_slice()));
+        Ok(())
+    }
 
-        let val = flushing
-            .as_ref()
-            .and_then(|x| x.get(key))
-            .or_else(|| self.memtable.get(key));
+    // joins operands with commas
+    struct Append;
 
-        if let Some(c) = val {
-            Ok(Some(c.to_vec()))
-        } else {
-            match self.search_for_kv_in_sstables(key)? {
-                Some(v) => Ok(Some(v)),
-                _ => Ok(None),
+    impl MergeOperator for Append {
+        fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+            match existing {
+                Some(existing) => [existing, b",", operand].concat(),
+                None => operand.to_vec(),
             }
         }
     }
 
-    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
-        match (key.len() as u64 + value.len() as u64 + self.memtable.size) < self.memtable.threshold
+    #[test]
+    fn merge_operands_fold_on_read() -> Result<()> {
+        let dir = tempdir()?;
         {
-            true => {
-                self.memtable.put(key, value);
-            }
-            false => {
-                self.rotate_memtable_and_wal()?;
+            let mut db = open_engine(dir.path())?;
+            db.set_merge_operator(Arc::new(Append));
+            db.put(b"list", b"a")?;
+            db.merge(b"list", b"b")?;
+            db.merge(b"fresh", b"x")?;
+        }
+
+        // the operands were replayed into a table, the new ones sit in the memtable
+        let mut db = open_engine(dir.path())?;
+        assert!(matches!(
+            db.get(b"list"),
+            Err(DbError::MissingMergeOperator)
+        ));
+        db.set_merge_operator(Arc::new(Append));
+        let snapshot = db.snapshot();
+        db.merge(b"list", b"c")?;
+        db.delete(b"fresh")?;
+        db.merge(b"fresh", b"y")?;
+
+        assert_eq!(db.get(b"list")?, Some(b"a,b,c".to_vec()));
+        assert_eq!(snapshot.get(&db, b"list")?, Some(b"a,b".to_vec()));
+        assert_eq!(
+            collect(db.scan::<&[u8], _>(..))?,
+            vec![
+                ("fresh".to_string(), "y".to_string()),
+                ("list".to_string(), "a,b,c".to_string()),
+            ]
+        );
+        let mut iter = db.iter();
+        iter.seek_to_last()?;
+        assert_eq!(iter.value(), Some(b"a,b,c".as_slice()));
+        Ok(())
+    }
+
+    #[test]
+    fn conditional_writes_only_log_when_they_apply() -> Result<()> {
+        let dir = tempdir()?;
+        {
+            let mut db = open_engine(dir.path())?;
+            assert!(db.put_if_absent(b"lock", b"owner-1")?);
+            assert!(!db.put_if_absent(b"lock", b"owner-2")?);
+            assert!(!db.compare_and_swap(b"lock", Some(b"owner-2"), b"owner-3")?);
+            assert!(db.compare_and_swap(b"lock", Some(b"owner-1"), b"owner-3")?);
+            assert!(!db.delete_if_equals(b"lock", b"owner-1")?);
+            assert_eq!(db.last_seq, 2);
+        }
+
+        let mut db = open_engine(dir.path())?;
+        assert_eq!(db.get(b"lock")?, Some(b"owner-3".to_vec()));
+        assert!(db.delete_if_equals(b"lock", b"owner-3")?);
+        assert!(db.compare_and_swap(b"lock", None, b"owner-4")?);
+        assert_eq!(db.get(b"lock")?, Some(b"owner-4".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn column_families_share_the_wal_but_not_their_keys() -> Result<()> {
+        let dir = tempdir()?;
+        let sessions = {
+            let mut db = open_engine(dir.path())?;
+            let users = db.create_column_family("users", ColumnFamilyOptions::default())?;
+            let sessions = db.create_column_family("sessions", ColumnFamilyOptions::default())?;
+            assert!(
+                db.create_column_family("users", ColumnFamilyOptions::default())
+                    .is_err()
+            );
+            db.put(b"k", b"default")?;
+            db.put_cf(users, b"k", b"alice")?;
+
+            let mut batch = WriteBatch::new();
+            batch.put_cf(users, b"u", b"1");
+            batch.put_cf(sessions, b"s", b"1");
+            batch.delete(b"k");
+            db.write(batch)?;
+            assert_eq!(db.get(b"k")?, None);
+            assert_eq!(db.get_cf(users, b"k")?, Some(b"alice".to_vec()));
+            assert_eq!(db.get_cf(sessions, b"k")?, None);
+            sessions
+        };
+
+        // replay hands every record back to its own family
+        let mut db = open_engine(dir.path())?;
+        assert_eq!(
+            db.list_column_families(),
+            vec!["default", "users", "sessions"]
+        );
+        let users = db.column_family("users").unwrap();
+        assert_eq!(db.family(users)?.sstables.read().unwrap().len(), 1);
+        assert_eq!(
+            collect(db.scan_cf::<&[u8], _>(users, ..)?)?,
+            vec![
+                ("k".to_string(), "alice".to_string()),
+                ("u".to_string(), "1".to_string()),
+            ]
+        );
+        assert_eq!(db.get(b"k")?, None);
+        assert_eq!(db.get_cf(sessions, b"s")?, Some(b"1".to_vec()));
+
+        db.put_cf(sessions, b"t", b"2")?;
+        db.drop_column_family("sessions")?;
+        assert!(db.drop_column_family("default").is_err());
+        assert!(db.get_cf(sessions, b"s").is_err());
+        drop(db);
+
+        // the records of the dropped family are left in the WAL, a new family never sees them
+        let mut db = open_engine(dir.path())?;
+        assert_eq!(db.list_column_families(), vec!["default", "users"]);
+        let recreated = db.create_column_family("sessions", ColumnFamilyOptions::default())?;
+        assert_ne!(recreated, sessions);
+        assert_eq!(db.get_cf(recreated, b"t")?, None);
+        assert_eq!(db.get_cf(users, b"u")?, Some(b"1".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn column_family_options_survive_a_reopen() -> Result<()> {
+        let dir = tempdir()?;
+        {
+            let mut db = open_engine(dir.path())?;
+            let tuned = ColumnFamilyOptions::default()
+                .compaction(CompactionStrategy::SizeTiered(
+                    SizeTieredCompaction::default(),
+                ))
+                .compression(CompressionType::Lz4)
+                .memtable_threshold(64 * 1024)
+                .memtable(MemtableKind::SkipList);
+            db.create_column_family("logs", tuned)?;
+            let metrics = db.create_column_family("metrics", ColumnFamilyOptions::default())?;
+            db.set_column_family_options(
+                metrics,
+                ColumnFamilyOptions::default().memtable(MemtableKind::BTreeMap),
+            )?;
+        }
+
+        // rolled into the fresh MANIFEST of the first reopen, read back from it by the second
+        for _ in 0..2 {
+            let db = open_engine(dir.path())?;
+            let logs = &db.family(db.column_family("logs").unwrap())?.options;
+            assert!(matches!(logs.compaction, CompactionStrategy::SizeTiered(_)));
+            assert_eq!(logs.compression, CompressionType::Lz4);
+            assert_eq!(logs.memtable_threshold, 64 * 1024);
+            assert_eq!(logs.memtable, MemtableKind::SkipList);
+            let metrics = &db.family(db.column_family("metrics").unwrap())?.options;
+            assert_eq!(metrics.memtable, MemtableKind::BTreeMap);
+            assert!(matches!(metrics.compaction, CompactionStrategy::Leveled(_)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn options_are_validated_and_kept_with_the_data() -> Result<()> {
+        let dir = tempdir()?;
+        let invalid = |options| {
+            matches!(
+                KVEngine::open(dir.path(), options),
+                Err(DbError::InvalidOptions(_))
+            )
+        };
+        assert!(invalid(Options::default().block_size(0)));
+        assert!(invalid(Options::default().sync(SyncConfig::Every(0))));
+        assert!(invalid(Options::default().max_value_size(MAX_BLOCK_SIZE)));
+        assert!(invalid(Options::default().value_log_threshold(0)));
+        assert!(invalid(
+            Options::default()
+                .max_value_size(1024)
+                .value_log_threshold(4096)
+        ));
 
-                self.memtable.put(key, value);
+        let options = Options::default().block_size(64).max_key_size(8);
+        {
+            let mut db = KVEngine::open(dir.path(), options.clone())?;
+            assert!(matches!(
+                db.put(b"too long a key", b"v"),
+                Err(DbError::InvalidKey(_))
+            ));
+            for i in 0..100 {
+                db.put(format!("key{:03}", i).as_bytes(), b"value")?;
             }
         }
-        self.wal
-            .record_to_wal(WalRecordType::Insertion(key, value))?;
+        assert!(fs::read_to_string(dir.path().join("OPTIONS"))?.contains("block_size=64\n"));
+
+        // the tables on disk may hold keys of up to 8 bytes
+        assert!(invalid(options.clone().max_key_size(4)));
+        let db = KVEngine::open(dir.path(), options.max_key_size(16))?;
+        let tables = db.default_family().sstables.read().unwrap();
+        assert!(tables[0].sparse_index.len() > 10);
+        assert_eq!(db.get(b"key042")?, Some(b"value".to_vec()));
+        Ok(())
+    }
 
+    #[test]
+    fn a_failed_open_keeps_the_stored_options() -> Result<()> {
+        let dir = tempdir()?;
+        drop(KVEngine::open(
+            dir.path(),
+            Options::default().block_size(64),
+        )?);
+        fs::write(dir.path().join("CURRENT"), "MANIFEST-0\n")?;
+
+        assert!(KVEngine::open(dir.path(), Options::default().block_size(128)).is_err());
+        assert_eq!(Options::load(dir.path())?.unwrap().block_size, 64);
         Ok(())
     }
 
-    fn delete(&mut self, key: &[u8]) -> Result<()> {
-        self.memtable.delete(key);
-        self.wal.record_to_wal(WalRecordType::Deletion(key))?;
+    #[test]
+    fn db_handle_serves_readers_while_writers_run() -> Result<()> {
+        fn shareable<T: Send + Sync>() {}
+        shareable::<Db>();
+
+        let dir = tempdir()?;
+        let db = Arc::new(Db::open(
+            dir.path(),
+            Options::default().sync(SyncConfig::None),
+        )?);
+        for i in 0..100 {
+            db.put(format!("base{:03}", i).as_bytes(), b"v")?;
+        }
+
+        let mut handles = Vec::new();
+        for t in 0..2 {
+            let db = Arc::clone(&db);
+            handles.push(std::thread::spawn(move || -> Result<()> {
+                for i in 0..500 {
+                    db.put(format!("w{}-{:03}", t, i).as_bytes(), b"x")?;
+                }
+                Ok(())
+            }));
+        }
+        for _ in 0..4 {
+            let db = Arc::clone(&db);
+            handles.push(std::thread::spawn(move || -> Result<()> {
+                for i in 0..500 {
+                    let key = format!("base{:03}", i % 100);
+                    assert_eq!(db.get(key.as_bytes())?, Some(b"v".to_vec()));
+                }
+                Ok(())
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+
+        assert_eq!(
+            db.scan(Bound::Included(b"w"), Bound::Unbounded).count(),
+            1000
+        );
+        assert!(db.put_if_absent(b"base000", b"other").is_ok_and(|put| !put));
         Ok(())
     }
 
-    fn sync_memtable(memtable: AVL) {
-        unimplemented!()
+    #[test]
+    fn db_offers_families_snapshots_batches_and_iterators() -> Result<()> {
+        let dir = tempdir()?;
+        let db = Db::open(dir.path(), Options::default())?;
+        db.set_merge_operator(Arc::new(Append));
+        let users = db.create_column_family("users", ColumnFamilyOptions::default())?;
+        assert_eq!(db.column_family("users"), Some(users));
+        assert_eq!(db.list_column_families(), vec!["default", "users"]);
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"a", b"1");
+        batch.put_cf(users, b"a", b"user");
+        batch.put(b"b", b"2");
+        db.write_batch(&batch)?;
+        db.merge(b"b", b"3")?;
+        let snapshot = db.snapshot();
+        db.delete(b"a")?;
+        db.delete_cf(users, b"a")?;
+        db.put_cf(users, b"z", b"last")?;
+
+        assert_eq!(db.get_at(&snapshot, b"a")?, Some(b"1".to_vec()));
+        assert_eq!(db.get(b"a")?, None);
+        assert_eq!(db.get(b"b")?, Some(b"2,3".to_vec()));
+        assert_eq!(db.get_cf(users, b"z")?, Some(b"last".to_vec()));
+        assert_eq!(
+            db.scan_at(&snapshot, Bound::Unbounded, Bound::Unbounded)?
+                .count(),
+            2
+        );
+        let found = db.with_iter(|iter| {
+            iter.seek_for_prev(b"c")?;
+            Ok(iter.key().map(<[u8]>::to_vec))
+        })?;
+        assert_eq!(found, Some(b"b".to_vec()));
+
+        // the other family reads at the snapshot and iterates on its own keys
+        assert_eq!(
+            db.get_cf_at(&snapshot, users, b"a")?,
+            Some(b"user".to_vec())
+        );
+        assert_eq!(db.get_cf_at(&snapshot, users, b"z")?, None);
+        let at_snapshot: Vec<_> = db
+            .scan_cf_at(&snapshot, users, Bound::Unbounded, Bound::Unbounded)?
+            .collect::<Result<_>>()?;
+        assert_eq!(at_snapshot, vec![(b"a".to_vec(), b"user".to_vec())]);
+        let keys = db.with_iter_cf(users, |iter| {
+            let mut keys = Vec::new();
+            iter.seek_to_first()?;
+            while let Some(key) = iter.key() {
+                keys.push(key.to_vec());
+                iter.next()?;
+            }
+            Ok(keys)
+        })?;
+        assert_eq!(keys, vec![b"z".to_vec()]);
+        assert_eq!(
+            db.scan_cf(users, Bound::Unbounded, Bound::Unbounded)?
+                .count(),
+            1
+        );
+
+        db.drop_column_family("users")?;
+        assert_eq!(db.column_family("users"), None);
+        assert_eq!(db.collect_value_log()?, 0);
+        assert_eq!(db.block_cache_stats().usage, 0);
+        Ok(())
     }
-    fn sync(&mut self) -> io::Result<()> {
-        if let Some(writer) = &mut self.curr_file_buffer {
-            writer.flush()?;
-            writer.get_ref().sync_all()?;
+
+    #[test]
+    fn db_writers_share_wal_fsyncs() -> Result<()> {
+        let dir = tempdir()?;
+        let db = Arc::new(Db::open(dir.path(), Options::default())?);
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let db = Arc::clone(&db);
+                std::thread::spawn(move || -> Result<()> {
+                    for i in 0..50 {
+                        db.put(format!("w{}-{:02}", t, i).as_bytes(), b"x")?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
         }
 
+        // every put returned durable, with fewer fsyncs than puts
+        assert!(db.read().wal.sync_count() < 400);
+        assert_eq!(
+            db.scan(Bound::Included(b"w"), Bound::Unbounded).count(),
+            400
+        );
         Ok(())
     }
 
-    fn rotate_memtable_and_wal(&mut self) -> Result<()> {
-        let frozen = Arc::new(std::mem::replace(
-            &mut self.memtable,
-            AVL::new(MEMTABLE_THRESHOLD),
-        ));
+    #[test]
+    fn db_write_whose_fsync_fails_stays_invisible() -> Result<()> {
+        let dir = tempdir()?;
+        let db = Db::open(dir.path(), Options::default().sync(SyncConfig::Always))?;
+        db.put(b"a", b"1")?;
+
+        db.read().wal.fail_syncs();
+        assert!(db.put(b"b", b"2").is_err());
+        assert!(db.compare_and_swap(b"a", Some(b"1"), b"3").is_err());
+
+        assert_eq!(db.get(b"b")?, None);
+        assert_eq!(db.get(b"a")?, Some(b"1".to_vec()));
+        assert_eq!(db.read().last_seq, 1);
+        Ok(())
+    }
+
+    // a frozen memtable holding a single version of b"key", queued on the family
+    fn frozen_job(
+        dir: &Path,
+        manifest: &Arc<Mutex<Manifest>>,
+        family: &ColumnFamily,
+        value: &[u8],
+        seq: u64,
+    ) -> FlushJob {
+        let mut memtable = MemtableKind::Avl.new_memtable();
+        memtable.put(b"key", value, seq, 0);
+        let memtable: Arc<dyn Memtable> = Arc::from(memtable);
+        family
+            .immutable
+            .write()
+            .unwrap()
+            .push_front(Arc::clone(&memtable));
+        let (_, tmp_path, final_path) = KVEngine::create_new_data_file(dir).unwrap();
+        FlushJob {
+            cf: family.id,
+            wal_id: 0,
+            memtable,
+            immutable: Arc::clone(&family.immutable),
+            sstables: Arc::clone(&family.sstables),
+            tmp_path,
+            final_path,
+            table: TableOptions::from(CompressionType::None),
+            vlog: Arc::new(ValueLog::new(dir, &Options::default())),
+            merge_operator: None,
+            snapshots: SnapshotList::default(),
+            compaction: CompactionJob {
+                cf: family.id,
+                dir: dir.to_path_buf(),
+                sstables: Arc::clone(&family.sstables),
+                strategy: family.options.compaction.clone(),
+                table: TableOptions::from(CompressionType::None),
+                merge_operator: None,
+                snapshots: SnapshotList::default(),
+                manifest: Arc::clone(manifest),
+                running: Arc::default(),
+            },
+        }
+    }
 
-        self.flushing_memtable = Some(Arc::downgrade(&frozen));
-        let old_wal = std::mem::replace(
-            &mut self.wal,
-            WAL::new(MEMTABLE_THRESHOLD, self.sync_config)?,
+    // a coordinator without an engine, flush results are handed to it directly
+    fn started_manager(dir: &Path) -> Result<(FlushingManager, Arc<Mutex<Manifest>>)> {
+        let manifest = Arc::new(Mutex::new(Manifest::create(dir, VersionEdit::default())?));
+        let mut manager = FlushingManager::new(Arc::default());
+        manager.start(
+            Arc::clone(&manifest),
+            FrozenWals::default(),
+            Arc::new(ValueLog::new(dir, &Options::default())),
         );
-        self.frozen_wal = Some(old_wal);
-        let (file, tmp_path, final_path) = KVEngine::create_new_data_file(&self.data_directory)?;
+        Ok((manager, manifest))
+    }
+
+    fn send_flushed(manager: &FlushingManager, id: u64, job: FlushJob, result: Result<SSTable>) {
+        manager.write_controller.flush_started();
+        let finished = FlushFinished(Arc::clone(&manager.write_controller));
+        let _ = manager.tx.as_ref().unwrap().send(FlushingThreadResponse {
+            id,
+            job,
+            result,
+            _finished: finished,
+        });
+    }
 
-        let _ =
-            self.flushing_manager
-                .background_flush_memtable(frozen, tmp_path.clone(), final_path);
+    fn default_family() -> ColumnFamily {
+        ColumnFamily::new(
+            DEFAULT_COLUMN_FAMILY,
+            DEFAULT_COLUMN_FAMILY_NAME,
+            Options::default().column_family_options(),
+        )
+    }
 
+    #[test]
+    fn flushes_are_installed_in_the_order_they_were_frozen() -> Result<()> {
+        let dir = tempdir()?;
+        let (manager, manifest) = started_manager(dir.path())?;
+        let family = default_family();
+        let cache = BlockCache::new(BLOCK_CACHE_CAPACITY);
+        let older = frozen_job(dir.path(), &manifest, &family, b"old", 1);
+        let newer = frozen_job(dir.path(), &manifest, &family, b"new", 2);
+
+        // the newer flush finishes first and has to wait for the older one
+        let table = newer.flush();
+        send_flushed(&manager, 1, newer, table);
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(family.sstables.read().unwrap().is_empty());
+        assert_eq!(family.immutable.read().unwrap().len(), 2);
+        let found = family.lookup(b"key", u64::MAX, &cache)?.unwrap();
+        assert_eq!(found.value, b"new");
+
+        let table = older.flush();
+        send_flushed(&manager, 0, older, table);
+        while !family.immutable.read().unwrap().is_empty() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(family.sstables.read().unwrap().len(), 2);
+        let found = family.lookup(b"key", u64::MAX, &cache)?.unwrap();
+        assert_eq!(found.value, b"new");
         Ok(())
     }
 
-    // fn serialize_record(tstamp: u64, key: &[u8], value: &[u8]) -> Vec<u8> {
-    //     let crc32 = Crc::<u32>::new(&CRC_32_ISO_HDLC);
-    //     let body: Vec<u8> = [
-    //         &tstamp.to_le_bytes()[..],
-    //         &(key.len() as u64).to_le_bytes(),
-    //         &(value.len() as u64).to_le_bytes(),
-    //         key,
-    //         value,
-    //     ]
-    //     .concat();
-    //     let checksum = crc32.checksum(&body);
-    //     let mut record = checksum.to_le_bytes().to_vec();
-    //     record.extend(body);
-    //     record
-    // }
-}
+    #[test]
+    fn a_failed_flush_is_retried_and_its_error_sticks() -> Result<()> {
+        let dir = tempdir()?;
+        let (manager, manifest) = started_manager(dir.path())?;
+        let family = default_family();
+        let mut job = frozen_job(dir.path(), &manifest, &family, b"value", 1);
+        let missing = dir.path().join("missing");
+        job.tmp_path = missing.join("1.sst.tmp");
+        job.final_path = missing.join("1.sst");
+
+        let failed = job.flush();
+        assert!(failed.is_err());
+        send_flushed(&manager, 0, job, failed);
+        std::thread::sleep(Duration::from_millis(
+            3 * FLUSH_RETRY_DELAY.as_millis() as u64,
+        ));
+        assert!(matches!(
+            manager.check_background_error(),
+            Err(DbError::Background(_))
+        ));
+        assert_eq!(manager.write_controller.stats().pending_memtables, 1);
+        assert_eq!(family.immutable.read().unwrap().len(), 1);
+
+        // whatever made it fail goes away, the next attempt gets through and writes are still
+        // refused until the engine is reopened
+        fs::create_dir(&missing)?;
+        while manager.write_controller.stats().pending_memtables > 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(matches!(
+            manager.check_background_error(),
+            Err(DbError::Background(_))
+        ));
+        assert!(family.immutable.read().unwrap().is_empty());
+        assert_eq!(family.sstables.read().unwrap().len(), 1);
+        Ok(())
+    }
 
-/*Notes:
- // footer is :  | min key | max key | sizeof(sparse_index) | sparse_index_offset| sizeof(bloom_filter) | bloom filter_offset | sizeof(minkey) | minkey offset | sizeof(maxkey) | maxkey offset | 64 bytes(not including min and max key)
-DataBlocks:  [ tstamp(8) | ksz(8) | value_sz(8) | key | value  tstamp(8) | ksz(8) | value_sz(8) | key | value ... crc(4)]
-SSTable: Datablock1 | DataBlock2 ... Datablock N | Footer
-Bloom filter: k-hash bit array per SSTable to skip files on negative lookups. Use 10 bits per key. Built during flush of AVL.
-*/
-// SparseIndex => [ firskey:[offset, datablock_length] ]
-// wal record looks like: ksz, vsz, k, v, crc(4 bytes)
-// When you read a data block in the sparse index, remember to account for the 4 crc bytes yourself, they are not accounted forin the length
-/*
-TODOS:
-Build SSTables on open to have the metadata in memory.
-Need to rewrite the delete function. Right now I am removing the Node from the tree but this can cause a bug:
-if you delete a key thats in the memtable, you remove the node, but what if its in one of the SStables?
-since the memtable hasnt been flushed yet, you will check memtable -> not found then check ss table and return the value even though
-it was deleted.
-So instead of removing the node, just add a tombstone on deletes. this means that the tree will just grow and now need to rebalance on deletes.
-On delete: just do insert(key, node) and have node.deleted true.
+    #[test]
+    fn gets_do_not_hang_while_flushes_install() -> Result<()> {
+        let dir = tempdir()?;
+        let options = Options::default()
+            .sync(SyncConfig::None)
+            .memtable_size(2048);
+        let db = Arc::new(Db::open(dir.path(), options)?);
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let (done_tx, done_rx) = mpsc::channel();
+        {
+            let (db, stop, done_tx) = (Arc::clone(&db), Arc::clone(&stop), done_tx.clone());
+            spawn(move || {
+                for i in 0..2000 {
+                    db.put(format!("key{:04}", i).as_bytes(), &[b'v'; 100])
+                        .unwrap();
+                }
+                stop.store(true, std::sync::atomic::Ordering::Release);
+                let _ = done_tx.send(());
+            });
+        }
+        // found keys make the readers take the frozen memtables twice while flushes install
+        for _ in 0..4 {
+            let (db, stop, done_tx) = (Arc::clone(&db), Arc::clone(&stop), done_tx.clone());
+            spawn(move || {
+                while !stop.load(std::sync::atomic::Ordering::Acquire) {
+                    for i in (0..2000).step_by(97) {
+                        db.get(format!("key{:04}", i).as_bytes()).unwrap();
+                    }
+                }
+                let _ = done_tx.send(());
+            });
+        }
+        for _ in 0..5 {
+            done_rx
+                .recv_timeout(Duration::from_secs(30))
+                .expect("a get deadlocked against a flush install");
+        }
+        Ok(())
+    }
 
-On KVEngine get() you check if a kv is in the memtable, if yes, check the deleted flag.
-[ tstamp(8) | ksz(8) | value_sz(8) | deletedflag(1) | key | value ]
-deletedflag 1 = deleted, 0 = alive
+    #[test]
+    fn frozen_memtables_stay_readable_while_flushes_queue_up() -> Result<()> {
+        let dir = tempdir()?;
+        let invalid = Options::default()
+            .max_pending_memtables(2)
+            .slowdown_pending_memtables(3);
+        assert!(matches!(
+            Db::open(dir.path(), invalid),
+            Err(DbError::InvalidOptions(_))
+        ));
 
+        let options = Options::default()
+            .sync(SyncConfig::None)
+            .memtable_size(2048)
+            .max_pending_memtables(2)
+            .slowdown_pending_memtables(1);
+        {
+            let db = Db::open(dir.path(), options.clone())?;
+            // every few writes rotate the memtable while earlier ones may still be flushing
+            for i in 0..300 {
+                db.put(format!("key{:03}", i).as_bytes(), &[b'v'; 100])?;
+            }
+            for i in 0..300 {
+                let key = format!("key{:03}", i);
+                assert_eq!(db.get(key.as_bytes())?, Some(vec![b'v'; 100]), "{key}");
+            }
+            assert_eq!(db.scan(Bound::Unbounded, Bound::Unbounded).count(), 300);
+            assert!(db.write_stall_stats().pending_memtables <= 2);
+        }
 
- For WAL records, we have deletion and insertion types so far. Will use one byte to define type. 00000100(4) = INSERTION. 00000010(2) = DELETION.
- serialized should look like this: TYPE | RECORD
- RECORD can be tstamp | ksz | key |crc (4 bytes) OR it can be  | tstamp | ksz |vsz | key | value | crc(4 bytes)
- POTENTIAL PROBLEM: should I include sequence numbers for each k/v pair ?
- PROBLEM/UPDATE: make Bufreaders with capacity instead
- PROBLEM/OPT: metadata footer can
+        let db = Db::open(dir.path(), options)?;
+        assert_eq!(db.get(b"key123")?, Some(vec![b'v'; 100]));
+        assert_eq!(db.scan(Bound::Unbounded, Bound::Unbounded).count(), 300);
+        Ok(())
+    }
 
-*/
+    #[test]
+    fn flushed_tables_are_installed_and_their_wals_destroyed() -> Result<()> {
+        let dir = tempdir()?;
+        let options = Options::default()
+            .sync(SyncConfig::None)
+            .memtable_size(2048);
+        let mut engine = KVEngine::open(dir.path(), options.clone())?;
+        for i in 0..300 {
+            engine.put(format!("key{:03}", i).as_bytes(), &[b'v'; 100])?;
+        }
+        while engine.write_stall_stats().pending_memtables > 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let family = engine.default_family();
+        assert!(family.immutable.read().unwrap().is_empty());
+        assert!(!family.sstables.read().unwrap().is_empty());
+        let wals = fs::read_dir(dir.path())?
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("wal".as_ref()))
+            .count();
+        assert_eq!(wals, 1);
+        assert_eq!(engine.get(b"key123")?, Some(vec![b'v'; 100]));
+
+        // a failed job keeps refusing writes, reads go on
+        *engine.flushing_manager.background_error.lock().unwrap() = Some("disk full".to_string());
+        assert!(matches!(
+            engine.put(b"key", b"value"),
+            Err(DbError::Background(_))
+        ));
+        assert_eq!(engine.get(b"key000")?, Some(vec![b'v'; 100]));
+        drop(engine);
+
+        // the installed tables are in the MANIFEST, nothing had to be replayed
+        let engine = KVEngine::open(dir.path(), options)?;
+        assert_eq!(engine.scan::<&[u8], _>(..).count(), 300);
+        Ok(())
+    }
+}
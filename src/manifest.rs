@@ -0,0 +1,516 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions, remove_file, rename};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::errors::{CorruptionType, DataCorruptedErr, DbError, Result};
+use crate::helpers::{compute_crc_data_block, new_file_number, next_file_number};
+use crate::lsm::{ColumnFamilyId, DEFAULT_COLUMN_FAMILY, SSTable};
+
+const CURRENT_FILE: &str = "CURRENT";
+const MANIFEST_PREFIX: &str = "MANIFEST-";
+const MAX_MANIFEST_SIZE: u64 = 4 * 1024 * 1024; // past this the log is rolled into a fresh snapshot
+const RECORD_LEN_SIZE: usize = 8;
+const RECORD_CRC_SIZE: usize = 4;
+
+const TAG_ADD_FILE: u8 = 1;
+const TAG_REMOVE_FILE: u8 = 2;
+const TAG_NEXT_FILE_NUMBER: u8 = 3;
+const TAG_LAST_SEQUENCE: u8 = 4;
+const TAG_ADD_COLUMN_FAMILY: u8 = 5;
+const TAG_DROP_COLUMN_FAMILY: u8 = 6;
+const TAG_NEXT_COLUMN_FAMILY: u8 = 7;
+const TAG_ADD_FAMILY_FILE: u8 = 8;
+const TAG_COLUMN_FAMILY_OPTIONS: u8 = 9;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct FileMeta {
+    pub(crate) column_family: ColumnFamilyId,
+    pub(crate) id: u64,
+    pub(crate) level: u32,
+    pub(crate) min_key: Vec<u8>,
+    pub(crate) max_key: Vec<u8>,
+}
+
+// One change to the live table set or the column families. Encoded as tagged fields:
+// add:    [ TAG | id | level(4) | min_ksz | min_key | max_ksz | max_key ]
+//         tables of other column families than the default one: [ TAG | cf(4) | id | level(4) | ... ]
+// remove: [ TAG | id ]
+// next file number / last sequence: [ TAG | u64 ]
+// add column family: [ TAG | cf(4) | name_sz | name ]
+// column family options: [ TAG | cf(4) | options_sz | options ], the latest one of a family wins
+// drop column family / next column family: [ TAG | cf(4) ]
+#[derive(Default, Debug, PartialEq)]
+pub(crate) struct VersionEdit {
+    added: Vec<FileMeta>,
+    removed: Vec<u64>,
+    next_file_number: Option<u64>,
+    last_sequence: Option<u64>,
+    added_families: Vec<(ColumnFamilyId, String)>,
+    family_options: Vec<(ColumnFamilyId, String)>,
+    dropped_families: Vec<ColumnFamilyId>,
+    next_column_family: Option<ColumnFamilyId>,
+}
+
+impl VersionEdit {
+    pub(crate) fn add_file(&mut self, cf: ColumnFamilyId, table: &SSTable) {
+        self.added.push(FileMeta {
+            column_family: cf,
+            id: table.id,
+            level: table.level,
+            min_key: table.min_key.clone(),
+            max_key: table.max_key.clone(),
+        });
+    }
+
+    pub(crate) fn remove_file(&mut self, id: u64) {
+        self.removed.push(id);
+    }
+
+    pub(crate) fn set_last_sequence(&mut self, seq: u64) {
+        self.last_sequence = Some(seq);
+    }
+
+    // ids are never handed out twice, so WAL records of a dropped family stay orphaned
+    pub(crate) fn add_column_family(&mut self, cf: ColumnFamilyId, name: &str) {
+        self.added_families.push((cf, name.to_string()));
+        self.next_column_family = self.next_column_family.max(Some(cf + 1));
+    }
+
+    // options are kept as the name=value lines ColumnFamilyOptions encodes them to
+    pub(crate) fn set_column_family_options(&mut self, cf: ColumnFamilyId, options: String) {
+        self.family_options.push((cf, options));
+    }
+
+    // the tables of the family go with it
+    pub(crate) fn drop_column_family(&mut self, cf: ColumnFamilyId) {
+        self.dropped_families.push(cf);
+    }
+
+    pub(crate) fn set_next_column_family(&mut self, cf: ColumnFamilyId) {
+        self.next_column_family = self.next_column_family.max(Some(cf));
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        // families first, the tables that follow may belong to them
+        for (cf, name) in &self.added_families {
+            buf.push(TAG_ADD_COLUMN_FAMILY);
+            buf.extend_from_slice(&cf.to_le_bytes());
+            buf.extend_from_slice(&(name.len() as u64).to_le_bytes());
+            buf.extend_from_slice(name.as_bytes());
+        }
+        for (cf, options) in &self.family_options {
+            buf.push(TAG_COLUMN_FAMILY_OPTIONS);
+            buf.extend_from_slice(&cf.to_le_bytes());
+            buf.extend_from_slice(&(options.len() as u64).to_le_bytes());
+            buf.extend_from_slice(options.as_bytes());
+        }
+        for file in &self.added {
+            if file.column_family == DEFAULT_COLUMN_FAMILY {
+                buf.push(TAG_ADD_FILE);
+            } else {
+                buf.push(TAG_ADD_FAMILY_FILE);
+                buf.extend_from_slice(&file.column_family.to_le_bytes());
+            }
+            buf.extend_from_slice(&file.id.to_le_bytes());
+            buf.extend_from_slice(&file.level.to_le_bytes());
+            buf.extend_from_slice(&(file.min_key.len() as u64).to_le_bytes());
+            buf.extend_from_slice(&file.min_key);
+            buf.extend_from_slice(&(file.max_key.len() as u64).to_le_bytes());
+            buf.extend_from_slice(&file.max_key);
+        }
+        for id in &self.removed {
+            buf.push(TAG_REMOVE_FILE);
+            buf.extend_from_slice(&id.to_le_bytes());
+        }
+        if let Some(n) = self.next_file_number {
+            buf.push(TAG_NEXT_FILE_NUMBER);
+            buf.extend_from_slice(&n.to_le_bytes());
+        }
+        if let Some(seq) = self.last_sequence {
+            buf.push(TAG_LAST_SEQUENCE);
+            buf.extend_from_slice(&seq.to_le_bytes());
+        }
+        for cf in &self.dropped_families {
+            buf.push(TAG_DROP_COLUMN_FAMILY);
+            buf.extend_from_slice(&cf.to_le_bytes());
+        }
+        if let Some(cf) = self.next_column_family {
+            buf.push(TAG_NEXT_COLUMN_FAMILY);
+            buf.extend_from_slice(&cf.to_le_bytes());
+        }
+    }
+
+    fn decode(b: &[u8]) -> std::result::Result<Self, CorruptionType> {
+        let mut edit = VersionEdit::default();
+        let mut pos = 0;
+        while pos < b.len() {
+            let tag = take(b, &mut pos, 1)?[0];
+            match tag {
+                TAG_ADD_FILE | TAG_ADD_FAMILY_FILE => {
+                    let column_family = match tag {
+                        TAG_ADD_FAMILY_FILE => take_u32(b, &mut pos)?,
+                        _ => DEFAULT_COLUMN_FAMILY,
+                    };
+                    let id = take_u64(b, &mut pos)?;
+                    let level = u32::from_le_bytes(take(b, &mut pos, 4)?.try_into().unwrap());
+                    let min_len = take_u64(b, &mut pos)? as usize;
+                    let min_key = take(b, &mut pos, min_len)?.to_vec();
+                    let max_len = take_u64(b, &mut pos)? as usize;
+                    let max_key = take(b, &mut pos, max_len)?.to_vec();
+                    edit.added.push(FileMeta {
+                        column_family,
+                        id,
+                        level,
+                        min_key,
+                        max_key,
+                    });
+                }
+                TAG_REMOVE_FILE => edit.removed.push(take_u64(b, &mut pos)?),
+                TAG_NEXT_FILE_NUMBER => edit.next_file_number = Some(take_u64(b, &mut pos)?),
+                TAG_LAST_SEQUENCE => edit.last_sequence = Some(take_u64(b, &mut pos)?),
+                TAG_ADD_COLUMN_FAMILY => {
+                    let cf = take_u32(b, &mut pos)?;
+                    let name_len = take_u64(b, &mut pos)? as usize;
+                    let name =
+                        String::from_utf8(take(b, &mut pos, name_len)?.to_vec()).map_err(|_| {
+                            CorruptionType::Other("column family name is not utf-8".to_string())
+                        })?;
+                    edit.added_families.push((cf, name));
+                }
+                TAG_COLUMN_FAMILY_OPTIONS => {
+                    let cf = take_u32(b, &mut pos)?;
+                    let len = take_u64(b, &mut pos)? as usize;
+                    let options =
+                        String::from_utf8(take(b, &mut pos, len)?.to_vec()).map_err(|_| {
+                            CorruptionType::Other("column family options are not utf-8".to_string())
+                        })?;
+                    edit.family_options.push((cf, options));
+                }
+                TAG_DROP_COLUMN_FAMILY => edit.dropped_families.push(take_u32(b, &mut pos)?),
+                TAG_NEXT_COLUMN_FAMILY => edit.next_column_family = Some(take_u32(b, &mut pos)?),
+                other => {
+                    return Err(CorruptionType::Other(format!(
+                        "unknown manifest tag {}",
+                        other
+                    )));
+                }
+            }
+        }
+        Ok(edit)
+    }
+}
+
+fn take<'a>(
+    b: &'a [u8],
+    pos: &mut usize,
+    n: usize,
+) -> std::result::Result<&'a [u8], CorruptionType> {
+    let end =
+        pos.checked_add(n)
+            .filter(|end| *end <= b.len())
+            .ok_or(CorruptionType::LengthMismatch {
+                expected: pos.saturating_add(n),
+                found: b.len(),
+            })?;
+    let out = &b[*pos..end];
+    *pos = end;
+    Ok(out)
+}
+
+fn take_u64(b: &[u8], pos: &mut usize) -> std::result::Result<u64, CorruptionType> {
+    Ok(u64::from_le_bytes(take(b, pos, 8)?.try_into().unwrap()))
+}
+
+fn take_u32(b: &[u8], pos: &mut usize) -> std::result::Result<u32, CorruptionType> {
+    Ok(u32::from_le_bytes(take(b, pos, 4)?.try_into().unwrap()))
+}
+
+// what all edits of a manifest add up to
+#[derive(Default, Clone)]
+pub(crate) struct ManifestState {
+    pub(crate) files: BTreeMap<u64, FileMeta>,
+    pub(crate) next_file_number: u64,
+    pub(crate) last_sequence: u64,
+    pub(crate) column_families: BTreeMap<ColumnFamilyId, String>, // every family but the default one
+    pub(crate) column_family_options: BTreeMap<ColumnFamilyId, String>,
+    pub(crate) next_column_family: ColumnFamilyId,
+}
+
+impl ManifestState {
+    fn apply(&mut self, edit: &VersionEdit) {
+        for (cf, name) in &edit.added_families {
+            self.column_families.insert(*cf, name.clone());
+        }
+        for (cf, options) in &edit.family_options {
+            self.column_family_options.insert(*cf, options.clone());
+        }
+        for id in &edit.removed {
+            self.files.remove(id);
+        }
+        for file in &edit.added {
+            self.files.insert(file.id, file.clone());
+        }
+        if let Some(n) = edit.next_file_number {
+            self.next_file_number = self.next_file_number.max(n);
+        }
+        if let Some(seq) = edit.last_sequence {
+            self.last_sequence = self.last_sequence.max(seq);
+        }
+        for cf in &edit.dropped_families {
+            self.column_families.remove(cf);
+            self.column_family_options.remove(cf);
+            self.files.retain(|_, file| file.column_family != *cf);
+        }
+        if let Some(cf) = edit.next_column_family {
+            self.next_column_family = self.next_column_family.max(cf);
+        }
+    }
+
+    // a single edit that rebuilds this state from nothing
+    fn snapshot(&self) -> VersionEdit {
+        VersionEdit {
+            added: self.files.values().cloned().collect(),
+            removed: Vec::new(),
+            next_file_number: Some(self.next_file_number),
+            last_sequence: Some(self.last_sequence),
+            added_families: self
+                .column_families
+                .iter()
+                .map(|(cf, name)| (*cf, name.clone()))
+                .collect(),
+            family_options: self
+                .column_family_options
+                .iter()
+                .map(|(cf, options)| (*cf, options.clone()))
+                .collect(),
+            dropped_families: Vec::new(),
+            next_column_family: Some(self.next_column_family),
+        }
+    }
+}
+
+// Log of version edits, every record is [ len | edit | crc ] with the crc covering len and edit.
+// CURRENT names the manifest in use and is only ever replaced by a rename, so a crash leaves
+// either the old or the new manifest in charge.
+pub(crate) struct Manifest {
+    writer: BufWriter<File>,
+    dir: PathBuf,
+    size: u64,
+    state: ManifestState,
+    failed: Option<io::ErrorKind>, // a half written record would hide every edit after it
+}
+
+impl Manifest {
+    // replays the manifest CURRENT points to, None if the directory never had one
+    pub(crate) fn recover(dir: &Path) -> Result<Option<ManifestState>> {
+        let current = match fs::read_to_string(dir.join(CURRENT_FILE)) {
+            Ok(current) => current,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let name = current.trim_end();
+        let path = dir.join(name);
+        if !name.starts_with(MANIFEST_PREFIX) || !path.is_file() {
+            return Err(DbError::FileError(
+                "CURRENT does not point to a manifest".to_string(),
+                path,
+            ));
+        }
+
+        let buf = fs::read(&path)?;
+        let mut state = ManifestState::default();
+        let mut pos = 0;
+        while pos < buf.len() {
+            match Self::decode_record(&buf, pos) {
+                Ok((edit, next)) => {
+                    state.apply(&edit);
+                    pos = next;
+                }
+                // edits count once they are synced, a torn last record was never acknowledged
+                Err(_) if Self::reaches_end(&buf, pos) => break,
+                Err(reason) => {
+                    return Err(DbError::DataCorrupted(DataCorruptedErr {
+                        offset: pos as u64,
+                        file_path: path,
+                        reason,
+                    }));
+                }
+            }
+        }
+        Ok(Some(state))
+    }
+
+    fn decode_record(
+        buf: &[u8],
+        pos: usize,
+    ) -> std::result::Result<(VersionEdit, usize), CorruptionType> {
+        let mut cursor = pos;
+        let len = take_u64(buf, &mut cursor)? as usize;
+        let edit = take(buf, &mut cursor, len)?;
+        let crc_end = cursor;
+        let expected =
+            u32::from_le_bytes(take(buf, &mut cursor, RECORD_CRC_SIZE)?.try_into().unwrap());
+        let found = compute_crc_data_block(&buf[pos..crc_end]);
+        if expected != found {
+            return Err(CorruptionType::CrcMismatch { expected, found });
+        }
+        Ok((VersionEdit::decode(edit)?, cursor))
+    }
+
+    fn reaches_end(buf: &[u8], pos: usize) -> bool {
+        if buf.len() - pos < RECORD_LEN_SIZE {
+            return true;
+        }
+        let len = u64::from_le_bytes(buf[pos..pos + RECORD_LEN_SIZE].try_into().unwrap());
+        (pos + RECORD_LEN_SIZE + RECORD_CRC_SIZE) as u64 + len >= buf.len() as u64
+    }
+
+    // writes a new manifest holding edit as its snapshot, points CURRENT at it and removes
+    // every older manifest
+    pub(crate) fn create(dir: &Path, edit: VersionEdit) -> Result<Manifest> {
+        let mut state = ManifestState::default();
+        state.apply(&edit);
+        let name = format!("{}{}", MANIFEST_PREFIX, new_file_number());
+        let path = dir.join(&name);
+        let file = OpenOptions::new()
+            .append(true)
+            .create_new(true)
+            .open(&path)?;
+        let mut manifest = Manifest {
+            writer: BufWriter::new(file),
+            dir: dir.to_path_buf(),
+            size: 0,
+            state,
+            failed: None,
+        };
+        let mut snapshot = manifest.state.snapshot();
+        snapshot.next_file_number = Some(next_file_number());
+        manifest.append(&snapshot)?;
+        manifest.state.apply(&snapshot);
+
+        let tmp_path = dir.join(format!("{}.tmp", CURRENT_FILE));
+        let mut current = File::create(&tmp_path)?;
+        current.write_all(format!("{}\n", name).as_bytes())?;
+        current.sync_all()?;
+        rename(&tmp_path, dir.join(CURRENT_FILE))?;
+        File::open(dir)?.sync_all()?;
+
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            if file_name.starts_with(MANIFEST_PREFIX) && file_name != name {
+                remove_file(entry.path())?;
+            }
+        }
+        Ok(manifest)
+    }
+
+    // durable once this returns, the edit also records how far file numbers have been handed out
+    pub(crate) fn log(&mut self, mut edit: VersionEdit) -> Result<()> {
+        if let Some(kind) = self.failed {
+            return Err(io::Error::new(kind, "an earlier manifest write failed").into());
+        }
+        edit.next_file_number = Some(next_file_number());
+        if let Err(err) = self.append(&edit) {
+            if let DbError::Io(io_err) = &err {
+                self.failed = Some(io_err.kind());
+            } else {
+                self.failed = Some(io::ErrorKind::Other);
+            }
+            return Err(err);
+        }
+        self.state.apply(&edit);
+
+        if self.size > MAX_MANIFEST_SIZE {
+            *self = Manifest::create(&self.dir, self.state.snapshot())?;
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, edit: &VersionEdit) -> Result<()> {
+        let mut record = vec![0u8; RECORD_LEN_SIZE];
+        edit.encode(&mut record);
+        let len = (record.len() - RECORD_LEN_SIZE) as u64;
+        record[..RECORD_LEN_SIZE].copy_from_slice(&len.to_le_bytes());
+        let crc = compute_crc_data_block(&record);
+        record.extend_from_slice(&crc.to_le_bytes());
+
+        self.writer.write_all(&record)?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.size += record.len() as u64;
+        Ok(())
+    }
+
+    pub(crate) fn state(&self) -> &ManifestState {
+        &self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn file(id: u64, level: u32, min_key: &str, max_key: &str) -> FileMeta {
+        FileMeta {
+            column_family: DEFAULT_COLUMN_FAMILY,
+            id,
+            level,
+            min_key: min_key.as_bytes().to_vec(),
+            max_key: max_key.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn recover_replays_edits_and_ignores_torn_tail() -> Result<()> {
+        let dir = tempdir()?;
+        let mut manifest = Manifest::create(
+            dir.path(),
+            VersionEdit {
+                added: vec![file(1, 0, "a", "m"), file(2, 0, "k", "z")],
+                ..VersionEdit::default()
+            },
+        )?;
+        let mut edit = VersionEdit {
+            added: vec![file(3, 1, "a", "z")],
+            ..VersionEdit::default()
+        };
+        edit.remove_file(1);
+        edit.remove_file(2);
+        edit.set_last_sequence(42);
+        manifest.log(edit)?;
+        drop(manifest);
+        let current = fs::read_to_string(dir.path().join(CURRENT_FILE))?;
+        let path = dir.path().join(current.trim_end());
+
+        // half of the next record made it to disk before a crash
+        let mut f = OpenOptions::new().append(true).open(&path)?;
+        f.write_all(&100u64.to_le_bytes())?;
+        f.write_all(&[TAG_REMOVE_FILE, 3])?;
+        drop(f);
+
+        let state = Manifest::recover(dir.path())?.unwrap();
+        assert_eq!(
+            state.files.values().cloned().collect::<Vec<_>>(),
+            vec![file(3, 1, "a", "z")]
+        );
+        assert_eq!(state.last_sequence, 42);
+        assert!(state.next_file_number > 0);
+
+        // rolling leaves a single manifest behind that holds the same state
+        let rolled = Manifest::create(dir.path(), state.snapshot())?;
+        let manifests = fs::read_dir(dir.path())?
+            .filter(|e| {
+                e.as_ref()
+                    .is_ok_and(|e| e.file_name().to_string_lossy().starts_with(MANIFEST_PREFIX))
+            })
+            .count();
+        assert_eq!(manifests, 1);
+        assert_eq!(rolled.state().files.len(), 1);
+        assert!(!path.exists());
+        Ok(())
+    }
+}
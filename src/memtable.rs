@@ -0,0 +1,358 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use crate::errors::Result;
+use crate::lsm::{Avl, ColumnFamilyId, KvEntry};
+use crate::range_del::RangeTombstone;
+use crate::skiplist::SkipList;
+use crate::wal::{BatchOp, WriteBatch};
+
+const ENTRY_OVERHEAD: u64 = 64; // what a BTreeMap version costs on top of its key and value
+
+// Structure behind the memtables of a column family. All of them keep every version of a key in
+// the same order, they differ in how writes and reads share them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MemtableKind {
+    #[default]
+    Avl,
+    // inserts run next to readers instead of taking the engine exclusively, see Db
+    SkipList,
+    BTreeMap,
+}
+
+impl MemtableKind {
+    pub(crate) fn new_memtable(self) -> Box<dyn Memtable> {
+        match self {
+            MemtableKind::Avl => Box::new(Avl::new()),
+            MemtableKind::SkipList => Box::new(SkipList::new()),
+            MemtableKind::BTreeMap => Box::new(BTreeMemtable::new()),
+        }
+    }
+}
+
+// one version of a key, borrowed from the memtable holding it
+#[derive(Clone, Copy)]
+pub(crate) struct MemtableEntry<'a> {
+    pub(crate) key: &'a [u8],
+    pub(crate) value: &'a [u8],
+    pub(crate) seq: u64,
+    pub(crate) expires_at: u64,
+    pub(crate) deleted: bool,
+    pub(crate) merge_operand: bool,
+}
+
+impl MemtableEntry<'_> {
+    pub(crate) fn to_kv_entry(self) -> KvEntry {
+        KvEntry {
+            key: self.key.to_vec(),
+            value: self.value.to_vec(),
+            seq: self.seq,
+            expires_at: self.expires_at,
+            deleted: self.deleted,
+            value_ptr: false, // values are only separated on flush
+            merge_operand: self.merge_operand,
+        }
+    }
+}
+
+// What the engine needs from a memtable. Every write is a new version, versions are ordered by
+// ascending key and then newest first (see version_cmp) so snapshots can read below newer
+// writes. The same (key, seq) only shows up again on a WAL replay and replaces the first copy.
+pub(crate) trait Memtable: Send + Sync {
+    // expires_at is 0 for values that never expire
+    fn put(&mut self, key: &[u8], value: &[u8], seq: u64, expires_at: u64);
+    fn delete(&mut self, key: &[u8], seq: u64);
+    // operands stay separate versions until a read, flush or compaction folds them
+    fn merge(&mut self, key: &[u8], operand: &[u8], seq: u64);
+    fn delete_range(&mut self, start: &[u8], end: &[u8], seq: u64);
+    fn range_tombstones(&self) -> Vec<RangeTombstone>;
+    // smallest version (by key, then newest first) above the lower bound
+    fn ceiling(&self, lower: Bound<(&[u8], u64)>) -> Option<MemtableEntry<'_>>;
+    // largest version (by key, then newest first) below the upper bound
+    fn floor(&self, upper: Bound<(&[u8], u64)>) -> Option<MemtableEntry<'_>>;
+    // every version (tombstones included) of the keys in the range, in memtable order
+    fn iter<'a>(
+        &'a self,
+        lower: Bound<&'a [u8]>,
+        upper: Bound<&'a [u8]>,
+    ) -> Box<dyn Iterator<Item = MemtableEntry<'a>> + 'a>;
+    // approximate bytes held, the memtable is rotated once it reaches memtable_size
+    fn memory_usage(&self) -> u64;
+    fn is_empty(&self) -> bool;
+
+    // memtables that take writes through a shared reference, see KVEngine::apply_shared
+    fn concurrent(&self) -> Option<&SkipList> {
+        None
+    }
+
+    // newest version of key written at or before read_seq
+    fn get(&self, key: &[u8], read_seq: u64) -> Option<KvEntry> {
+        self.ceiling(Bound::Included((key, read_seq)))
+            .filter(|e| e.key == key)
+            .map(MemtableEntry::to_kv_entry)
+    }
+
+    // operation i of the batch gets first_seq + i, only the ones of column family cf are applied
+    fn apply_batch(&mut self, batch: &WriteBatch, first_seq: u64, cf: ColumnFamilyId) {
+        for (seq, (op_cf, op)) in (first_seq..).zip(batch.ops()) {
+            if *op_cf != cf {
+                continue;
+            }
+            match op {
+                BatchOp::Put(k, v) => self.put(k, v, seq, 0),
+                BatchOp::Delete(k) => self.delete(k, seq),
+            }
+        }
+    }
+
+    // newest version visible at read_seq of the smallest key above lower that has one
+    fn visible_ceiling(&self, lower: Bound<&[u8]>, read_seq: u64) -> Option<MemtableEntry<'_>> {
+        let mut bound = match lower {
+            Bound::Included(k) => Bound::Included((k, u64::MAX)),
+            Bound::Excluded(k) => Bound::Excluded((k, 0)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        loop {
+            let e = self.ceiling(bound)?;
+            if e.seq <= read_seq {
+                return Some(e);
+            }
+            // jump over the versions written after read_seq
+            let visible = self.ceiling(Bound::Included((e.key, read_seq)))?;
+            if visible.key == e.key {
+                return Some(visible);
+            }
+            bound = Bound::Included((visible.key, u64::MAX));
+        }
+    }
+
+    // newest version visible at read_seq of the largest key below upper that has one
+    fn visible_floor(&self, upper: Bound<&[u8]>, read_seq: u64) -> Option<MemtableEntry<'_>> {
+        let mut bound = match upper {
+            Bound::Included(k) => Bound::Included((k, 0)),
+            Bound::Excluded(k) => Bound::Excluded((k, u64::MAX)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        loop {
+            // lands on the oldest version of the key
+            let e = self.floor(bound)?;
+            if e.seq <= read_seq {
+                return self.ceiling(Bound::Included((e.key, read_seq)));
+            }
+            // even the oldest version is too new, so the whole key is invisible
+            bound = Bound::Excluded((e.key, u64::MAX));
+        }
+    }
+
+    // hands the versions of every key, newest first, to write_key in key order
+    fn for_each_key(
+        &self,
+        write_key: &mut dyn FnMut(&mut Vec<KvEntry>) -> Result<()>,
+    ) -> Result<()> {
+        let mut versions: Vec<KvEntry> = Vec::new();
+        for e in self.iter(Bound::Unbounded, Bound::Unbounded) {
+            if let Some(last) = versions.last() {
+                // a version replayed twice, the first copy is the one readers see
+                if last.key == e.key && last.seq == e.seq {
+                    continue;
+                }
+                if last.key != e.key {
+                    write_key(&mut versions)?;
+                    versions.clear();
+                }
+            }
+            versions.push(e.to_kv_entry());
+        }
+        if !versions.is_empty() {
+            write_key(&mut versions)?;
+        }
+        Ok(())
+    }
+}
+
+struct Version {
+    value: Vec<u8>,
+    expires_at: u64,
+    deleted: bool,
+    merge_operand: bool,
+}
+
+// Memtable on the standard library BTreeMap, a baseline to measure the others against.
+// Reverse puts the newest version of a key first.
+pub(crate) struct BTreeMemtable {
+    versions: BTreeMap<(Vec<u8>, Reverse<u64>), Version>,
+    size: u64,
+    range_tombstones: Vec<RangeTombstone>,
+}
+
+impl BTreeMemtable {
+    pub(crate) fn new() -> Self {
+        Self {
+            versions: BTreeMap::new(),
+            size: 0,
+            range_tombstones: Vec::new(),
+        }
+    }
+
+    fn insert(&mut self, key: &[u8], value: &[u8], seq: u64, version: (u64, bool, bool)) {
+        let (expires_at, deleted, merge_operand) = version;
+        self.size += (key.len() + value.len()) as u64 + ENTRY_OVERHEAD;
+        self.versions.insert(
+            (key.to_vec(), Reverse(seq)),
+            Version {
+                value: value.to_vec(),
+                expires_at,
+                deleted,
+                merge_operand,
+            },
+        );
+    }
+}
+
+fn map_key(bound: Bound<(&[u8], u64)>) -> Bound<(Vec<u8>, Reverse<u64>)> {
+    bound.map(|(k, seq)| (k.to_vec(), Reverse(seq)))
+}
+
+fn to_entry<'a>(
+    ((key, Reverse(seq)), v): (&'a (Vec<u8>, Reverse<u64>), &'a Version),
+) -> MemtableEntry<'a> {
+    MemtableEntry {
+        key,
+        value: &v.value,
+        seq: *seq,
+        expires_at: v.expires_at,
+        deleted: v.deleted,
+        merge_operand: v.merge_operand,
+    }
+}
+
+impl Memtable for BTreeMemtable {
+    fn put(&mut self, key: &[u8], value: &[u8], seq: u64, expires_at: u64) {
+        self.insert(key, value, seq, (expires_at, false, false));
+    }
+
+    fn delete(&mut self, key: &[u8], seq: u64) {
+        self.insert(key, &[], seq, (0, true, false));
+    }
+
+    fn merge(&mut self, key: &[u8], operand: &[u8], seq: u64) {
+        self.insert(key, operand, seq, (0, false, true));
+    }
+
+    fn delete_range(&mut self, start: &[u8], end: &[u8], seq: u64) {
+        self.range_tombstones.push(RangeTombstone {
+            start: start.to_vec(),
+            end: end.to_vec(),
+            seq,
+        });
+        self.size += (start.len() + end.len()) as u64;
+    }
+
+    fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        self.range_tombstones.clone()
+    }
+
+    fn ceiling(&self, lower: Bound<(&[u8], u64)>) -> Option<MemtableEntry<'_>> {
+        self.versions
+            .range((map_key(lower), Bound::Unbounded))
+            .next()
+            .map(to_entry)
+    }
+
+    fn floor(&self, upper: Bound<(&[u8], u64)>) -> Option<MemtableEntry<'_>> {
+        self.versions
+            .range((Bound::Unbounded, map_key(upper)))
+            .next_back()
+            .map(to_entry)
+    }
+
+    fn iter<'a>(
+        &'a self,
+        lower: Bound<&'a [u8]>,
+        upper: Bound<&'a [u8]>,
+    ) -> Box<dyn Iterator<Item = MemtableEntry<'a>> + 'a> {
+        let lower = match lower {
+            Bound::Included(k) => Bound::Included((k, u64::MAX)),
+            Bound::Excluded(k) => Bound::Excluded((k, 0)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        // taking the upper bound as a filter keeps an empty range from panicking
+        Box::new(
+            self.versions
+                .range((map_key(lower), Bound::Unbounded))
+                .map(to_entry)
+                .take_while(move |e| match upper {
+                    Bound::Included(k) => e.key <= k,
+                    Bound::Excluded(k) => e.key < k,
+                    Bound::Unbounded => true,
+                }),
+        )
+    }
+
+    fn memory_usage(&self) -> u64 {
+        self.size
+    }
+
+    fn is_empty(&self) -> bool {
+        self.versions.is_empty() && self.range_tombstones.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_kind_orders_and_resolves_versions_alike() {
+        for kind in [
+            MemtableKind::Avl,
+            MemtableKind::SkipList,
+            MemtableKind::BTreeMap,
+        ] {
+            let mut memtable = kind.new_memtable();
+            memtable.put(b"b", b"1", 1, 0);
+            memtable.put(b"a", b"1", 2, 0);
+            memtable.put(b"b", b"2", 3, 0);
+            memtable.delete(b"c", 4);
+            memtable.merge(b"a", b"+", 5);
+            // replayed from the WAL a second time
+            memtable.put(b"b", b"2", 3, 0);
+
+            let versions: Vec<(&[u8], u64)> = memtable
+                .iter(Bound::Unbounded, Bound::Unbounded)
+                .map(|e| (e.key, e.seq))
+                .collect();
+            assert!(
+                versions.starts_with(&[(b"a", 5), (b"a", 2), (b"b", 3)]),
+                "{kind:?}"
+            );
+            assert_eq!(versions.last(), Some(&(b"c".as_slice(), 4)), "{kind:?}");
+
+            assert_eq!(memtable.get(b"b", 2).unwrap().value, b"1", "{kind:?}");
+            assert_eq!(
+                memtable.get(b"b", u64::MAX).unwrap().value,
+                b"2",
+                "{kind:?}"
+            );
+            assert!(memtable.get(b"c", u64::MAX).unwrap().deleted, "{kind:?}");
+            assert!(memtable.get(b"c", 3).is_none(), "{kind:?}");
+            let last = memtable.visible_floor(Bound::Unbounded, 3).unwrap();
+            assert_eq!((last.key, last.seq), (b"b".as_slice(), 3), "{kind:?}");
+
+            let mut keys = Vec::new();
+            memtable
+                .for_each_key(&mut |versions| {
+                    keys.push((versions[0].key.clone(), versions.len()));
+                    Ok(())
+                })
+                .unwrap();
+            assert_eq!(
+                keys,
+                vec![(b"a".to_vec(), 2), (b"b".to_vec(), 2), (b"c".to_vec(), 1)],
+                "{kind:?}"
+            );
+            assert!(memtable.memory_usage() > 0, "{kind:?}");
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use crate::errors::{DbError, Result};
+use crate::lsm::KvEntry;
+use crate::range_del::RangeTombstone;
+
+// Combines a merge operand with the value of its key, so read-modify-write (counters, appends)
+// needs no read. Registered with KVEngine::set_merge_operator, tables written with one operator
+// must always be opened with the same one.
+pub trait MergeOperator: Send + Sync {
+    // existing is None when the key has no value. Operands are applied one at a time, oldest first
+    fn merge(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8>;
+}
+
+// value of key once the operands (newest first) are applied on top of existing
+pub(crate) fn apply_operands(
+    operator: Option<&dyn MergeOperator>,
+    key: &[u8],
+    existing: Option<Vec<u8>>,
+    operands: &[Vec<u8>],
+) -> Result<Option<Vec<u8>>> {
+    if operands.is_empty() {
+        return Ok(existing);
+    }
+    let operator = operator.ok_or(DbError::MissingMergeOperator)?;
+    Ok(operands.iter().rev().fold(existing, |value, operand| {
+        Some(operator.merge(key, value.as_deref(), operand))
+    }))
+}
+
+// Rewrites operands into the value readers see at them wherever everything below is known.
+// versions holds every version of one key, newest first. nothing_below is set when no older
+// version exists outside of them. Only operands picked by materialize are rewritten, the others
+// stay as they are and still need the versions below them.
+pub(crate) fn collapse(
+    operator: &dyn MergeOperator,
+    versions: &mut [KvEntry],
+    nothing_below: bool,
+    range_tombstones: &[RangeTombstone],
+    now: u64,
+    materialize: impl Fn(usize) -> bool,
+) {
+    // value under the current version, None while it is unknown: it lives outside of versions,
+    // in the value log, or has an expiry that would change the result later on
+    let mut below: Option<Option<Vec<u8>>> = nothing_below.then_some(None);
+    let mut older_seq = 0;
+    for i in (0..versions.len()).rev() {
+        let entry = &mut versions[i];
+        // a range tombstone written in between hides everything below
+        if range_tombstones
+            .iter()
+            .any(|t| t.covers(&entry.key, older_seq) && t.seq < entry.seq)
+        {
+            below = Some(None);
+        }
+        older_seq = entry.seq;
+
+        below = if entry.deleted || entry.is_expired(now) {
+            Some(None)
+        } else if entry.value_ptr || entry.expires_at != 0 {
+            None
+        } else if !entry.merge_operand {
+            Some(Some(entry.value.clone()))
+        } else {
+            below.map(|existing| {
+                let merged = operator.merge(&entry.key, existing.as_deref(), &entry.value);
+                if materialize(i) {
+                    entry.value.clone_from(&merged);
+                    entry.merge_operand = false;
+                }
+                Some(merged)
+            })
+        };
+    }
+}
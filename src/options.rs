@@ -0,0 +1,495 @@
+use std::fs::{self, File, rename};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::compact::{CompactionStrategy, LeveledCompaction, SizeTieredCompaction};
+use crate::compression::{Codecs, CompressionCodec, CompressionType};
+use crate::errors::{DbError, Result};
+use crate::lsm::{
+    BLOCK_CODEC_MASK, BLOOM_BITS_PER_KEY, ColumnFamilyOptions, DATA_BLOCK, ENTRY_HEADER_SIZE,
+    KEY_MAX_BYTES_SIZE, MAX_BLOCK_SIZE, MAX_FILE_SIZE, MAX_PENDING_MEMTABLES, MEMTABLE_THRESHOLD,
+    SLOWDOWN_PENDING_MEMTABLES, TableOptions, VALUE_LOG_THRESHOLD, VALUE_MAX_BYTES_SIZE,
+};
+use crate::memtable::MemtableKind;
+use crate::vlog::POINTER_SIZE;
+use crate::wal::{RecoveryMode, SyncConfig};
+
+const OPTIONS_FILE: &str = "OPTIONS";
+
+// Tuning of an LSM engine, fixed while it is open. Start from Options::default() and chain the
+// setters, KVEngine::open validates the result and keeps a copy in the OPTIONS file of the
+// data directory so a later open can tell when the tree on disk no longer fits.
+#[derive(Clone)]
+pub struct Options {
+    pub(crate) sync: SyncConfig,
+    pub(crate) recovery: RecoveryMode,
+    pub(crate) compression: CompressionType,
+    pub(crate) codecs: Vec<Arc<dyn CompressionCodec>>, // registered beside the built-in codecs
+    pub(crate) compaction: CompactionStrategy,
+    pub(crate) memtable: MemtableKind,
+    pub(crate) memtable_size: u64, // bytes a memtable (and its WAL) takes before it is flushed
+    pub(crate) max_pending_memtables: usize, // frozen memtables waiting on a flush before writes stop
+    pub(crate) slowdown_pending_memtables: usize, // from here on every write is delayed a little
+    pub(crate) max_file_size: u64,           // compaction cuts its outputs past this
+    pub(crate) block_size: u64,              // a data block is closed once it grows past this
+    pub(crate) max_block_size: u64,          // anything longer on disk is read as corruption
+    pub(crate) max_key_size: u64,
+    pub(crate) max_value_size: u64,
+    pub(crate) bloom_bits_per_key: u64,
+    pub(crate) value_log_threshold: u64, // longer values are moved to the value log on flush
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            sync: SyncConfig::Always,
+            recovery: RecoveryMode::default(),
+            compression: CompressionType::default(),
+            codecs: Vec::new(),
+            compaction: CompactionStrategy::default(),
+            memtable: MemtableKind::default(),
+            memtable_size: MEMTABLE_THRESHOLD,
+            max_pending_memtables: MAX_PENDING_MEMTABLES,
+            slowdown_pending_memtables: SLOWDOWN_PENDING_MEMTABLES,
+            max_file_size: MAX_FILE_SIZE,
+            block_size: DATA_BLOCK,
+            max_block_size: MAX_BLOCK_SIZE,
+            max_key_size: KEY_MAX_BYTES_SIZE,
+            max_value_size: VALUE_MAX_BYTES_SIZE,
+            bloom_bits_per_key: BLOOM_BITS_PER_KEY,
+            value_log_threshold: VALUE_LOG_THRESHOLD,
+        }
+    }
+}
+
+impl Options {
+    pub fn sync(mut self, sync: SyncConfig) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    pub fn memtable(mut self, kind: MemtableKind) -> Self {
+        self.memtable = kind;
+        self
+    }
+
+    pub fn memtable_size(mut self, bytes: u64) -> Self {
+        self.memtable_size = bytes;
+        self
+    }
+
+    pub fn max_pending_memtables(mut self, count: usize) -> Self {
+        self.max_pending_memtables = count;
+        self
+    }
+
+    pub fn slowdown_pending_memtables(mut self, count: usize) -> Self {
+        self.slowdown_pending_memtables = count;
+        self
+    }
+
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = bytes;
+        self
+    }
+
+    pub fn block_size(mut self, bytes: u64) -> Self {
+        self.block_size = bytes;
+        self
+    }
+
+    pub fn max_block_size(mut self, bytes: u64) -> Self {
+        self.max_block_size = bytes;
+        self
+    }
+
+    pub fn max_key_size(mut self, bytes: u64) -> Self {
+        self.max_key_size = bytes;
+        self
+    }
+
+    pub fn max_value_size(mut self, bytes: u64) -> Self {
+        self.max_value_size = bytes;
+        self
+    }
+
+    pub fn bloom_bits_per_key(mut self, bits: u64) -> Self {
+        self.bloom_bits_per_key = bits;
+        self
+    }
+
+    pub fn value_log_threshold(mut self, bytes: u64) -> Self {
+        self.value_log_threshold = bytes;
+        self
+    }
+
+    pub fn recovery(mut self, recovery: RecoveryMode) -> Self {
+        self.recovery = recovery;
+        self
+    }
+
+    pub fn compression(mut self, compression: CompressionType) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    // makes CompressionType::Custom(codec.tag()) usable, and tables written with it readable
+    pub fn codec(mut self, codec: Arc<dyn CompressionCodec>) -> Self {
+        self.codecs.push(codec);
+        self
+    }
+
+    pub fn compaction(mut self, compaction: CompactionStrategy) -> Self {
+        self.compaction = compaction;
+        self
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        let invalid = |reason: String| Err(DbError::InvalidOptions(reason));
+        for (name, value) in [
+            ("memtable_size", self.memtable_size),
+            ("max_file_size", self.max_file_size),
+            ("block_size", self.block_size),
+            ("max_key_size", self.max_key_size),
+            ("max_value_size", self.max_value_size),
+            ("bloom_bits_per_key", self.bloom_bits_per_key),
+            ("max_pending_memtables", self.max_pending_memtables as u64),
+            (
+                "slowdown_pending_memtables",
+                self.slowdown_pending_memtables as u64,
+            ),
+        ] {
+            if value == 0 {
+                return invalid(format!("{name} has to be greater than 0"));
+            }
+        }
+        if self.slowdown_pending_memtables > self.max_pending_memtables {
+            return invalid(format!(
+                "slowdown_pending_memtables {} is past max_pending_memtables {}",
+                self.slowdown_pending_memtables, self.max_pending_memtables
+            ));
+        }
+        // a separated value is replaced by its pointer, and no value is longer than max_value_size
+        if self.value_log_threshold < POINTER_SIZE as u64
+            || self.value_log_threshold > self.max_value_size
+        {
+            return invalid(format!(
+                "value_log_threshold {} has to be between {} and max_value_size {}",
+                self.value_log_threshold, POINTER_SIZE, self.max_value_size
+            ));
+        }
+        if let SyncConfig::Every(0) = self.sync {
+            return invalid("sync interval has to be greater than 0 ms".to_string());
+        }
+        // a block is only closed after the record that takes it past block_size
+        let largest_block = self
+            .block_size
+            .saturating_add(ENTRY_HEADER_SIZE as u64)
+            .saturating_add(self.max_key_size)
+            .saturating_add(self.max_value_size);
+        if largest_block > self.max_block_size {
+            return invalid(format!(
+                "max_block_size {} cannot hold a block of {} bytes",
+                self.max_block_size, largest_block
+            ));
+        }
+        // block offsets and prefix compressed record lengths are u32
+        if self.max_block_size > u32::MAX as u64 {
+            return invalid(format!(
+                "max_block_size {} does not fit in 32 bits",
+                self.max_block_size
+            ));
+        }
+        for (i, codec) in self.codecs.iter().enumerate() {
+            let tag = codec.tag();
+            if Codecs::is_builtin(tag) || tag > BLOCK_CODEC_MASK {
+                return invalid(format!(
+                    "codec tag {tag} has to be between 2 and {BLOCK_CODEC_MASK}"
+                ));
+            }
+            if self.codecs[..i].iter().any(|other| other.tag() == tag) {
+                return invalid(format!("codec tag {tag} is registered twice"));
+            }
+        }
+        self.check_compression(self.compression)
+    }
+
+    // a custom codec has to be registered before anything can be written with it
+    pub(crate) fn check_compression(&self, compression: CompressionType) -> Result<()> {
+        match compression {
+            CompressionType::Custom(tag) if !self.codecs.iter().any(|codec| codec.tag() == tag) => {
+                Err(DbError::InvalidOptions(format!(
+                    "compression uses codec tag {tag}, which is not registered"
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // options of the default column family and of the families found on disk
+    pub(crate) fn column_family_options(&self) -> ColumnFamilyOptions {
+        let mut compaction = self.compaction.clone();
+        if let CompactionStrategy::Leveled(leveled) = &mut compaction {
+            leveled.target_file_size = self.max_file_size;
+        }
+        ColumnFamilyOptions {
+            compaction,
+            compression: self.compression,
+            memtable_threshold: self.memtable_size,
+            memtable: self.memtable,
+        }
+    }
+
+    pub(crate) fn table_options(&self, compression: CompressionType) -> TableOptions {
+        TableOptions {
+            compression,
+            codecs: Codecs::new(&self.codecs),
+            block_size: self.block_size,
+            max_block_size: self.max_block_size,
+            bloom_bits_per_key: self.bloom_bits_per_key,
+        }
+    }
+
+    // What is on disk was checked against the limits it was written with, lowering one could
+    // turn valid records into corruption. Everything else can change between two opens.
+    pub(crate) fn check_compatible(&self, stored: &Options) -> Result<()> {
+        for (name, stored, current) in [
+            ("max_key_size", stored.max_key_size, self.max_key_size),
+            ("max_value_size", stored.max_value_size, self.max_value_size),
+            ("max_block_size", stored.max_block_size, self.max_block_size),
+        ] {
+            if current < stored {
+                return Err(DbError::InvalidOptions(format!(
+                    "{name} cannot shrink from {stored} to {current} once data was written"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // one name=value line per persisted option
+    fn encode(&self) -> String {
+        let sync = match self.sync {
+            SyncConfig::None => "none".to_string(),
+            SyncConfig::Every(ms) => format!("every {ms}"),
+            SyncConfig::Always => "always".to_string(),
+        };
+        let recovery = match self.recovery {
+            RecoveryMode::Fail => "fail",
+            RecoveryMode::TruncateTail => "truncate_tail",
+            RecoveryMode::SkipCorrupted => "skip_corrupted",
+        };
+        [
+            ("sync", sync),
+            ("recovery", recovery.to_string()),
+            ("compression", compression_name(self.compression)),
+            ("compaction", compaction_name(&self.compaction).to_string()),
+            ("memtable", memtable_name(self.memtable).to_string()),
+            ("memtable_size", self.memtable_size.to_string()),
+            (
+                "max_pending_memtables",
+                self.max_pending_memtables.to_string(),
+            ),
+            (
+                "slowdown_pending_memtables",
+                self.slowdown_pending_memtables.to_string(),
+            ),
+            ("max_file_size", self.max_file_size.to_string()),
+            ("block_size", self.block_size.to_string()),
+            ("max_block_size", self.max_block_size.to_string()),
+            ("max_key_size", self.max_key_size.to_string()),
+            ("max_value_size", self.max_value_size.to_string()),
+            ("bloom_bits_per_key", self.bloom_bits_per_key.to_string()),
+            ("value_log_threshold", self.value_log_threshold.to_string()),
+        ]
+        .iter()
+        .map(|(name, value)| format!("{name}={value}\n"))
+        .collect()
+    }
+
+    // options the file was written with, unknown names are left at their defaults
+    fn decode(s: &str) -> Option<Options> {
+        let mut options = Options::default();
+        for line in s.lines().filter(|l| !l.is_empty()) {
+            let (name, value) = line.split_once('=')?;
+            let size = || value.parse::<u64>().ok();
+            match name {
+                "sync" => {
+                    options.sync = match value {
+                        "none" => SyncConfig::None,
+                        "always" => SyncConfig::Always,
+                        _ => SyncConfig::Every(value.strip_prefix("every ")?.parse().ok()?),
+                    }
+                }
+                "memtable" => options.memtable = parse_memtable(value)?,
+                "recovery" => {
+                    options.recovery = match value {
+                        "fail" => RecoveryMode::Fail,
+                        "truncate_tail" => RecoveryMode::TruncateTail,
+                        "skip_corrupted" => RecoveryMode::SkipCorrupted,
+                        _ => return None,
+                    }
+                }
+                "compression" => options.compression = parse_compression(value)?,
+                "compaction" => options.compaction = parse_compaction(value)?,
+                "memtable_size" => options.memtable_size = size()?,
+                "max_pending_memtables" => options.max_pending_memtables = value.parse().ok()?,
+                "slowdown_pending_memtables" => {
+                    options.slowdown_pending_memtables = value.parse().ok()?
+                }
+                "max_file_size" => options.max_file_size = size()?,
+                "block_size" => options.block_size = size()?,
+                "max_block_size" => options.max_block_size = size()?,
+                "max_key_size" => options.max_key_size = size()?,
+                "max_value_size" => options.max_value_size = size()?,
+                "bloom_bits_per_key" => options.bloom_bits_per_key = size()?,
+                "value_log_threshold" => options.value_log_threshold = size()?,
+                _ => {}
+            }
+        }
+        Some(options)
+    }
+
+    // None for directories written before the OPTIONS file existed
+    pub(crate) fn load(dir: &Path) -> Result<Option<Options>> {
+        let path = dir.join(OPTIONS_FILE);
+        let s = match fs::read_to_string(&path) {
+            Ok(s) => s,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        Options::decode(&s)
+            .map(Some)
+            .ok_or_else(|| DbError::FileError("OPTIONS file is malformed".to_string(), path))
+    }
+
+    // replaced by a rename like CURRENT, a crash leaves either the old or the new file
+    pub(crate) fn persist(&self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(format!("{}.tmp", OPTIONS_FILE));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(self.encode().as_bytes())?;
+        file.sync_all()?;
+        rename(&tmp_path, dir.join(OPTIONS_FILE))?;
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+impl ColumnFamilyOptions {
+    // one name=value line per option like the OPTIONS file, the MANIFEST keeps it with the family
+    pub(crate) fn encode(&self) -> String {
+        [
+            ("compaction", compaction_name(&self.compaction).to_string()),
+            ("compression", compression_name(self.compression)),
+            ("memtable", memtable_name(self.memtable).to_string()),
+            ("memtable_threshold", self.memtable_threshold.to_string()),
+        ]
+        .iter()
+        .map(|(name, value)| format!("{name}={value}\n"))
+        .collect()
+    }
+
+    // unknown names are left at their defaults
+    pub(crate) fn decode(s: &str) -> Option<ColumnFamilyOptions> {
+        let mut options = ColumnFamilyOptions::default();
+        for line in s.lines().filter(|l| !l.is_empty()) {
+            let (name, value) = line.split_once('=')?;
+            match name {
+                "compaction" => options.compaction = parse_compaction(value)?,
+                "compression" => options.compression = parse_compression(value)?,
+                "memtable" => options.memtable = parse_memtable(value)?,
+                "memtable_threshold" => options.memtable_threshold = value.parse().ok()?,
+                _ => {}
+            }
+        }
+        Some(options)
+    }
+}
+
+fn memtable_name(memtable: MemtableKind) -> &'static str {
+    match memtable {
+        MemtableKind::Avl => "avl",
+        MemtableKind::SkipList => "skiplist",
+        MemtableKind::BTreeMap => "btree",
+    }
+}
+
+fn parse_memtable(value: &str) -> Option<MemtableKind> {
+    match value {
+        "avl" => Some(MemtableKind::Avl),
+        "skiplist" => Some(MemtableKind::SkipList),
+        "btree" => Some(MemtableKind::BTreeMap),
+        _ => None,
+    }
+}
+
+// a custom codec is kept by its tag, the codec itself has to be registered again on open
+fn compression_name(compression: CompressionType) -> String {
+    match compression {
+        CompressionType::None => "none".to_string(),
+        CompressionType::Lz4 => "lz4".to_string(),
+        CompressionType::Custom(tag) => format!("custom {tag}"),
+    }
+}
+
+fn parse_compression(value: &str) -> Option<CompressionType> {
+    match value {
+        "none" => Some(CompressionType::None),
+        "lz4" => Some(CompressionType::Lz4),
+        _ => Some(CompressionType::Custom(
+            value.strip_prefix("custom ")?.parse().ok()?,
+        )),
+    }
+}
+
+// the tuning of a strategy is not configurable, its kind is all there is to keep
+fn compaction_name(compaction: &CompactionStrategy) -> &'static str {
+    match compaction {
+        CompactionStrategy::Leveled(_) => "leveled",
+        CompactionStrategy::SizeTiered(_) => "size_tiered",
+    }
+}
+
+fn parse_compaction(value: &str) -> Option<CompactionStrategy> {
+    match value {
+        "leveled" => Some(CompactionStrategy::Leveled(LeveledCompaction::default())),
+        "size_tiered" => Some(CompactionStrategy::SizeTiered(
+            SizeTieredCompaction::default(),
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn options_round_trip_through_the_options_file() -> Result<()> {
+        let dir = tempdir()?;
+        Options::default()
+            .sync(SyncConfig::Every(250))
+            .memtable(MemtableKind::SkipList)
+            .recovery(RecoveryMode::SkipCorrupted)
+            .compression(CompressionType::Lz4)
+            .compaction(CompactionStrategy::SizeTiered(
+                SizeTieredCompaction::default(),
+            ))
+            .block_size(1024)
+            .persist(dir.path())?;
+
+        let loaded = Options::load(dir.path())?.unwrap();
+        assert!(matches!(loaded.sync, SyncConfig::Every(250)));
+        assert_eq!(loaded.memtable, MemtableKind::SkipList);
+        assert_eq!(loaded.recovery, RecoveryMode::SkipCorrupted);
+        assert_eq!(loaded.compression, CompressionType::Lz4);
+        assert!(matches!(
+            loaded.compaction,
+            CompactionStrategy::SizeTiered(_)
+        ));
+        assert_eq!(loaded.block_size, 1024);
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+use crate::errors::CorruptionType;
+use crate::helpers::compute_crc_data_block;
+
+// Deletes every key in [start, end) written before seq. Lives next to the point entries of a
+// memtable or SSTable and is checked against every version read from any source.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct RangeTombstone {
+    pub(crate) start: Vec<u8>,
+    pub(crate) end: Vec<u8>,
+    pub(crate) seq: u64,
+}
+
+impl RangeTombstone {
+    // whether the version of key written at seq is hidden by this tombstone
+    pub(crate) fn covers(&self, key: &[u8], seq: u64) -> bool {
+        seq < self.seq && self.start.as_slice() <= key && key < self.end.as_slice()
+    }
+
+    // the part of the tombstone inside [lower, upper), None bounds are open
+    pub(crate) fn clip(&self, lower: Option<&[u8]>, upper: Option<&[u8]>) -> Option<Self> {
+        let start = lower.map_or(&self.start[..], |l| l.max(&self.start[..]));
+        let end = upper.map_or(&self.end[..], |u| u.min(&self.end[..]));
+        (start < end).then(|| Self {
+            start: start.to_vec(),
+            end: end.to_vec(),
+            seq: self.seq,
+        })
+    }
+}
+
+pub(crate) fn is_covered(tombstones: &[RangeTombstone], key: &[u8], seq: u64) -> bool {
+    tombstones.iter().any(|t| t.covers(key, seq))
+}
+
+// SSTable block holding the range tombstones of the table:
+// [ seq(8) | start_sz(8) | end_sz(8) | start | end ] ... crc(4)
+pub(crate) fn encode_block(tombstones: &[RangeTombstone]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for t in tombstones {
+        block.extend_from_slice(&t.seq.to_le_bytes());
+        block.extend_from_slice(&(t.start.len() as u64).to_le_bytes());
+        block.extend_from_slice(&(t.end.len() as u64).to_le_bytes());
+        block.extend_from_slice(&t.start);
+        block.extend_from_slice(&t.end);
+    }
+    let crc = compute_crc_data_block(&block);
+    block.extend_from_slice(&crc.to_le_bytes());
+    block
+}
+
+pub(crate) fn decode_block(b: &[u8]) -> Result<Vec<RangeTombstone>, CorruptionType> {
+    let Some(body_len) = b.len().checked_sub(4) else {
+        return Err(CorruptionType::LengthMismatch {
+            expected: 4,
+            found: b.len(),
+        });
+    };
+    let (body, crc) = b.split_at(body_len);
+    let expected = u32::from_le_bytes(crc.try_into().unwrap());
+    let found = compute_crc_data_block(body);
+    if expected != found {
+        return Err(CorruptionType::CrcMismatch { expected, found });
+    }
+
+    let truncated =
+        |pos: usize| CorruptionType::Other(format!("truncated range tombstone at {pos}"));
+    let mut tombstones = Vec::new();
+    let mut pos = 0;
+    while pos < body.len() {
+        let header = body.get(pos..pos + 24).ok_or_else(|| truncated(pos))?;
+        let u64_at = |i: usize| u64::from_le_bytes(header[i..i + 8].try_into().unwrap());
+        let seq = u64_at(0);
+        let start_end = usize::try_from(u64_at(8))
+            .ok()
+            .and_then(|len| (pos + 24).checked_add(len))
+            .ok_or_else(|| truncated(pos))?;
+        let end_end = usize::try_from(u64_at(16))
+            .ok()
+            .and_then(|len| start_end.checked_add(len))
+            .ok_or_else(|| truncated(pos))?;
+        let start = body
+            .get(pos + 24..start_end)
+            .ok_or_else(|| truncated(pos))?;
+        let end = body.get(start_end..end_end).ok_or_else(|| truncated(pos))?;
+        tombstones.push(RangeTombstone {
+            start: start.to_vec(),
+            end: end.to_vec(),
+            seq,
+        });
+        pos = end_end;
+    }
+    Ok(tombstones)
+}
@@ -0,0 +1,416 @@
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock, RwLock};
+
+use crate::lsm::{ColumnFamilyId, version_cmp};
+use crate::memtable::{Memtable, MemtableEntry};
+use crate::range_del::RangeTombstone;
+use crate::wal::{BatchOp, WriteBatch};
+
+const MAX_HEIGHT: usize = 12;
+const BRANCHING: u64 = 4; // a node reaches the next level with probability 1/BRANCHING
+const FIRST_CHUNK_SIZE: usize = 256;
+const MAX_CHUNKS: usize = 24; // chunk i holds FIRST_CHUNK_SIZE << i nodes, together about u32::MAX
+const NIL: u32 = 0; // ends a list, ids of real nodes start at 1
+const NODE_OVERHEAD: u64 = 80; // what a node costs on top of its key and value
+
+// One version of a key. Key and value share a single allocation.
+pub(crate) struct SkipEntry {
+    data: Box<[u8]>, // key | value
+    key_len: usize,
+    pub(crate) seq: u64,
+    pub(crate) expires_at: u64,
+    pub(crate) deleted: bool,
+    pub(crate) merge_operand: bool,
+}
+
+impl SkipEntry {
+    pub(crate) fn key(&self) -> &[u8] {
+        &self.data[..self.key_len]
+    }
+
+    pub(crate) fn value(&self) -> &[u8] {
+        &self.data[self.key_len..]
+    }
+
+    fn as_entry(&self) -> MemtableEntry<'_> {
+        MemtableEntry {
+            key: self.key(),
+            value: self.value(),
+            seq: self.seq,
+            expires_at: self.expires_at,
+            deleted: self.deleted,
+            merge_operand: self.merge_operand,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Node {
+    entry: OnceLock<SkipEntry>, // set before the node is linked anywhere
+    next: [AtomicU32; MAX_HEIGHT],
+}
+
+// Nodes are handed out from chunks that are allocated once and never move, so a reader can
+// hold on to a node while the writer keeps adding more. Chunks double in size, a small
+// memtable only pays for the first one.
+struct Arena {
+    chunks: [OnceLock<Box<[Node]>>; MAX_CHUNKS],
+}
+
+impl Arena {
+    fn new() -> Self {
+        Self {
+            chunks: [const { OnceLock::new() }; MAX_CHUNKS],
+        }
+    }
+
+    // chunk and offset of a node id
+    fn locate(id: u32) -> (usize, usize) {
+        let i = id as usize - 1;
+        let chunk = (i / FIRST_CHUNK_SIZE + 1).ilog2() as usize;
+        (chunk, i - FIRST_CHUNK_SIZE * ((1 << chunk) - 1))
+    }
+
+    fn node(&self, id: u32) -> &Node {
+        let (chunk, offset) = Self::locate(id);
+        let nodes = self.chunks[chunk]
+            .get()
+            .expect("a linked node lives in an allocated chunk");
+        &nodes[offset]
+    }
+
+    // only called by the writer, with an id no node was given yet
+    fn alloc(&self, id: u32, entry: SkipEntry) -> &Node {
+        let (chunk, offset) = Self::locate(id);
+        let nodes = self.chunks[chunk].get_or_init(|| {
+            (0..FIRST_CHUNK_SIZE << chunk)
+                .map(|_| Node::default())
+                .collect()
+        });
+        let node = &nodes[offset];
+        if node.entry.set(entry).is_err() {
+            unreachable!("node ids are handed out once");
+        }
+        node
+    }
+}
+
+struct Writer {
+    next_id: u32,
+    rng: u64, // xorshift state for node heights
+}
+
+impl Writer {
+    fn random_height(&mut self) -> usize {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let mut bits = self.rng;
+        let mut height = 1;
+        while height < MAX_HEIGHT && bits.is_multiple_of(BRANCHING) {
+            height += 1;
+            bits /= BRANCHING;
+        }
+        height
+    }
+}
+
+// Memtable in the same order as the AVL (ascending key, newest version first) that can be read
+// while it is written to. Inserts are serialized on the writer lock and publish a node with a
+// release store on each level, bottom up. Readers never lock, they follow the links with
+// acquire loads and only ever see fully written nodes.
+pub(crate) struct SkipList {
+    arena: Arena,
+    head: [AtomicU32; MAX_HEIGHT],
+    writer: Mutex<Writer>,
+    size: AtomicU64, // approximate bytes held
+    range_tombstones: RwLock<Vec<RangeTombstone>>,
+}
+
+impl SkipList {
+    pub(crate) fn new() -> Self {
+        Self {
+            arena: Arena::new(),
+            head: Default::default(),
+            writer: Mutex::new(Writer {
+                next_id: 1,
+                rng: 0x9E37_79B9_7F4A_7C15,
+            }),
+            size: AtomicU64::new(0),
+            range_tombstones: RwLock::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn size(&self) -> u64 {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.head[0].load(Ordering::Acquire) == NIL
+            && self.range_tombstones.read().unwrap().is_empty()
+    }
+
+    fn link(&self, id: u32, level: usize) -> &AtomicU32 {
+        match id {
+            NIL => &self.head[level],
+            _ => &self.arena.node(id).next[level],
+        }
+    }
+
+    fn entry(&self, id: u32) -> &SkipEntry {
+        self.arena
+            .node(id)
+            .entry
+            .get()
+            .expect("a node is written before it is linked")
+    }
+
+    // last node on every level that still sorts before, NIL standing for the head
+    fn seek(&self, before: impl Fn(&SkipEntry) -> bool) -> [u32; MAX_HEIGHT] {
+        let mut preds = [NIL; MAX_HEIGHT];
+        let mut x = NIL;
+        for level in (0..MAX_HEIGHT).rev() {
+            loop {
+                let next = self.link(x, level).load(Ordering::Acquire);
+                if next == NIL || !before(self.entry(next)) {
+                    break;
+                }
+                x = next;
+            }
+            preds[level] = x;
+        }
+        preds
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8], seq: u64, expires_at: u64, flags: (bool, bool)) {
+        let (deleted, merge_operand) = flags;
+        let mut writer = self.writer.lock().unwrap();
+        // an equal version (WAL replay) goes in front of the old one and shadows it
+        let preds = self.seek(|e| version_cmp(e.key(), e.seq, key, seq).is_lt());
+        let height = writer.random_height();
+        let id = writer.next_id;
+        writer.next_id += 1;
+
+        let node = self.arena.alloc(
+            id,
+            SkipEntry {
+                data: [key, value].concat().into_boxed_slice(),
+                key_len: key.len(),
+                seq,
+                expires_at,
+                deleted,
+                merge_operand,
+            },
+        );
+        for (level, pred) in preds.iter().enumerate().take(height) {
+            let link = self.link(*pred, level);
+            node.next[level].store(link.load(Ordering::Acquire), Ordering::Relaxed);
+            link.store(id, Ordering::Release);
+        }
+        self.size.fetch_add(
+            (key.len() + value.len()) as u64 + NODE_OVERHEAD,
+            Ordering::Relaxed,
+        );
+    }
+
+    // expires_at is 0 for values that never expire
+    pub(crate) fn put(&self, key: &[u8], value: &[u8], seq: u64, expires_at: u64) {
+        self.insert(key, value, seq, expires_at, (false, false));
+    }
+
+    pub(crate) fn delete(&self, key: &[u8], seq: u64) {
+        self.insert(key, &[], seq, 0, (true, false));
+    }
+
+    pub(crate) fn merge(&self, key: &[u8], operand: &[u8], seq: u64) {
+        self.insert(key, operand, seq, 0, (false, true));
+    }
+
+    // operation i of the batch gets first_seq + i, only the ones of column family cf are applied
+    pub(crate) fn apply_batch(&self, batch: &WriteBatch, first_seq: u64, cf: ColumnFamilyId) {
+        for (seq, (op_cf, op)) in (first_seq..).zip(batch.ops()) {
+            if *op_cf != cf {
+                continue;
+            }
+            match op {
+                BatchOp::Put(k, v) => self.put(k, v, seq, 0),
+                BatchOp::Delete(k) => self.delete(k, seq),
+            }
+        }
+    }
+
+    pub(crate) fn delete_range(&self, start: &[u8], end: &[u8], seq: u64) {
+        self.range_tombstones.write().unwrap().push(RangeTombstone {
+            start: start.to_vec(),
+            end: end.to_vec(),
+            seq,
+        });
+        self.size
+            .fetch_add((start.len() + end.len()) as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        self.range_tombstones.read().unwrap().clone()
+    }
+
+    // smallest version (by key, then newest first) above the lower bound
+    pub(crate) fn ceiling(&self, lower: Bound<(&[u8], u64)>) -> Option<&SkipEntry> {
+        let preds = self.seek(|e| !above_lower(e, lower));
+        match self.link(preds[0], 0).load(Ordering::Acquire) {
+            NIL => None,
+            id => Some(self.entry(id)),
+        }
+    }
+
+    // largest version (by key, then newest first) below the upper bound
+    pub(crate) fn floor(&self, upper: Bound<(&[u8], u64)>) -> Option<&SkipEntry> {
+        match self.seek(|e| below_upper(e, upper))[0] {
+            NIL => None,
+            id => Some(self.entry(id)),
+        }
+    }
+
+    // every version (tombstones included) of the keys in the range, in memtable order
+    pub(crate) fn range<'a>(
+        &'a self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&'a [u8]>,
+    ) -> impl Iterator<Item = &'a SkipEntry> {
+        let lower = match lower {
+            Bound::Included(k) => Bound::Included((k, u64::MAX)),
+            Bound::Excluded(k) => Bound::Excluded((k, 0)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let preds = self.seek(|e| !above_lower(e, lower));
+        let mut next = self.link(preds[0], 0).load(Ordering::Acquire);
+        std::iter::from_fn(move || {
+            if next == NIL {
+                return None;
+            }
+            let id = next;
+            next = self.link(id, 0).load(Ordering::Acquire);
+            Some(self.entry(id))
+        })
+        .take_while(move |e| match upper {
+            Bound::Included(k) => e.key() <= k,
+            Bound::Excluded(k) => e.key() < k,
+            Bound::Unbounded => true,
+        })
+    }
+}
+
+fn above_lower(entry: &SkipEntry, lower: Bound<(&[u8], u64)>) -> bool {
+    match lower {
+        Bound::Included((k, seq)) => version_cmp(entry.key(), entry.seq, k, seq).is_ge(),
+        Bound::Excluded((k, seq)) => version_cmp(entry.key(), entry.seq, k, seq).is_gt(),
+        Bound::Unbounded => true,
+    }
+}
+
+fn below_upper(entry: &SkipEntry, upper: Bound<(&[u8], u64)>) -> bool {
+    match upper {
+        Bound::Included((k, seq)) => version_cmp(entry.key(), entry.seq, k, seq).is_le(),
+        Bound::Excluded((k, seq)) => version_cmp(entry.key(), entry.seq, k, seq).is_lt(),
+        Bound::Unbounded => true,
+    }
+}
+
+// writes only ever need &self, the trait takes &mut because the other memtables do
+impl Memtable for SkipList {
+    fn put(&mut self, key: &[u8], value: &[u8], seq: u64, expires_at: u64) {
+        SkipList::put(self, key, value, seq, expires_at);
+    }
+
+    fn delete(&mut self, key: &[u8], seq: u64) {
+        SkipList::delete(self, key, seq);
+    }
+
+    fn merge(&mut self, key: &[u8], operand: &[u8], seq: u64) {
+        SkipList::merge(self, key, operand, seq);
+    }
+
+    fn delete_range(&mut self, start: &[u8], end: &[u8], seq: u64) {
+        SkipList::delete_range(self, start, end, seq);
+    }
+
+    fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        SkipList::range_tombstones(self)
+    }
+
+    fn ceiling(&self, lower: Bound<(&[u8], u64)>) -> Option<MemtableEntry<'_>> {
+        SkipList::ceiling(self, lower).map(SkipEntry::as_entry)
+    }
+
+    fn floor(&self, upper: Bound<(&[u8], u64)>) -> Option<MemtableEntry<'_>> {
+        SkipList::floor(self, upper).map(SkipEntry::as_entry)
+    }
+
+    fn iter<'a>(
+        &'a self,
+        lower: Bound<&'a [u8]>,
+        upper: Bound<&'a [u8]>,
+    ) -> Box<dyn Iterator<Item = MemtableEntry<'a>> + 'a> {
+        Box::new(self.range(lower, upper).map(SkipEntry::as_entry))
+    }
+
+    fn memory_usage(&self) -> u64 {
+        self.size()
+    }
+
+    fn is_empty(&self) -> bool {
+        SkipList::is_empty(self)
+    }
+
+    fn concurrent(&self) -> Option<&SkipList> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn readers_see_a_consistent_list_while_it_grows() {
+        let list = Arc::new(SkipList::new());
+        // crosses several arena chunks
+        let writer = {
+            let list = Arc::clone(&list);
+            thread::spawn(move || {
+                for i in 0..5000u64 {
+                    list.put(format!("{:05}", i).as_bytes(), b"v", i + 1, 0);
+                }
+            })
+        };
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let list = Arc::clone(&list);
+                thread::spawn(move || {
+                    for _ in 0..20 {
+                        let keys: Vec<Vec<u8>> = list
+                            .range(Bound::Unbounded, Bound::Unbounded)
+                            .map(|e| e.key().to_vec())
+                            .collect();
+                        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+                    }
+                })
+            })
+            .collect();
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        assert_eq!(list.range(Bound::Unbounded, Bound::Unbounded).count(), 5000);
+        list.delete(b"00042", 6000);
+        let newest = list.ceiling(Bound::Included((b"00042", u64::MAX))).unwrap();
+        assert!(newest.deleted);
+        let older = list.ceiling(Bound::Included((b"00042", 5999))).unwrap();
+        assert_eq!((older.value(), older.seq), (b"v".as_slice(), 43));
+        let last = list.floor(Bound::Unbounded).unwrap();
+        assert_eq!(last.key(), b"04999");
+    }
+}
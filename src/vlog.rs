@@ -0,0 +1,286 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions, remove_file};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::errors::{CorruptionType, DataCorruptedErr, DbError, Result};
+use crate::helpers::{compute_crc, new_file_number, new_timestamp};
+use crate::lsm::KvEntry;
+use crate::options::Options;
+
+const VLOG_EXTENSION: &str = "vlog";
+const RECORD_HEADER_SIZE: usize = 28; // crc(4) | tstamp(8) | ksz(8) | value_sz(8)
+pub(crate) const POINTER_SIZE: usize = 24; // file_id(8) | offset(8) | size(8)
+
+// key and location of every record in one file
+type FileRecords = Vec<(Vec<u8>, ValuePointer)>;
+
+// Where a separated value lives: the record starting at offset in {file_id}.vlog, size bytes
+// long header included. Stored in place of the value in memtable flushes and SSTables.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ValuePointer {
+    pub(crate) file_id: u64,
+    pub(crate) offset: u64,
+    pub(crate) size: u64,
+}
+
+impl ValuePointer {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        [
+            self.file_id.to_le_bytes(),
+            self.offset.to_le_bytes(),
+            self.size.to_le_bytes(),
+        ]
+        .concat()
+    }
+
+    pub(crate) fn decode(b: &[u8]) -> Option<Self> {
+        if b.len() != POINTER_SIZE {
+            return None;
+        }
+        let u64_at = |i: usize| u64::from_le_bytes(b[i..i + 8].try_into().unwrap());
+        Some(Self {
+            file_id: u64_at(0),
+            offset: u64_at(8),
+            size: u64_at(16),
+        })
+    }
+}
+
+// Append only file of large values, written once by a memtable flush. Records use the same
+// layout as the bitcask data files: [ crc | tstamp | ksz | value_sz | key | value ]
+pub(crate) struct VlogWriter {
+    id: u64,
+    path: PathBuf,
+    file: BufWriter<File>,
+    offset: u64,
+}
+
+impl VlogWriter {
+    pub(crate) fn append(&mut self, key: &[u8], value: &[u8]) -> Result<ValuePointer> {
+        let tstamp = new_timestamp().to_le_bytes();
+        let ksz = (key.len() as u64).to_le_bytes();
+        let vsz = (value.len() as u64).to_le_bytes();
+        let crc = compute_crc(&tstamp, &ksz, &vsz, key, value);
+
+        self.file.write_all(&crc.to_le_bytes())?;
+        self.file.write_all(&tstamp)?;
+        self.file.write_all(&ksz)?;
+        self.file.write_all(&vsz)?;
+        self.file.write_all(key)?;
+        self.file.write_all(value)?;
+
+        let pointer = ValuePointer {
+            file_id: self.id,
+            offset: self.offset,
+            size: (RECORD_HEADER_SIZE + key.len() + value.len()) as u64,
+        };
+        self.offset += pointer.size;
+        Ok(pointer)
+    }
+
+    // the values have to be on disk before any table points at them
+    pub(crate) fn finish(mut self) -> Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+        if let Some(parent) = self.path.parent() {
+            File::open(parent)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+// The value log files of one engine directory. Values longer than threshold are moved out of
+// the LSM tree on flush, so compaction only ever rewrites the small pointers.
+pub(crate) struct ValueLog {
+    dir: PathBuf,
+    threshold: u64,
+    max_record_size: u64, // longer pointers are read as corruption
+    files: RwLock<HashMap<u64, Arc<File>>>, // read handles, opened on first use
+    pending: Mutex<HashSet<u64>>, // written by a flush whose table isn't installed yet
+}
+
+impl ValueLog {
+    pub(crate) fn new(dir: &Path, options: &Options) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            threshold: options.value_log_threshold,
+            max_record_size: RECORD_HEADER_SIZE as u64
+                + options.max_key_size
+                + options.max_value_size,
+            files: RwLock::new(HashMap::new()),
+            pending: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn path(&self, file_id: u64) -> PathBuf {
+        self.dir.join(format!("{}.{}", file_id, VLOG_EXTENSION))
+    }
+
+    pub(crate) fn separates(&self, value: &[u8]) -> bool {
+        value.len() as u64 > self.threshold
+    }
+
+    pub(crate) fn writer(&self) -> Result<VlogWriter> {
+        let id = new_file_number();
+        let path = self.path(id);
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        self.pending.lock().unwrap().insert(id);
+        Ok(VlogWriter {
+            id,
+            path,
+            file: BufWriter::new(file),
+            offset: 0,
+        })
+    }
+
+    // every table written so far is installed, their value log files can be collected
+    pub(crate) fn release_pending(&self) {
+        self.pending.lock().unwrap().clear();
+    }
+
+    // files garbage collection may look at, oldest first
+    pub(crate) fn collectable_files(&self) -> Result<Vec<u64>> {
+        let pending = self.pending.lock().unwrap();
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|x| x.to_str()) != Some(VLOG_EXTENSION) {
+                continue;
+            }
+            if let Some(id) = path
+                .file_stem()
+                .and_then(|x| x.to_str()?.parse::<u64>().ok())
+                .filter(|id| !pending.contains(id))
+            {
+                ids.push(id);
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn file(&self, file_id: u64) -> Result<Arc<File>> {
+        if let Some(file) = self.files.read().unwrap().get(&file_id) {
+            return Ok(Arc::clone(file));
+        }
+        let path = self.path(file_id);
+        let file = match File::open(&path) {
+            Ok(file) => Arc::new(file),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(DbError::FileError(
+                    "value log file is missing".to_string(),
+                    path,
+                ));
+            }
+            Err(err) => return Err(err.into()),
+        };
+        self.files
+            .write()
+            .unwrap()
+            .insert(file_id, Arc::clone(&file));
+        Ok(file)
+    }
+
+    pub(crate) fn read(&self, key: &[u8], pointer: ValuePointer) -> Result<Vec<u8>> {
+        let corrupted = |reason| {
+            DbError::DataCorrupted(DataCorruptedErr {
+                offset: pointer.offset,
+                file_path: self.path(pointer.file_id),
+                reason,
+            })
+        };
+        if pointer.size > self.max_record_size || pointer.size < RECORD_HEADER_SIZE as u64 {
+            return Err(corrupted(CorruptionType::BufferExceedsMaxLength {
+                size: pointer.size,
+                max_size: self.max_record_size,
+            }));
+        }
+
+        let mut buf = vec![0u8; pointer.size as usize];
+        self.file(pointer.file_id)?
+            .read_exact_at(&mut buf, pointer.offset)?;
+        let (record_key, value) = parse_record(&buf)
+            .filter(|(_, _, end)| *end == buf.len())
+            .map(|(k, v, _)| (k, v))
+            .ok_or_else(|| {
+                corrupted(CorruptionType::Other(
+                    "value log record does not match its pointer".to_string(),
+                ))
+            })?;
+        if record_key != key {
+            return Err(corrupted(CorruptionType::Other(
+                "value log record belongs to another key".to_string(),
+            )));
+        }
+        Ok(value.to_vec())
+    }
+
+    // value of an entry, dereferenced when it was separated
+    pub(crate) fn resolve(&self, entry: &KvEntry) -> Result<Vec<u8>> {
+        if !entry.value_ptr {
+            return Ok(entry.value.clone());
+        }
+        let pointer = ValuePointer::decode(&entry.value).ok_or_else(|| {
+            DbError::DataCorrupted(DataCorruptedErr {
+                offset: 0,
+                file_path: self.dir.clone(),
+                reason: CorruptionType::LengthMismatch {
+                    expected: POINTER_SIZE,
+                    found: entry.value.len(),
+                },
+            })
+        })?;
+        self.read(&entry.key, pointer)
+    }
+
+    // size of the file and its records. A flush that crashed midway leaves a torn
+    // tail, nothing points past it
+    pub(crate) fn records(&self, file_id: u64) -> Result<(u64, FileRecords)> {
+        let buf = fs::read(self.path(file_id))?;
+        let mut records = Vec::new();
+        let mut pos = 0;
+        while let Some((key, _, end)) = parse_record(&buf[pos..]) {
+            records.push((
+                key.to_vec(),
+                ValuePointer {
+                    file_id,
+                    offset: pos as u64,
+                    size: end as u64,
+                },
+            ));
+            pos += end;
+        }
+        Ok((buf.len() as u64, records))
+    }
+
+    pub(crate) fn remove(&self, file_id: u64) -> Result<()> {
+        self.files.write().unwrap().remove(&file_id);
+        remove_file(self.path(file_id))?;
+        Ok(())
+    }
+}
+
+// parses the record at the start of b, returns its key, value and length. None when it is
+// truncated or fails its crc
+fn parse_record(b: &[u8]) -> Option<(&[u8], &[u8], usize)> {
+    let header = b.get(..RECORD_HEADER_SIZE)?;
+    let crc = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let tstamp: [u8; 8] = header[4..12].try_into().unwrap();
+    let ksz: [u8; 8] = header[12..20].try_into().unwrap();
+    let vsz: [u8; 8] = header[20..28].try_into().unwrap();
+
+    let key_end = RECORD_HEADER_SIZE.checked_add(u64::from_le_bytes(ksz).try_into().ok()?)?;
+    let end = key_end.checked_add(u64::from_le_bytes(vsz).try_into().ok()?)?;
+    let key = b.get(RECORD_HEADER_SIZE..key_end)?;
+    let value = b.get(key_end..end)?;
+    if compute_crc(&tstamp, &ksz, &vsz, key, value) != crc {
+        return None;
+    }
+    Some((key, value, end))
+}
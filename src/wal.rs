@@ -1 +1,955 @@
-// WAL implementation goes here
+use std::fs::{self, File, OpenOptions, remove_file};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{JoinHandle, spawn};
+use std::time::Duration;
+
+use crate::errors::{CorruptionType, DataCorruptedErr, DbError, Result};
+use crate::helpers::{RangeCrc, compute_crc_data_block, new_file_number, new_timestamp};
+use crate::lsm::{ColumnFamilyId, DEFAULT_COLUMN_FAMILY, KEY_MAX_BYTES_SIZE, VALUE_MAX_BYTES_SIZE};
+use std::cmp::max;
+
+pub(crate) const TAG_DELETION: u8 = 2;
+pub(crate) const TAG_INSERTION: u8 = 4;
+pub(crate) const TAG_BATCH: u8 = 8;
+pub(crate) const TAG_RANGE_DELETION: u8 = 16;
+pub(crate) const TAG_EXPIRING_INSERTION: u8 = 32;
+pub(crate) const TAG_MERGE: u8 = 64;
+// set on the tag of records and batch operations of any column family but the default one,
+// a cf(4) follows the tag. Default family records keep the plain layout
+pub(crate) const TAG_COLUMN_FAMILY_FLAG: u8 = 128;
+
+// WAL config for flush
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SyncConfig {
+    None,       // fast, data can be lost
+    Every(u64), // in ms, fsynced by a background timer
+    Always,     // Ddurable, concurrent writers share one fsync (group commit)
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum WalRecordType<'a> {
+    Deletion(&'a [u8]),            // ( key )
+    Insertion(&'a [u8], &'a [u8]), // (key, value)
+    Batch(&'a WriteBatch),
+    RangeDeletion(&'a [u8], &'a [u8]),          // [start, end)
+    ExpiringInsertion(&'a [u8], &'a [u8], u64), // (key, value, expires_at)
+    Merge(&'a [u8], &'a [u8]),                  // (key, operand)
+}
+
+pub(crate) enum BatchOp {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+// Puts and deletes logged as a single WAL record and applied together: after a crash either
+// every operation in the batch is there or none of them is. Operations may target different
+// column families.
+#[derive(Default)]
+pub struct WriteBatch {
+    ops: Vec<(ColumnFamilyId, BatchOp)>,
+    size: u64, // key + value bytes
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.put_cf(DEFAULT_COLUMN_FAMILY, key, value);
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.delete_cf(DEFAULT_COLUMN_FAMILY, key);
+    }
+
+    pub fn put_cf(&mut self, cf: ColumnFamilyId, key: &[u8], value: &[u8]) {
+        self.size += (key.len() + value.len()) as u64;
+        self.ops
+            .push((cf, BatchOp::Put(key.to_vec(), value.to_vec())));
+    }
+
+    pub fn delete_cf(&mut self, cf: ColumnFamilyId, key: &[u8]) {
+        self.size += key.len() as u64;
+        self.ops.push((cf, BatchOp::Delete(key.to_vec())));
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    // key + value bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    // body of the WAL record: [ TAG | ksz(8) | vsz(8) | key | value ] per put, [ TAG | ksz(8) | key ] per delete.
+    // Operations of other column families carry the flag and their cf(4) like records do
+    fn encode(&self, buf: &mut Vec<u8>) {
+        for (cf, op) in &self.ops {
+            match op {
+                BatchOp::Put(k, v) => {
+                    push_tag(buf, TAG_INSERTION, *cf);
+                    buf.extend_from_slice(&(k.len() as u64).to_le_bytes());
+                    buf.extend_from_slice(&(v.len() as u64).to_le_bytes());
+                    buf.extend_from_slice(k);
+                    buf.extend_from_slice(v);
+                }
+                BatchOp::Delete(k) => {
+                    push_tag(buf, TAG_DELETION, *cf);
+                    buf.extend_from_slice(&(k.len() as u64).to_le_bytes());
+                    buf.extend_from_slice(k);
+                }
+            }
+        }
+    }
+
+    // None when the body doesnt hold exactly count well formed operations
+    fn decode(body: &[u8], count: u64, max_key_size: u64, max_value_size: u64) -> Option<Self> {
+        fn read_u64(body: &[u8], pos: &mut usize) -> Option<u64> {
+            let bytes = body.get(*pos..pos.checked_add(8)?)?;
+            *pos += 8;
+            Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+        }
+        fn read_bytes<'b>(body: &'b [u8], pos: &mut usize, len: u64) -> Option<&'b [u8]> {
+            let bytes = body.get(*pos..pos.checked_add(len as usize)?)?;
+            *pos += len as usize;
+            Some(bytes)
+        }
+
+        let mut batch = WriteBatch::new();
+        let mut pos = 0;
+        while pos < body.len() {
+            let tag = body[pos] & !TAG_COLUMN_FAMILY_FLAG;
+            let cf = match body[pos] & TAG_COLUMN_FAMILY_FLAG {
+                0 => DEFAULT_COLUMN_FAMILY,
+                _ => {
+                    let bytes = body.get(pos + 1..pos + 5)?;
+                    pos += 4;
+                    ColumnFamilyId::from_le_bytes(bytes.try_into().unwrap())
+                }
+            };
+            pos += 1;
+            let ksz = read_u64(body, &mut pos).filter(|&k| k <= max_key_size)?;
+            match tag {
+                TAG_INSERTION => {
+                    let vsz = read_u64(body, &mut pos).filter(|&v| v <= max_value_size)?;
+                    let key = read_bytes(body, &mut pos, ksz)?;
+                    let value = read_bytes(body, &mut pos, vsz)?;
+                    batch.put_cf(cf, key, value);
+                }
+                TAG_DELETION => batch.delete_cf(cf, read_bytes(body, &mut pos, ksz)?),
+                _ => return None,
+            }
+        }
+        (batch.len() as u64 == count).then_some(batch)
+    }
+
+    pub(crate) fn ops(&self) -> &[(ColumnFamilyId, BatchOp)] {
+        &self.ops
+    }
+}
+
+fn push_tag(buf: &mut Vec<u8>, tag: u8, cf: ColumnFamilyId) {
+    if cf == DEFAULT_COLUMN_FAMILY {
+        buf.push(tag);
+    } else {
+        buf.push(tag | TAG_COLUMN_FAMILY_FLAG);
+        buf.extend_from_slice(&cf.to_le_bytes());
+    }
+}
+
+struct WalAppender {
+    wal_writer: Option<BufWriter<File>>,
+    record_buffer: Vec<u8>,
+}
+
+// Writers waiting for their records to be durable. Whoever finds no fsync in flight becomes
+// the leader and syncs everything appended so far, the others wait for it and share the result
+// instead of issuing their own fsync.
+#[derive(Default)]
+struct GroupCommit {
+    state: Mutex<CommitState>,
+    synced_cv: Condvar,
+}
+
+#[derive(Default)]
+struct CommitState {
+    appended: u64, // records handed to the OS
+    synced: u64,   // records known to be on disk
+    syncing: bool,
+    fsyncs: u64,
+    failed: Option<io::ErrorKind>, // a failed fsync poisons the log, the page cache can no longer be trusted
+    #[cfg(test)]
+    fail_syncs: bool,
+}
+
+impl CommitState {
+    fn check(&self) -> io::Result<()> {
+        match self.failed {
+            Some(kind) => Err(io::Error::new(kind, "a previous WAL fsync failed")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl GroupCommit {
+    fn sync(&self, file: &File) -> io::Result<()> {
+        let res = file.sync_data();
+        let mut state = self.state.lock().unwrap();
+        state.fsyncs += 1;
+        if let Err(err) = &res {
+            state.failed = Some(err.kind());
+        }
+        res
+    }
+
+    // blocks until record number ticket is on disk
+    fn wait_durable(&self, file: &File, ticket: u64) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            // a record synced before a later fsync failed stays durable, so the writes that
+            // succeed are always the ones up to some record
+            if state.synced >= ticket {
+                return Ok(());
+            }
+            state.check()?;
+            if state.syncing {
+                state = self.synced_cv.wait(state).unwrap();
+                continue;
+            }
+
+            state.syncing = true;
+            let target = state.appended;
+            #[cfg(test)]
+            let inject = state.fail_syncs;
+            drop(state);
+            let res = file.sync_data();
+            #[cfg(test)]
+            let res = match inject {
+                true => Err(io::Error::other("injected fsync failure")),
+                false => res,
+            };
+
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            state.fsyncs += 1;
+            match res {
+                Ok(()) => state.synced = max(state.synced, target),
+                Err(err) => state.failed = Some(err.kind()),
+            }
+            self.synced_cv.notify_all();
+        }
+    }
+}
+
+// Appends records to one log file. Shared by reference: appends are serialized internally
+// and waiting for durability happens outside the append lock.
+pub(crate) struct WalWriter {
+    appender: Mutex<WalAppender>,
+    sync_file: Arc<File>, // second handle on the log so fsync runs without blocking appends
+    sync_c: SyncConfig,
+    commit: Arc<GroupCommit>,
+    syncer: Option<(Sender<()>, JoinHandle<()>)>, // timer thread for SyncConfig::Every
+    path: PathBuf,
+}
+
+impl WalWriter {
+    pub(crate) fn new(dir: &Path, sync_c: SyncConfig) -> io::Result<WalWriter> {
+        let wal_path = dir.join(format!("{}.wal", new_file_number()));
+        let wal_file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&wal_path)?;
+        let sync_file = Arc::new(wal_file.try_clone()?);
+        let commit = Arc::new(GroupCommit::default());
+
+        let syncer = match sync_c {
+            SyncConfig::Every(ms) => Some(Self::spawn_syncer(
+                sync_file.try_clone()?,
+                Arc::clone(&commit),
+                Duration::from_millis(ms),
+            )),
+            SyncConfig::None | SyncConfig::Always => None,
+        };
+
+        Ok(Self {
+            appender: Mutex::new(WalAppender {
+                wal_writer: Some(BufWriter::new(wal_file)),
+                record_buffer: Vec::new(),
+            }),
+            sync_file,
+            sync_c,
+            commit,
+            syncer,
+            path: wal_path,
+        })
+    }
+
+    // fsyncs every interval until the WAL is dropped, then one last time
+    fn spawn_syncer(
+        file: File,
+        commit: Arc<GroupCommit>,
+        interval: Duration,
+    ) -> (Sender<()>, JoinHandle<()>) {
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+        let handle = spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(interval) {
+                // the error sticks in commit and is reported by the next append
+                if commit.sync(&file).is_err() {
+                    return;
+                }
+            }
+            let _ = commit.sync(&file);
+        });
+        (stop_tx, handle)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn destruct(mut self) -> Result<()> {
+        self.appender.get_mut().unwrap().wal_writer = None;
+        remove_file(&self.path)?;
+        Ok(())
+    }
+
+    // number of fsyncs issued so far, with group commit this stays below the number of records
+    #[cfg(test)]
+    pub(crate) fn sync_count(&self) -> u64 {
+        self.commit.state.lock().unwrap().fsyncs
+    }
+
+    // every fsync from now on fails
+    #[cfg(test)]
+    pub(crate) fn fail_syncs(&self) {
+        self.commit.state.lock().unwrap().fail_syncs = true;
+    }
+
+    // appends and returns once the record is as durable as sync_c asks for
+    #[cfg(test)]
+    pub(crate) fn record_to_wal<'a>(
+        &self,
+        seq: u64,
+        cf: ColumnFamilyId,
+        record: WalRecordType<'a>,
+    ) -> Result<()> {
+        self.append(seq, cf, record)?.wait_durable()
+    }
+
+    // writes the record to the OS, the ticket tells when it is on disk. A batch ignores cf,
+    // each of its operations names its own family
+    pub(crate) fn append<'a>(
+        &self,
+        seq: u64,
+        cf: ColumnFamilyId,
+        record: WalRecordType<'a>,
+    ) -> Result<Ticket> {
+        let mut appender = self.appender.lock().unwrap();
+        let WalAppender {
+            wal_writer,
+            record_buffer,
+        } = &mut *appender;
+        record_buffer.clear();
+        let tstamp = new_timestamp();
+
+        match record {
+            WalRecordType::Deletion(k) => {
+                push_tag(record_buffer, TAG_DELETION, cf);
+                record_buffer.extend_from_slice(&seq.to_le_bytes());
+                record_buffer.extend_from_slice(&tstamp.to_le_bytes());
+                record_buffer.extend_from_slice(&(k.len() as u64).to_le_bytes());
+                record_buffer.extend_from_slice(k);
+            }
+            WalRecordType::Insertion(k, v) => {
+                push_tag(record_buffer, TAG_INSERTION, cf);
+                record_buffer.extend_from_slice(&seq.to_le_bytes());
+                record_buffer.extend_from_slice(&tstamp.to_le_bytes());
+                record_buffer.extend_from_slice(&(k.len() as u64).to_le_bytes());
+                record_buffer.extend_from_slice(&(v.len() as u64).to_le_bytes());
+                record_buffer.extend_from_slice(k);
+                record_buffer.extend_from_slice(v);
+            }
+            WalRecordType::ExpiringInsertion(k, v, expires_at) => {
+                // same layout as an insertion, the tstamp slot holds the expiry
+                push_tag(record_buffer, TAG_EXPIRING_INSERTION, cf);
+                record_buffer.extend_from_slice(&seq.to_le_bytes());
+                record_buffer.extend_from_slice(&expires_at.to_le_bytes());
+                record_buffer.extend_from_slice(&(k.len() as u64).to_le_bytes());
+                record_buffer.extend_from_slice(&(v.len() as u64).to_le_bytes());
+                record_buffer.extend_from_slice(k);
+                record_buffer.extend_from_slice(v);
+            }
+            WalRecordType::Merge(k, operand) => {
+                push_tag(record_buffer, TAG_MERGE, cf);
+                record_buffer.extend_from_slice(&seq.to_le_bytes());
+                record_buffer.extend_from_slice(&tstamp.to_le_bytes());
+                record_buffer.extend_from_slice(&(k.len() as u64).to_le_bytes());
+                record_buffer.extend_from_slice(&(operand.len() as u64).to_le_bytes());
+                record_buffer.extend_from_slice(k);
+                record_buffer.extend_from_slice(operand);
+            }
+            WalRecordType::RangeDeletion(start, end) => {
+                push_tag(record_buffer, TAG_RANGE_DELETION, cf);
+                record_buffer.extend_from_slice(&seq.to_le_bytes());
+                record_buffer.extend_from_slice(&tstamp.to_le_bytes());
+                record_buffer.extend_from_slice(&(start.len() as u64).to_le_bytes());
+                record_buffer.extend_from_slice(&(end.len() as u64).to_le_bytes());
+                record_buffer.extend_from_slice(start);
+                record_buffer.extend_from_slice(end);
+            }
+            WalRecordType::Batch(batch) => {
+                // seq is the one of the first operation, the rest follow in order
+                push_tag(record_buffer, TAG_BATCH, DEFAULT_COLUMN_FAMILY);
+                record_buffer.extend_from_slice(&seq.to_le_bytes());
+                record_buffer.extend_from_slice(&tstamp.to_le_bytes());
+                record_buffer.extend_from_slice(&(batch.len() as u64).to_le_bytes());
+                let body_start = record_buffer.len() + 8;
+                record_buffer.extend_from_slice(&0u64.to_le_bytes());
+                batch.encode(record_buffer);
+                let body_len = (record_buffer.len() - body_start) as u64;
+                record_buffer[body_start - 8..body_start].copy_from_slice(&body_len.to_le_bytes());
+            }
+        }
+
+        let crc = compute_crc_data_block(record_buffer);
+        record_buffer.extend_from_slice(&crc.to_le_bytes());
+
+        self.commit.state.lock().unwrap().check()?;
+        if let Some(writer) = wal_writer.as_mut() {
+            // always reaches the OS, only the fsync depends on sync_c
+            writer.write_all(record_buffer)?;
+            writer.flush()?;
+        }
+        // still under the appender lock, so tickets follow file order
+        let mut state = self.commit.state.lock().unwrap();
+        state.appended += 1;
+        Ok(Ticket {
+            commit: Arc::clone(&self.commit),
+            sync_file: Arc::clone(&self.sync_file),
+            sync_c: self.sync_c,
+            number: state.appended,
+        })
+    }
+
+    // fsyncs everything appended so far whatever sync_c says
+    pub(crate) fn sync(&self) -> Result<()> {
+        let appended = self.commit.state.lock().unwrap().appended;
+        Ok(self.commit.wait_durable(&self.sync_file, appended)?)
+    }
+}
+
+// A record handed to the OS. Waiting on it takes no lock of the engine, so writers queued up
+// behind each other share one fsync. Keeps the log open, a rotation in between changes nothing.
+pub(crate) struct Ticket {
+    commit: Arc<GroupCommit>,
+    sync_file: Arc<File>,
+    sync_c: SyncConfig,
+    number: u64,
+}
+
+impl Ticket {
+    // returns once the record is as durable as sync_c asks for
+    pub(crate) fn wait_durable(self) -> Result<()> {
+        match self.sync_c {
+            SyncConfig::None | SyncConfig::Every(_) => Ok(()),
+            SyncConfig::Always => Ok(self.commit.wait_durable(&self.sync_file, self.number)?),
+        }
+    }
+}
+
+impl Drop for WalWriter {
+    fn drop(&mut self) {
+        // hanging up wakes the timer thread
+        if let Some((stop_tx, handle)) = self.syncer.take() {
+            drop(stop_tx);
+            let _ = handle.join();
+        }
+    }
+}
+
+// What replay does with a record that doesnt check out.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum RecoveryMode {
+    // any damage fails the replay
+    Fail,
+    // a crash mid-append leaves a torn record at the end of the log, it gets cut off.
+    // damage with valid records after it still fails the replay
+    #[default]
+    TruncateTail,
+    // damaged records are dropped, replay resumes at the next record that checks out
+    SkipCorrupted,
+}
+
+// A record read back from the log.
+pub(crate) enum WalRecord {
+    Insertion {
+        seq: u64,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: u64, // 0 when the value never expires
+    },
+    Deletion {
+        seq: u64,
+        key: Vec<u8>,
+    },
+    Batch {
+        seq: u64,
+        batch: WriteBatch,
+    },
+    RangeDeletion {
+        seq: u64,
+        start: Vec<u8>,
+        end: Vec<u8>,
+    },
+    Merge {
+        seq: u64,
+        key: Vec<u8>,
+        operand: Vec<u8>,
+    },
+}
+
+// Where a record sits in the log, as its header says.
+struct RecordHeader {
+    tag: u8,
+    cf: ColumnFamilyId,
+    base: usize, // the byte after the cf, header offsets are relative to it
+    header_len: usize,
+    seq: u64,
+    first_sz: u64, // key size, or op count of a batch
+    end: usize,    // one past the crc
+}
+
+// Yields the records of one log file in append order. A log never grows much past the
+// memtable threshold, so it is read in one go, which lets recovery scan past damaged bytes.
+pub(crate) struct WalReader {
+    path: PathBuf,
+    buf: Vec<u8>,
+    pos: usize,
+    skipped: usize,              // bytes dropped by recovery
+    range_crc: Option<RangeCrc>, // built on the first damaged record
+    mode: RecoveryMode,
+    done: bool,
+    max_key_size: u64, // longer sizes are read as corruption
+    max_value_size: u64,
+}
+
+impl WalReader {
+    pub(crate) fn open(path: &Path, mode: RecoveryMode) -> Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            buf: fs::read(path)?,
+            pos: 0,
+            skipped: 0,
+            range_crc: None,
+            mode,
+            done: false,
+            max_key_size: KEY_MAX_BYTES_SIZE,
+            max_value_size: VALUE_MAX_BYTES_SIZE,
+        })
+    }
+
+    // the limits the log was written with
+    pub(crate) fn with_limits(mut self, max_key_size: u64, max_value_size: u64) -> Self {
+        self.max_key_size = max_key_size;
+        self.max_value_size = max_value_size;
+        self
+    }
+
+    // end of the last record that checked out
+    #[cfg(test)]
+    pub(crate) fn valid_len(&self) -> u64 {
+        self.pos as u64
+    }
+
+    #[cfg(test)]
+    pub(crate) fn skipped_bytes(&self) -> u64 {
+        self.skipped as u64
+    }
+
+    fn u64_at(&self, at: usize) -> Option<u64> {
+        let bytes = self.buf.get(at..at.checked_add(8)?)?;
+        Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    // reads the header of the record starting at pos and checks its sizes against the limits
+    // and the end of the log, without touching the payload
+    fn header_at(&self, pos: usize) -> std::result::Result<RecordHeader, CorruptionType> {
+        let buf = &self.buf;
+        // every record is TAG | cf(4) if flagged | seq(8) | tstamp(8) | two u64 sizes (one for deletions) | payload | crc(4),
+        // offsets below are relative to base, the byte after the cf
+        let tag = buf[pos] & !TAG_COLUMN_FAMILY_FLAG;
+        let (cf, base) = match buf[pos] & TAG_COLUMN_FAMILY_FLAG {
+            0 => (DEFAULT_COLUMN_FAMILY, pos),
+            _ => {
+                let bytes = buf
+                    .get(pos + 1..pos + 5)
+                    .ok_or(CorruptionType::LengthMismatch {
+                        expected: 5,
+                        found: buf.len() - pos,
+                    })?;
+                (
+                    ColumnFamilyId::from_le_bytes(bytes.try_into().unwrap()),
+                    pos + 4,
+                )
+            }
+        };
+        let torn = |needed: usize| CorruptionType::LengthMismatch {
+            expected: base - pos + needed,
+            found: buf.len() - pos,
+        };
+        let seq = self.u64_at(base + 1).ok_or_else(|| torn(9))?;
+        let (header_len, first_sz, second_sz) = match tag {
+            TAG_DELETION => {
+                let ksz = self.u64_at(base + 17).ok_or_else(|| torn(25))?;
+                if ksz > self.max_key_size {
+                    return Err(CorruptionType::Other(format!(
+                        "record size overflow: ksz={ksz}"
+                    )));
+                }
+                (25, ksz, 0)
+            }
+            TAG_INSERTION | TAG_EXPIRING_INSERTION | TAG_MERGE | TAG_BATCH | TAG_RANGE_DELETION => {
+                let first = self.u64_at(base + 17).ok_or_else(|| torn(33))?;
+                let second = self.u64_at(base + 25).ok_or_else(|| torn(33))?;
+                let max_second = match tag {
+                    TAG_RANGE_DELETION => self.max_key_size,
+                    _ => self.max_value_size,
+                };
+                if tag != TAG_BATCH && (first > self.max_key_size || second > max_second) {
+                    return Err(CorruptionType::Other(format!(
+                        "record size overflow: ksz={first} vsz={second}"
+                    )));
+                }
+                (33, first, second)
+            }
+            _ => {
+                return Err(CorruptionType::Other(format!(
+                    "unknown WAL record tag {tag}"
+                )));
+            }
+        };
+
+        // for a batch first_sz is the op count, only second_sz (the body) takes up room
+        let payload_len = match tag {
+            TAG_BATCH => second_sz,
+            _ => first_sz + second_sz,
+        };
+        let end = usize::try_from(payload_len)
+            .ok()
+            .and_then(|len| (base + header_len).checked_add(len))
+            .and_then(|end| end.checked_add(4))
+            .ok_or_else(|| CorruptionType::Other(format!("record size overflow: {payload_len}")))?;
+        if end > buf.len() {
+            return Err(torn(end - base));
+        }
+        Ok(RecordHeader {
+            tag,
+            cf,
+            base,
+            header_len,
+            seq,
+            first_sz,
+            end,
+        })
+    }
+
+    // decodes the record starting at pos, returns it and its column family with the position
+    // of the next one
+    fn decode_at(
+        &self,
+        pos: usize,
+    ) -> std::result::Result<((ColumnFamilyId, WalRecord), usize), CorruptionType> {
+        let RecordHeader {
+            tag,
+            cf,
+            base,
+            header_len,
+            seq,
+            first_sz,
+            end,
+        } = self.header_at(pos)?;
+        let buf = &self.buf;
+
+        let crc_from_buff = u32::from_le_bytes(buf[end - 4..end].try_into().unwrap());
+        let fresh_crc = compute_crc_data_block(&buf[pos..end - 4]);
+        if fresh_crc != crc_from_buff {
+            return Err(CorruptionType::CrcMismatch {
+                expected: crc_from_buff,
+                found: fresh_crc,
+            });
+        }
+
+        let payload = &buf[base + header_len..end - 4];
+        let record = match tag {
+            TAG_DELETION => WalRecord::Deletion {
+                seq,
+                key: payload.to_vec(),
+            },
+            TAG_INSERTION | TAG_EXPIRING_INSERTION => {
+                let (key, value) = payload.split_at(first_sz as usize);
+                let expires_at = match tag {
+                    TAG_EXPIRING_INSERTION => self.u64_at(base + 9).unwrap(),
+                    _ => 0,
+                };
+                WalRecord::Insertion {
+                    seq,
+                    key: key.to_vec(),
+                    value: value.to_vec(),
+                    expires_at,
+                }
+            }
+            TAG_MERGE => {
+                let (key, operand) = payload.split_at(first_sz as usize);
+                WalRecord::Merge {
+                    seq,
+                    key: key.to_vec(),
+                    operand: operand.to_vec(),
+                }
+            }
+            TAG_RANGE_DELETION => {
+                let (start, end) = payload.split_at(first_sz as usize);
+                WalRecord::RangeDeletion {
+                    seq,
+                    start: start.to_vec(),
+                    end: end.to_vec(),
+                }
+            }
+            _ => WalRecord::Batch {
+                seq,
+                batch: WriteBatch::decode(
+                    payload,
+                    first_sz,
+                    self.max_key_size,
+                    self.max_value_size,
+                )
+                .ok_or_else(|| CorruptionType::Other("Malformed write batch in WAL".to_string()))?,
+            },
+        };
+        Ok(((cf, record), end))
+    }
+
+    // First record at or after from that checks out, in one pass over the damaged bytes. The
+    // crc of a candidate comes from the crcs of the log prefixes, so it costs the same however
+    // much of the log its header claims.
+    fn next_valid_record(&mut self, from: usize) -> Option<usize> {
+        if self.range_crc.is_none() {
+            self.range_crc = Some(RangeCrc::new(&self.buf));
+        }
+        let range_crc = self.range_crc.as_ref().unwrap();
+        (from..self.buf.len()).find(|&pos| {
+            let Ok(header) = self.header_at(pos) else {
+                return false;
+            };
+            let crc_from_buff =
+                u32::from_le_bytes(self.buf[header.end - 4..header.end].try_into().unwrap());
+            range_crc.crc(pos..header.end - 4) == crc_from_buff && self.decode_at(pos).is_ok()
+        })
+    }
+
+    // drops the damaged tail so the next append starts on a record boundary
+    fn truncate_tail(&mut self) -> Result<()> {
+        self.skipped += self.buf.len() - self.pos;
+        let f = OpenOptions::new().write(true).open(&self.path)?;
+        f.set_len(self.pos as u64)?;
+        f.sync_all()?;
+        Ok(())
+    }
+}
+
+impl Iterator for WalReader {
+    type Item = Result<(ColumnFamilyId, WalRecord)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done && self.pos < self.buf.len() {
+            let reason = match self.decode_at(self.pos) {
+                Ok((record, next_pos)) => {
+                    self.pos = next_pos;
+                    return Some(Ok(record));
+                }
+                Err(reason) => reason,
+            };
+
+            let resume = match self.mode {
+                RecoveryMode::Fail => None,
+                _ => self.next_valid_record(self.pos + 1),
+            };
+            match (self.mode, resume) {
+                (RecoveryMode::SkipCorrupted, Some(next_pos)) => {
+                    self.skipped += next_pos - self.pos;
+                    self.pos = next_pos;
+                }
+                // nothing valid follows, this is the torn tail of an append that never finished
+                (RecoveryMode::TruncateTail | RecoveryMode::SkipCorrupted, None) => {
+                    self.done = true;
+                    return self.truncate_tail().err().map(Err);
+                }
+                _ => {
+                    self.done = true;
+                    return Some(Err(DbError::DataCorrupted(DataCorruptedErr {
+                        offset: self.pos as u64,
+                        file_path: self.path.clone(),
+                        reason,
+                    })));
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use tempfile::tempdir;
+
+    // writes count insertions k0, k1.. and returns the offset where every record starts
+    fn write_records(wal: &WalWriter, count: u64) -> Result<Vec<u64>> {
+        let mut offsets = Vec::new();
+        for i in 0..count {
+            offsets.push(fs::metadata(wal.path())?.len());
+            let key = format!("k{i}");
+            wal.record_to_wal(
+                i + 1,
+                DEFAULT_COLUMN_FAMILY,
+                WalRecordType::Insertion(key.as_bytes(), b"value"),
+            )?;
+        }
+        Ok(offsets)
+    }
+
+    fn replayed_keys(path: &Path, mode: RecoveryMode) -> Result<Vec<String>> {
+        WalReader::open(path, mode)?
+            .map(|record| match record? {
+                (_, WalRecord::Insertion { key, .. }) => Ok(String::from_utf8(key).unwrap()),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn wal_sync_modes() -> Result<()> {
+        let dir = tempdir()?;
+        let wal = Arc::new(WalWriter::new(dir.path(), SyncConfig::Always)?);
+        let writers: Vec<_> = (0..8u64)
+            .map(|t| {
+                let wal = Arc::clone(&wal);
+                spawn(move || -> Result<()> {
+                    for i in 0..25u64 {
+                        let key = format!("{t}-{i}");
+                        wal.record_to_wal(
+                            t * 100 + i + 1,
+                            DEFAULT_COLUMN_FAMILY,
+                            WalRecordType::Insertion(key.as_bytes(), b"v"),
+                        )?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap()?;
+        }
+        // every record is durable, never more than one fsync per record
+        assert!(wal.sync_count() <= 200);
+        let replayed = WalReader::open(wal.path(), RecoveryMode::Fail)?.count();
+        assert_eq!(replayed, 200);
+
+        let lazy = WalWriter::new(dir.path(), SyncConfig::None)?;
+        lazy.record_to_wal(
+            1,
+            DEFAULT_COLUMN_FAMILY,
+            WalRecordType::Insertion(b"k", b"v"),
+        )?;
+        assert_eq!(lazy.sync_count(), 0);
+
+        let timed = WalWriter::new(dir.path(), SyncConfig::Every(5))?;
+        timed.record_to_wal(
+            1,
+            DEFAULT_COLUMN_FAMILY,
+            WalRecordType::Insertion(b"k", b"v"),
+        )?;
+        thread::sleep(Duration::from_millis(50));
+        assert!(timed.sync_count() > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn recovery_modes_handle_torn_tail_and_corrupted_records() -> Result<()> {
+        let dir = tempdir()?;
+        let wal = WalWriter::new(dir.path(), SyncConfig::None)?;
+        let offsets = write_records(&wal, 3)?;
+        let path = wal.path().to_path_buf();
+        let full_len = fs::metadata(&path)?.len();
+
+        // a crash halfway through the fourth append
+        let mut bytes = fs::read(&path)?;
+        bytes.extend_from_slice(&[TAG_INSERTION, 9, 9, 9]);
+        fs::write(&path, &bytes)?;
+
+        assert!(replayed_keys(&path, RecoveryMode::Fail).is_err());
+        assert_eq!(
+            replayed_keys(&path, RecoveryMode::TruncateTail)?,
+            vec!["k0", "k1", "k2"]
+        );
+        assert_eq!(fs::metadata(&path)?.len(), full_len);
+
+        // damage in the middle of the log is not a torn tail
+        let mut bytes = fs::read(&path)?;
+        bytes[offsets[1] as usize + 40] ^= 0xFF;
+        fs::write(&path, &bytes)?;
+
+        assert!(replayed_keys(&path, RecoveryMode::TruncateTail).is_err());
+        let mut reader = WalReader::open(&path, RecoveryMode::SkipCorrupted)?;
+        assert_eq!(reader.by_ref().count(), 2);
+        assert_eq!(reader.skipped_bytes(), offsets[2] - offsets[1]);
+        assert_eq!(reader.valid_len(), full_len);
+        Ok(())
+    }
+
+    #[test]
+    fn skip_corrupted_resumes_past_headers_claiming_the_rest_of_the_log() -> Result<()> {
+        let dir = tempdir()?;
+        let wal = WalWriter::new(dir.path(), SyncConfig::None)?;
+        let offsets = write_records(&wal, 3)?;
+        let path = wal.path().to_path_buf();
+        let mut bytes = fs::read(&path)?;
+
+        // batch headers whose body runs to the end of the log, none of them with a valid crc
+        let copies = 2000;
+        let damaged_at = offsets[1] as usize;
+        let total_len = bytes.len() + copies * 33;
+        let mut damage = Vec::new();
+        for i in 0..copies {
+            let body = total_len - (damaged_at + i * 33) - 33 - 4;
+            damage.push(TAG_BATCH);
+            damage.extend_from_slice(&7u64.to_le_bytes());
+            damage.extend_from_slice(&0u64.to_le_bytes());
+            damage.extend_from_slice(&1u64.to_le_bytes());
+            damage.extend_from_slice(&(body as u64).to_le_bytes());
+        }
+        bytes.splice(damaged_at..damaged_at, damage);
+        fs::write(&path, &bytes)?;
+
+        assert!(replayed_keys(&path, RecoveryMode::TruncateTail).is_err());
+        assert_eq!(
+            replayed_keys(&path, RecoveryMode::SkipCorrupted)?,
+            vec!["k0", "k1", "k2"]
+        );
+        let mut reader = WalReader::open(&path, RecoveryMode::SkipCorrupted)?;
+        assert_eq!(reader.by_ref().count(), 3);
+        assert_eq!(reader.skipped_bytes(), copies as u64 * 33);
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::lsm::{MAX_PENDING_MEMTABLES, SLOWDOWN_PENDING_MEMTABLES};
+
+const SLOWDOWN_DELAY: Duration = Duration::from_millis(1); // added to every write past the slowdown mark
+
+// What the write path went through because flushes fell behind.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct WriteStallStats {
+    pub pending_memtables: usize, // frozen memtables whose flush has not finished yet
+    pub slowed_writes: u64,
+    pub stopped_writes: u64,
+    pub stall_time: Duration, // spent in slowdowns and stops together
+}
+
+// Backpressure between writers and the flush threads. Every frozen memtable counts as pending
+// until its flush finishes. Past slowdown_at each write is delayed a little so flushes can
+// catch up, at stop_at a write that needs another memtable waits for a flush to finish.
+pub(crate) struct WriteController {
+    pending: Mutex<usize>,
+    flushed: Condvar,
+    slowdown_at: usize,
+    stop_at: usize,
+    stats: Mutex<WriteStallStats>,
+}
+
+impl Default for WriteController {
+    fn default() -> Self {
+        Self::new(SLOWDOWN_PENDING_MEMTABLES, MAX_PENDING_MEMTABLES)
+    }
+}
+
+impl WriteController {
+    pub(crate) fn new(slowdown_at: usize, stop_at: usize) -> Self {
+        Self {
+            pending: Mutex::new(0),
+            flushed: Condvar::new(),
+            slowdown_at,
+            stop_at,
+            stats: Mutex::new(WriteStallStats::default()),
+        }
+    }
+
+    pub(crate) fn flush_started(&self) {
+        *self.pending.lock().unwrap() += 1;
+    }
+
+    // called by the flush thread whether the flush worked or not
+    pub(crate) fn flush_finished(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        *pending = pending.saturating_sub(1);
+        self.flushed.notify_all();
+    }
+
+    // before every write, no engine lock may be held while it sleeps
+    pub(crate) fn delay_write(&self) {
+        if *self.pending.lock().unwrap() < self.slowdown_at {
+            return;
+        }
+        thread::sleep(SLOWDOWN_DELAY);
+        let mut stats = self.stats.lock().unwrap();
+        stats.slowed_writes += 1;
+        stats.stall_time += SLOWDOWN_DELAY;
+    }
+
+    // before a write freezes the memtable, waits until another one may be pending
+    pub(crate) fn wait_for_flush(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        if *pending < self.stop_at {
+            return;
+        }
+        let start = Instant::now();
+        while *pending >= self.stop_at {
+            pending = self.flushed.wait(pending).unwrap();
+        }
+        drop(pending);
+        let mut stats = self.stats.lock().unwrap();
+        stats.stopped_writes += 1;
+        stats.stall_time += start.elapsed();
+    }
+
+    pub(crate) fn stats(&self) -> WriteStallStats {
+        WriteStallStats {
+            pending_memtables: *self.pending.lock().unwrap(),
+            ..*self.stats.lock().unwrap()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn writes_stop_at_the_cap_until_a_flush_finishes() {
+        let controller = Arc::new(WriteController::new(1, 2));
+        controller.delay_write();
+        assert_eq!(controller.stats(), WriteStallStats::default());
+
+        controller.flush_started();
+        controller.flush_started();
+        controller.delay_write();
+        let writer = {
+            let controller = Arc::clone(&controller);
+            thread::spawn(move || controller.wait_for_flush())
+        };
+        thread::sleep(Duration::from_millis(20));
+        assert!(!writer.is_finished());
+
+        controller.flush_finished();
+        writer.join().unwrap();
+        let stats = controller.stats();
+        assert_eq!(stats.pending_memtables, 1);
+        assert_eq!((stats.slowed_writes, stats.stopped_writes), (1, 1));
+        assert!(stats.stall_time >= Duration::from_millis(20));
+    }
+}